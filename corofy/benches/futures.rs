@@ -0,0 +1,72 @@
+//! The same coroutines with boxed futures and with concrete ones
+//! (`--futures concrete`), timed and with the allocations they make.
+//!
+//! ```text
+//! cargo bench -p corofy --bench futures
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+mod boxed {
+    include!("futures/boxed_corofied.rs");
+}
+
+mod concrete {
+    include!("futures/concrete_corofied.rs");
+}
+
+/// Counts the allocations, so we see the ones a `wait` makes
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        // SAFETY: we just pass the call on
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: we just pass the call on
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// How many awaits each coroutine makes per run
+const N: usize = 1000;
+
+/// Runs `f` for about a second and prints the time and allocations per run
+fn bench(name: &str, f: impl Fn() -> usize) {
+    // Warm up, and check that both versions agree
+    let expected = f();
+    let start = Instant::now();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let mut runs = 0;
+    while start.elapsed() < Duration::from_secs(1) {
+        assert_eq!(black_box(f()), expected);
+        runs += 1;
+    }
+    let per_run = start.elapsed() / runs;
+    let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / runs as usize;
+    println!("{name:<20} {per_run:>12?}/run {allocations:>6} allocations/run");
+}
+
+fn main() {
+    bench("sum boxed", || boxed::block_on(boxed::sum(black_box(N))));
+    bench("sum concrete", || {
+        concrete::block_on(concrete::sum(black_box(N)))
+    });
+    bench("pairs boxed", || {
+        boxed::block_on(boxed::pairs(black_box(N)))
+    });
+    bench("pairs concrete", || {
+        concrete::block_on(concrete::pairs(black_box(N)))
+    });
+}
@@ -0,0 +1,341 @@
+// The coroutines `benches/futures.rs` runs. Each future we wait on is in a
+// local with a type, so `--futures concrete` doesn't need a type alias.
+// After a change, write both versions again:
+//
+// ```text
+// corofy benches/futures/coroutines.rs benches/futures/boxed_corofied.rs
+// corofy --futures concrete benches/futures/coroutines.rs benches/futures/concrete_corofied.rs
+// ```
+
+pub trait Future {
+    type Output;
+    fn poll(&mut self) -> PollState<Self::Output>;
+}
+
+pub enum PollState<T> {
+    Ready(T),
+    NotReady,
+}
+
+/// Resolves to `value` after it's been polled `polls` times without a value
+pub struct Countdown {
+    value: usize,
+    polls: usize,
+}
+
+impl Countdown {
+    pub fn new(value: usize, polls: usize) -> Self {
+        Self { value, polls }
+    }
+}
+
+impl Future for Countdown {
+    type Output = usize;
+
+    fn poll(&mut self) -> PollState<usize> {
+        match self.polls {
+            0 => PollState::Ready(self.value),
+            _ => {
+                self.polls -= 1;
+                PollState::NotReady
+            }
+        }
+    }
+}
+
+pub fn block_on<F: Future>(mut fut: F) -> F::Output {
+    loop {
+        if let PollState::Ready(value) = fut.poll() {
+            return value;
+        }
+    }
+}
+
+
+
+
+
+
+// =================================
+// We rewrite this:
+// =================================
+
+// coroutine fn sum(n: usize) -> usize {
+//     let mut total: usize = 0;
+//     let mut i: usize = 0;
+//     while i < n {
+//         let fut: Countdown = Countdown::new(i, 1);
+//         let value: usize = fut.wait;
+//         total += value;
+//         i += 1;
+//     }
+//     total
+// }
+
+// =================================
+// Into this:
+// =================================
+
+/// Waits `n` times in a loop
+pub fn sum(n: usize) -> impl Future<Output=usize> {
+    Coroutine0::new(n)
+}
+        
+enum State0 {
+    Start(usize),
+    Block1,
+    Wait2(Box<dyn Future<Output = usize>>),
+    Resolved,
+}
+
+#[derive(Default)]
+struct Stack0 {
+    i: Option<usize>,
+    n: Option<usize>,
+    total: Option<usize>,
+}
+
+struct Coroutine0 {
+    stack: Stack0,
+    state: State0,
+}
+
+impl Coroutine0 {
+    fn new(n: usize) -> Self {
+        Self {
+            state: State0::Start(n),
+            stack: Stack0::default(),
+        }
+    }
+}
+
+
+impl Future for Coroutine0 {
+    type Output = usize;
+
+    #[allow(unused_mut)]
+    fn poll(&mut self) -> PollState<Self::Output> {
+        loop {
+            match self.state {
+                State0::Start(n) => {
+                    // ---- Code you actually wrote ----
+                    let mut total: usize = 0;
+                    let mut i: usize = 0;
+                    // ---------------------------------
+                    // Save stack
+                    self.stack.i = Some(i);
+                    self.stack.n = Some(n);
+                    self.stack.total = Some(total);
+                    self.state = State0::Block1;
+                }
+
+                State0::Block1 => {
+                    // Restore stack
+                    let mut i = self.stack.i.take().unwrap();
+                    let n = self.stack.n.take().unwrap();
+                    let mut total = self.stack.total.take().unwrap();
+                    // ---- Code you actually wrote ----
+
+                    // ---------------------------------
+                    if i < n {
+                        // ---- Code you actually wrote ----
+                        let fut: Countdown = Countdown::new(i, 1);
+                        // ---------------------------------
+                        let fut2 = Box::new(fut);
+                        // Save stack
+                        self.stack.i = Some(i);
+                        self.stack.n = Some(n);
+                        self.stack.total = Some(total);
+                        self.state = State0::Wait2(fut2);
+                    } else {
+                        let output = total;
+                        self.state = State0::Resolved;
+                        self.stack = Stack0::default();
+                        break PollState::Ready(output);
+                    }
+                }
+
+                State0::Wait2(ref mut f2) => {
+                    match f2.poll() {
+                        PollState::Ready(value) => {
+                            // Restore stack
+                            let mut i = self.stack.i.take().unwrap();
+                            let mut total = self.stack.total.take().unwrap();
+                            // ---- Code you actually wrote ----
+                            total += value;
+                            i += 1;
+                            // ---------------------------------
+                            // Save stack
+                            self.stack.i = Some(i);
+                            self.stack.total = Some(total);
+                            self.state = State0::Block1;
+                        }
+                        PollState::NotReady => break PollState::NotReady,
+                    }
+                }
+
+                State0::Resolved => panic!("Polled a resolved future")
+            }
+        }
+    }
+}
+
+impl Drop for Coroutine0 {
+    fn drop(&mut self) {
+        if let State0::Wait2(..) = self.state {
+            self.state = State0::Resolved;
+            self.stack.total = None;
+            self.stack.i = None;
+        }
+    }
+}
+
+
+// =================================
+// We rewrite this:
+// =================================
+
+// coroutine fn pairs(n: usize) -> usize {
+//     let mut total: usize = 0;
+//     let mut i: usize = 0;
+//     while i < n {
+//         let a: Countdown = Countdown::new(i, 2);
+//         let b: Countdown = Countdown::new(1, 1);
+//         let (x, y): (usize, usize) = join!(a, b).wait;
+//         total += x * y;
+//         i += 1;
+//     }
+//     total
+// }
+
+// =================================
+// Into this:
+// =================================
+
+/// Waits on two futures at once, `n` times
+pub fn pairs(n: usize) -> impl Future<Output=usize> {
+    Coroutine1::new(n)
+}
+        
+enum State1 {
+    Start(usize),
+    Block1,
+    Wait2(Box<dyn Future<Output = usize>>, Box<dyn Future<Output = usize>>, Option<usize>, Option<usize>),
+    Resolved,
+}
+
+#[derive(Default)]
+struct Stack1 {
+    i: Option<usize>,
+    n: Option<usize>,
+    total: Option<usize>,
+}
+
+struct Coroutine1 {
+    stack: Stack1,
+    state: State1,
+}
+
+impl Coroutine1 {
+    fn new(n: usize) -> Self {
+        Self {
+            state: State1::Start(n),
+            stack: Stack1::default(),
+        }
+    }
+}
+
+
+impl Future for Coroutine1 {
+    type Output = usize;
+
+    #[allow(unused_mut)]
+    fn poll(&mut self) -> PollState<Self::Output> {
+        loop {
+            match self.state {
+                State1::Start(n) => {
+                    // ---- Code you actually wrote ----
+                    let mut total: usize = 0;
+                    let mut i: usize = 0;
+                    // ---------------------------------
+                    // Save stack
+                    self.stack.i = Some(i);
+                    self.stack.n = Some(n);
+                    self.stack.total = Some(total);
+                    self.state = State1::Block1;
+                }
+
+                State1::Block1 => {
+                    // Restore stack
+                    let mut i = self.stack.i.take().unwrap();
+                    let n = self.stack.n.take().unwrap();
+                    let mut total = self.stack.total.take().unwrap();
+                    // ---- Code you actually wrote ----
+
+                    // ---------------------------------
+                    if i < n {
+                        // ---- Code you actually wrote ----
+                        let a: Countdown = Countdown::new(i, 2);
+                        let b: Countdown = Countdown::new(1, 1);
+                        // ---------------------------------
+                        let fut2_0 = Box::new(a);
+                        let fut2_1 = Box::new(b);
+                        // Save stack
+                        self.stack.i = Some(i);
+                        self.stack.n = Some(n);
+                        self.stack.total = Some(total);
+                        self.state = State1::Wait2(fut2_0, fut2_1, None, None);
+                    } else {
+                        let output = total;
+                        self.state = State1::Resolved;
+                        self.stack = Stack1::default();
+                        break PollState::Ready(output);
+                    }
+                }
+
+                State1::Wait2(ref mut f2_0, ref mut f2_1, ref mut v2_0, ref mut v2_1) => {
+                    // Poll the futures that aren't ready yet
+                    if v2_0.is_none() {
+                        *v2_0 = match f2_0.poll() {
+                            PollState::Ready(value) => Some(value),
+                            PollState::NotReady => None,
+                        };
+                    }
+                    if v2_1.is_none() {
+                        *v2_1 = match f2_1.poll() {
+                            PollState::Ready(value) => Some(value),
+                            PollState::NotReady => None,
+                        };
+                    }
+                    if v2_0.is_none() || v2_1.is_none() {
+                        break PollState::NotReady;
+                    }
+                    let (x, y) = (v2_0.take().unwrap(), v2_1.take().unwrap());
+                    // Restore stack
+                    let mut i = self.stack.i.take().unwrap();
+                    let mut total = self.stack.total.take().unwrap();
+                    // ---- Code you actually wrote ----
+                    total += x * y;
+                    i += 1;
+                    // ---------------------------------
+                    // Save stack
+                    self.stack.i = Some(i);
+                    self.stack.total = Some(total);
+                    self.state = State1::Block1;
+                }
+
+                State1::Resolved => panic!("Polled a resolved future")
+            }
+        }
+    }
+}
+
+impl Drop for Coroutine1 {
+    fn drop(&mut self) {
+        if let State1::Wait2(..) = self.state {
+            self.state = State1::Resolved;
+            self.stack.total = None;
+            self.stack.i = None;
+        }
+    }
+}
@@ -0,0 +1,79 @@
+// The coroutines `benches/futures.rs` runs. Each future we wait on is in a
+// local with a type, so `--futures concrete` doesn't need a type alias.
+// After a change, write both versions again:
+//
+// ```text
+// corofy benches/futures/coroutines.rs benches/futures/boxed_corofied.rs
+// corofy --futures concrete benches/futures/coroutines.rs benches/futures/concrete_corofied.rs
+// ```
+
+pub trait Future {
+    type Output;
+    fn poll(&mut self) -> PollState<Self::Output>;
+}
+
+pub enum PollState<T> {
+    Ready(T),
+    NotReady,
+}
+
+/// Resolves to `value` after it's been polled `polls` times without a value
+pub struct Countdown {
+    value: usize,
+    polls: usize,
+}
+
+impl Countdown {
+    pub fn new(value: usize, polls: usize) -> Self {
+        Self { value, polls }
+    }
+}
+
+impl Future for Countdown {
+    type Output = usize;
+
+    fn poll(&mut self) -> PollState<usize> {
+        match self.polls {
+            0 => PollState::Ready(self.value),
+            _ => {
+                self.polls -= 1;
+                PollState::NotReady
+            }
+        }
+    }
+}
+
+pub fn block_on<F: Future>(mut fut: F) -> F::Output {
+    loop {
+        if let PollState::Ready(value) = fut.poll() {
+            return value;
+        }
+    }
+}
+
+/// Waits `n` times in a loop
+pub coroutine fn sum(n: usize) -> usize {
+    let mut total: usize = 0;
+    let mut i: usize = 0;
+    while i < n {
+        let fut: Countdown = Countdown::new(i, 1);
+        let value: usize = fut.wait;
+        total += value;
+        i += 1;
+    }
+    total
+}
+
+/// Waits on two futures at once, `n` times
+pub coroutine fn pairs(n: usize) -> usize {
+    let mut total: usize = 0;
+    let mut i: usize = 0;
+    while i < n {
+        let a: Countdown = Countdown::new(i, 2);
+        let b: Countdown = Countdown::new(1, 1);
+        let (x, y): (usize, usize) = join!(a, b).wait;
+        total += x * y;
+        i += 1;
+    }
+    total
+}
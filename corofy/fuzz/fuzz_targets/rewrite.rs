@@ -0,0 +1,21 @@
+//! `rewrite` returns a `ParseError` for anything it can't handle, it never
+//! panics. Start from the golden inputs so the fuzzer sees real coroutines:
+//!
+//! ```text
+//! cd corofy
+//! cargo +nightly fuzz run rewrite fuzz/corpus/rewrite tests/golden
+//! ```
+
+#![no_main]
+
+use std::io;
+
+use corofy::{Target, rewrite};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|src: &str| {
+    for target in Target::ALL {
+        // Errors are fine, a panic is what we're looking for
+        let _ = rewrite(src.to_string(), io::sink(), target);
+    }
+});
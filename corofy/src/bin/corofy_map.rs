@@ -0,0 +1,111 @@
+//! Points locations in the files corofy wrote back at the code they were
+//! generated from, using the source maps `corofy --map` writes next to them.
+//!
+//! ```text
+//! corofy_map src/main_corofied.rs:120:9
+//! cargo build 2>&1 | corofy_map
+//! ```
+//!
+//! With no locations, every `PATH.rs:LINE[:COL]` in stdin with a
+//! `PATH.rs.map` is replaced with `ORIGINAL:ORIGINAL_LINE` and the rest is
+//! passed on unchanged. Locations we can't map (no map, or a line we
+//! generated out of nothing) stay as they are.
+
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use corofy::SourceMap;
+
+/// What a location in a Rust file ends with, before its line
+const MARKER: &str = ".rs:";
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut maps = Maps::default();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        for arg in &args {
+            println!("{}", maps.translate(arg));
+        }
+        return Ok(());
+    }
+
+    let mut stdout = io::stdout().lock();
+    for line in io::stdin().lock().lines() {
+        match writeln!(stdout, "{}", maps.translate(&line?)) {
+            // Whoever reads us (`head`, say) has seen enough
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+            res => res?,
+        }
+    }
+    Ok(())
+}
+
+/// The maps we've read so far, `None` for the files that don't have one
+#[derive(Default)]
+struct Maps(HashMap<PathBuf, Option<SourceMap>>);
+
+impl Maps {
+    /// `text` with every location in a file we have a map for replaced with
+    /// the original location
+    fn translate(&mut self, text: &str) -> String {
+        let mut res = String::new();
+        let mut rest = text;
+        while let Some(i) = rest.find(MARKER) {
+            let end = i + MARKER.len();
+            // The path goes back to the last character that can't be in one
+            let start = rest[..i]
+                .rfind(|c: char| c.is_whitespace() || "\"'`(<[=".contains(c))
+                .map_or(0, |s| s + 1);
+            let path = &rest[start..end - 1];
+            let digits = rest[end..]
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len() - end);
+            let mut loc_end = end + digits;
+            // A column makes no sense in the original, it's dropped
+            if let Some(col) = rest[loc_end..].strip_prefix(':') {
+                let col_digits = col.find(|c: char| !c.is_ascii_digit()).unwrap_or(col.len());
+                if col_digits > 0 {
+                    loc_end += 1 + col_digits;
+                }
+            }
+
+            let original = rest[end..end + digits]
+                .parse()
+                .ok()
+                .and_then(|line| self.original(Path::new(path), line));
+            res.push_str(&rest[..start]);
+            match original {
+                Some((source, line)) => res.push_str(&format!("{}:{line}", source.display())),
+                None => res.push_str(&rest[start..loc_end]),
+            }
+            rest = &rest[loc_end..];
+        }
+        res.push_str(rest);
+        res
+    }
+
+    /// The original file and line of `line` in the generated file `path`
+    fn original(&mut self, path: &Path, line: usize) -> Option<(PathBuf, usize)> {
+        let map_path = PathBuf::from(format!("{}.map", path.display()));
+        let map = self
+            .0
+            .entry(map_path.clone())
+            .or_insert_with(|| fs::read_to_string(&map_path).ok()?.parse().ok())
+            .as_ref()?;
+        let original = map.original_line(line)?;
+        // The source is relative to the map, like the map is to the code
+        let source = match &map.source {
+            Some(source) => map_path
+                .parent()
+                .map_or_else(|| PathBuf::from(source), |dir| dir.join(source)),
+            None => path.to_path_buf(),
+        };
+        Some((source, original))
+    }
+}
@@ -0,0 +1,880 @@
+//! Splits the body of a coroutine into blocks at the `wait`s and at the
+//! control flow around them.
+//!
+//! Statements that don't contain a `wait` are copied as they are. An `if`,
+//! `match`, `loop` or `while` that contains one is taken apart: the
+//! condition ends the block it's in and each branch (or the loop body) gets
+//! blocks of its own. Every block ends with a `Term` that says where to go
+//! next.
+//!
+//! A block that can be reached from more than one place (the code after an
+//! `if`, the start of a loop), that comes right after a `wait` or that a
+//! `break`/`continue` jumps to gets its own state. Every other block is
+//! written out inside the block it's reached from.
+
+use std::ops::Range;
+
+use crate::hoist::Local;
+use crate::lexer::{Delim, Token, TokenKind};
+use crate::{
+    AwaitPoint, DEFAULT_OUTPUT, Owner, ParseError, Stmt, W_KW, await_point, brace_owner,
+    default_output, find_bindings, find_returns, find_top_level, find_top_level_ident, find_uses,
+    is_tail, is_unit, is_wait, parse_await, parse_stmt, snippet, split_statements, stmt_head,
+    trim_semi,
+};
+
+pub struct Block {
+    /// Locals the block declares before its code runs: the function
+    /// arguments, the value of a `wait` or the bindings of an `if let` or a
+    /// `match` arm
+    pub decls: Vec<Local>,
+    /// The source code the block covers (a byte range)
+    pub code: Range<usize>,
+    pub stmts: Vec<Stmt>,
+    /// `break`s and `continue`s in `stmts` that leave a loop we took apart,
+    /// as token ranges, and the block they jump to
+    pub jumps: Vec<(Range<usize>, usize)>,
+    pub term: Term,
+    /// Locals the terminator reads
+    pub term_uses: Vec<String>,
+}
+
+/// How a block ends. Ranges are token ranges.
+pub enum Term {
+    Goto(usize),
+    If {
+        cond: Range<usize>,
+        then: usize,
+        els: usize,
+    },
+    Match {
+        scrutinee: Range<usize>,
+        /// The pattern (including the guard) and the block of each arm
+        arms: Vec<(Range<usize>, usize)>,
+    },
+    /// Waits on `Cfg::awaits[point]` and continues with `resume`
+    Await {
+        point: usize,
+        resume: usize,
+    },
+    /// The end of the function body, the coroutine resolves to the value.
+    /// `None` if we don't know what to resolve to.
+    Return(Option<String>),
+    /// The code ends with a `return`, `break` or `continue`
+    Diverge,
+}
+
+impl Term {
+    fn succs(&self) -> Vec<usize> {
+        match self {
+            Term::Goto(next) => vec![*next],
+            Term::If { then, els, .. } => vec![*then, *els],
+            Term::Match { arms, .. } => arms.iter().map(|&(_, arm)| arm).collect(),
+            Term::Await { resume, .. } => vec![*resume],
+            Term::Return(_) | Term::Diverge => vec![],
+        }
+    }
+}
+
+pub struct Cfg {
+    /// The first block is where the function starts
+    pub blocks: Vec<Block>,
+    pub awaits: Vec<AwaitPoint>,
+    /// Token index of the `wait` of every await point
+    pub wait_tokens: Vec<usize>,
+}
+
+impl Cfg {
+    /// The blocks each block can continue to
+    pub fn succs(&self) -> Vec<Vec<usize>> {
+        self.blocks
+            .iter()
+            .map(|b| {
+                let mut succs = b.term.succs();
+                succs.extend(b.jumps.iter().map(|&(_, target)| target));
+                succs
+            })
+            .collect()
+    }
+
+    /// The await point `block` continues from, if it's the block after one
+    pub fn point(&self, block: usize) -> Option<&AwaitPoint> {
+        self.blocks.iter().find_map(|b| match b.term {
+            Term::Await { point, resume } if resume == block => Some(&self.awaits[point]),
+            _ => None,
+        })
+    }
+
+    /// The blocks that can run at all
+    pub fn reachable(&self) -> Vec<bool> {
+        let succs = self.succs();
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut todo = vec![0];
+        while let Some(block) = todo.pop() {
+            if !reachable[block] {
+                reachable[block] = true;
+                todo.extend(&succs[block]);
+            }
+        }
+        reachable
+    }
+
+    /// The blocks that need a state of their own
+    pub fn states(&self, reachable: &[bool]) -> Vec<bool> {
+        let mut preds = vec![0; self.blocks.len()];
+        let mut states = vec![false; self.blocks.len()];
+        states[0] = true;
+        for (block, succs) in self.succs().into_iter().enumerate() {
+            if !reachable[block] {
+                continue;
+            }
+            for next in succs {
+                preds[next] += 1;
+            }
+            for &(_, target) in &self.blocks[block].jumps {
+                states[target] = true;
+            }
+            if let Term::Await { resume, .. } = self.blocks[block].term {
+                states[resume] = true;
+            }
+        }
+        for (block, &n) in preds.iter().enumerate() {
+            states[block] |= n > 1;
+        }
+        states
+    }
+}
+
+/// A loop we took apart
+struct Loop {
+    label: Option<String>,
+    /// Where `continue` goes
+    head: usize,
+    /// Where `break` goes
+    exit: usize,
+}
+
+pub struct Builder<'a> {
+    src: &'a str,
+    tokens: &'a [Token],
+    /// The return type of the coroutine
+    ret: Option<&'a str>,
+    cfg: Cfg,
+    loops: Vec<Loop>,
+    /// The block we're adding statements to
+    cur: usize,
+}
+
+impl<'a> Builder<'a> {
+    /// `start` is the byte offset where the function body starts, `decls`
+    /// the function arguments and `ret` the return type
+    pub fn new(
+        src: &'a str,
+        tokens: &'a [Token],
+        start: usize,
+        decls: Vec<Local>,
+        ret: Option<&'a str>,
+    ) -> Self {
+        let mut builder = Self {
+            src,
+            tokens,
+            ret,
+            cfg: Cfg {
+                blocks: vec![],
+                awaits: vec![],
+                wait_tokens: vec![],
+            },
+            loops: vec![],
+            cur: 0,
+        };
+        let entry = builder.new_block();
+        builder.cfg.blocks[entry].decls = decls;
+        builder.start(entry, start);
+        builder
+    }
+
+    pub fn finish(self) -> Cfg {
+        self.cfg
+    }
+
+    /// Adds the statements (token ranges) to the current block, splitting it
+    /// up where necessary
+    pub fn lower(&mut self, stmts: Vec<Range<usize>>) -> Result<(), ParseError> {
+        for stmt in stmts {
+            self.lower_stmt(stmt)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the statements of the function body, or of a branch of an `if`
+    /// or `match` at its end, and ends the block it runs off the end in with
+    /// the value the coroutine resolves to. `close` is the token index of the
+    /// closing brace.
+    pub fn lower_body(
+        &mut self,
+        mut stmts: Vec<Range<usize>>,
+        close: usize,
+    ) -> Result<(), ParseError> {
+        let (src, tokens, ret) = (self.src, self.tokens, self.ret);
+        // The tail expression (or a `return` at the very end) is the value
+        // the coroutine resolves to
+        let tail = stmts.pop_if(|stmt| {
+            is_tail(src, tokens, stmt, ret.is_some()) && !self.contains_wait(stmt.clone())
+        });
+
+        // An `if` or `match` at the end that waits resolves the coroutine in
+        // each of its branches instead
+        if tail.is_none()
+            && ret.is_some_and(|ty| !is_unit(ty))
+            && let Some(last) = stmts
+                .pop_if(|stmt| is_tail(src, tokens, stmt, true) && self.branches(stmt).is_some())
+        {
+            self.lower(stmts)?;
+            let head = self.branches(&last).unwrap();
+            let last_end = tokens[last.end - 1].span.end;
+            if tokens[head].is_ident(src, "if") {
+                let join = self.new_block();
+                self.lower_if(head, join, true)?;
+                self.start(join, last_end);
+            } else {
+                self.lower_match(head, true)?;
+                let cur = self.cur;
+                self.start(cur, last_end);
+            }
+            // Every branch resolved the coroutine, nothing gets here
+            self.end(tokens[close].span.start, Term::Diverge, vec![]);
+            return Ok(());
+        }
+
+        let last = stmts.last().cloned();
+        self.lower(stmts)?;
+
+        // The value the coroutine resolves to when it runs off the end
+        let mut tail_uses = vec![];
+        let tail_await = match (&tail, &last) {
+            (None, Some(last)) => self.tail_await(last),
+            _ => None,
+        };
+        let value = if let Some(tail) = &tail {
+            tail_uses = find_uses(src, tokens, tail.clone());
+            let head = stmt_head(src, tokens, tail.clone());
+            let expr = match tokens[head].is_ident(src, "return") {
+                true => head + 1..tail.end,
+                false => tail.clone(),
+            };
+            let expr = trim_semi(src, tokens, expr);
+            Some(match expr.is_empty() {
+                true => default_output(ret),
+                false => {
+                    let bytes = tokens[expr.start].span.start..tokens[expr.end - 1].span.end;
+                    snippet(src, bytes, vec![])
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect()
+                }
+            })
+        } else if let Some(fut) = tail_await {
+            if fut.propagate {
+                return Err(ParseError::new(
+                    fut.span,
+                    format!(
+                        "The value of `.{W_KW}?` can't be what the coroutine resolves to. Bind \
+                         it with `let` and resolve to `Ok(value)`."
+                    ),
+                ));
+            }
+            // `fut.wait` at the very end, we resolve to its value
+            fut.pattern = "output".to_string();
+            fut.output = ret.unwrap_or(DEFAULT_OUTPUT).to_string();
+            fut.check_output()?;
+            Some("output".to_string())
+        } else {
+            match ret {
+                Some(ty) if !is_unit(ty) => None,
+                ret => Some(default_output(ret)),
+            }
+        };
+        let end = tail.as_ref().map_or(close, |tail| tail.start);
+        self.end(tokens[end].span.start, Term::Return(value), tail_uses);
+        Ok(())
+    }
+
+    /// The await point the last statement we lowered ended with, if it's an
+    /// expression (no `;`, no `let`)
+    fn tail_await(&mut self, stmt: &Range<usize>) -> Option<&mut AwaitPoint> {
+        let (src, tokens) = (self.src, self.tokens);
+        let dot = await_point(src, tokens, stmt)?;
+        let is_expr = !tokens[stmt.end - 1].is_punct(src, ";")
+            && !tokens[stmt.start].is_ident(src, "let")
+            && self.cfg.wait_tokens.last() == Some(&(dot + 1));
+        is_expr.then(|| self.cfg.awaits.last_mut().unwrap())
+    }
+
+    fn new_block(&mut self) -> usize {
+        self.cfg.blocks.push(Block {
+            decls: vec![],
+            code: 0..0,
+            stmts: vec![],
+            jumps: vec![],
+            term: Term::Diverge,
+            term_uses: vec![],
+        });
+        self.cfg.blocks.len() - 1
+    }
+
+    /// Makes `block` the current block, its code starts at byte offset `pos`
+    fn start(&mut self, block: usize, pos: usize) {
+        self.cur = block;
+        self.cfg.blocks[block].code = pos..pos;
+    }
+
+    /// Ends the current block at byte offset `pos`
+    fn end(&mut self, pos: usize, term: Term, uses: Vec<String>) {
+        let block = &mut self.cfg.blocks[self.cur];
+        block.code.end = pos.max(block.code.start);
+        block.term = term;
+        block.term_uses = uses;
+    }
+
+    fn contains_wait(&self, range: Range<usize>) -> bool {
+        range.into_iter().any(|i| is_wait(self.src, self.tokens, i))
+    }
+
+    fn lower_stmt(&mut self, stmt: Range<usize>) -> Result<(), ParseError> {
+        let (src, tokens) = (self.src, self.tokens);
+        let head = stmt_head(src, tokens, stmt.clone());
+        let stmt_start = tokens[stmt.start].span.start;
+        let stmt_end = tokens[stmt.end - 1].span.end;
+
+        if !self.contains_wait(stmt.clone()) {
+            return self.lower_plain(stmt, head);
+        }
+
+        if let Some(dot) = await_point(src, tokens, &stmt)
+            && !self.contains_wait(stmt.start..dot)
+        {
+            let point = parse_await(src, tokens, stmt.clone(), dot)?;
+            let resume = self.new_block();
+            self.cfg.blocks[resume].decls = point.decls.clone();
+            let uses = point.uses.clone();
+            let idx = self.cfg.awaits.len();
+            self.cfg.awaits.push(point);
+            self.cfg.wait_tokens.push(dot + 1);
+            self.end(stmt_start, Term::Await { point: idx, resume }, uses);
+            self.start(resume, stmt_end);
+            return Ok(());
+        }
+
+        let tok = &tokens[head];
+        if !self.is_whole(&stmt, head) {
+            // Not something we can split, the `wait` is reported later
+            return self.lower_plain(stmt, head);
+        }
+
+        if tok.is_ident(src, "for") {
+            return Err(ParseError::new(
+                tok.span,
+                format!(
+                    "`{W_KW}` inside a `for` loop is not supported since we can't store the \
+                     iterator without knowing its type. Use `while let Some(x) = iter.next()` \
+                     with a typed `let mut iter: Type = ...` instead."
+                ),
+            ));
+        }
+
+        if tok.is_ident(src, "if") {
+            let join = self.new_block();
+            self.lower_if(head, join, false)?;
+            self.start(join, stmt_end);
+        } else if tok.is_ident(src, "match") {
+            self.lower_match(head, false)?;
+            let cur = self.cur;
+            self.start(cur, stmt_end);
+        } else if tok.is_ident(src, "loop") || tok.is_ident(src, "while") {
+            self.lower_loop(stmt.start, head)?;
+            let cur = self.cur;
+            self.start(cur, stmt_end);
+        } else {
+            // A plain block
+            let close = tok.pair().unwrap();
+            let inner = self.new_block();
+            self.end(tok.span.start, Term::Goto(inner), vec![]);
+            self.start(inner, tok.span.end);
+            self.lower(split_statements(src, tokens, head + 1..close))?;
+            let after = self.new_block();
+            self.end(tokens[close].span.start, Term::Goto(after), vec![]);
+            self.start(after, stmt_end);
+        }
+        Ok(())
+    }
+
+    /// Whether `stmt` is the `if`, `match`, loop or block that starts at
+    /// `head` and nothing else but a `;`. `if a {} else {}.len()` is not
+    /// something we can take apart.
+    fn is_whole(&self, stmt: &Range<usize>, head: usize) -> bool {
+        let (src, tokens) = (self.src, self.tokens);
+        let tok = &tokens[head];
+        let construct_end = match tok.text(src) {
+            "if" if tok.kind == TokenKind::Ident => self.if_end(head),
+            "match" | "loop" | "while" | "for" if tok.kind == TokenKind::Ident => self
+                .first_brace(head + 1..stmt.end)
+                .map(|open| tokens[open].pair().unwrap() + 1),
+            _ if tok.is_open(Delim::Brace) => Some(tok.pair().unwrap() + 1),
+            _ => None,
+        };
+        construct_end.is_some_and(|end| {
+            end == stmt.end || (end + 1 == stmt.end && tokens[end].is_punct(src, ";"))
+        })
+    }
+
+    /// The `if` or `match` token of `stmt`, if it's an `if` or `match` with
+    /// a `wait` in it that we can take apart
+    fn branches(&self, stmt: &Range<usize>) -> Option<usize> {
+        let (src, tokens) = (self.src, self.tokens);
+        let head = stmt_head(src, tokens, stmt.clone());
+        let tok = &tokens[head];
+        ((tok.is_ident(src, "if") || tok.is_ident(src, "match"))
+            && self.contains_wait(stmt.clone())
+            && self.is_whole(stmt, head))
+        .then_some(head)
+    }
+
+    /// A statement we copy as it is
+    fn lower_plain(&mut self, stmt: Range<usize>, head: usize) -> Result<(), ParseError> {
+        let (src, tokens) = (self.src, self.tokens);
+        let stmt_end = tokens[stmt.end - 1].span.end;
+        let jumps = self.find_jumps(stmt.clone())?;
+        // Each of them is replaced by code that switches states, so they
+        // can't be part of the value of a `return` we replace
+        let returns = find_returns(src, tokens, stmt.clone());
+        let nested = returns
+            .iter()
+            .map(|r| r.start)
+            .chain(jumps.iter().map(|(j, _)| j.start))
+            .find(|&i| returns.iter().any(|r| r.start < i && i < r.end));
+        if let Some(nested) = nested {
+            return Err(ParseError::new(
+                tokens[nested].span,
+                format!(
+                    "`{}` inside the value of a `return` is not supported",
+                    tokens[nested].text(src)
+                ),
+            ));
+        }
+        let diverges = ["return", "break", "continue"]
+            .iter()
+            .any(|kw| tokens[head].is_ident(src, kw));
+        let parsed = parse_stmt(src, tokens, stmt)?;
+
+        let block = &mut self.cfg.blocks[self.cur];
+        block.stmts.push(parsed);
+        block.jumps.extend(jumps.iter().cloned());
+
+        // Anything after a jump starts a new block, so the jump is the last
+        // thing that happens in the block it's in
+        if diverges {
+            let dead = self.new_block();
+            self.end(stmt_end, Term::Diverge, vec![]);
+            self.start(dead, stmt_end);
+        } else if !jumps.is_empty() {
+            let next = self.new_block();
+            self.end(stmt_end, Term::Goto(next), vec![]);
+            self.start(next, stmt_end);
+        }
+        Ok(())
+    }
+
+    /// `if cond { .. } else if cond { .. } else { .. }` starting at the `if`
+    /// token. Every branch continues with `join`, or resolves the coroutine
+    /// if the `if` is the `tail` of the function body.
+    fn lower_if(&mut self, if_tok: usize, join: usize, tail: bool) -> Result<(), ParseError> {
+        let (src, tokens) = (self.src, self.tokens);
+        let open = self.first_brace(if_tok + 1..tokens.len()).unwrap();
+        let close = tokens[open].pair().unwrap();
+        let cond = if_tok + 1..open;
+        if cond.is_empty() {
+            return Err(ParseError::new(
+                tokens[if_tok].span,
+                "Expected a condition after `if`",
+            ));
+        }
+        let has_else = tokens
+            .get(close + 1)
+            .is_some_and(|t| t.is_ident(src, "else"));
+
+        let then = self.new_block();
+        self.cfg.blocks[then].decls = self.let_bindings(cond.clone());
+        let els = if has_else { self.new_block() } else { join };
+        let uses = self.cond_uses(cond.clone());
+        self.end(
+            tokens[if_tok].span.start,
+            Term::If { cond, then, els },
+            uses,
+        );
+
+        self.start(then, tokens[open].span.end);
+        self.lower_branch(open + 1..close, join, tail)?;
+
+        if has_else {
+            let else_tok = close + 1;
+            let next = &tokens[else_tok + 1];
+            if next.is_ident(src, "if") {
+                self.start(els, next.span.start);
+                self.lower_if(else_tok + 1, join, tail)?;
+            } else {
+                let close = next.pair().unwrap();
+                self.start(els, next.span.end);
+                self.lower_branch(else_tok + 2..close, join, tail)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The statements in `body`, a branch of an `if` or a `match` arm in
+    /// braces, continued with `join` or resolving the coroutine if it's the
+    /// `tail` of the function body
+    fn lower_branch(
+        &mut self,
+        body: Range<usize>,
+        join: usize,
+        tail: bool,
+    ) -> Result<(), ParseError> {
+        let stmts = split_statements(self.src, self.tokens, body.clone());
+        match tail {
+            true => self.lower_body(stmts, body.end),
+            false => {
+                self.lower(stmts)?;
+                self.end(self.tokens[body.end].span.start, Term::Goto(join), vec![]);
+                Ok(())
+            }
+        }
+    }
+
+    /// `match scrutinee { pat => .., }`. Leaves the block after the `match`
+    /// as the current block, which no arm continues with if the `match` is
+    /// the `tail` of the function body.
+    fn lower_match(&mut self, head: usize, tail: bool) -> Result<(), ParseError> {
+        let (src, tokens) = (self.src, self.tokens);
+        let open = self.first_brace(head + 1..tokens.len()).unwrap();
+        let close = tokens[open].pair().unwrap();
+        let scrutinee = head + 1..open;
+        if scrutinee.is_empty() {
+            return Err(ParseError::new(
+                tokens[head].span,
+                "Expected an expression after `match`",
+            ));
+        }
+        let mut uses = find_uses(src, tokens, scrutinee.clone());
+
+        // pattern, body and whether the body is a block
+        let mut parsed = vec![];
+        let mut i = open + 1;
+        while i < close {
+            let Some(arrow) = find_top_level(src, tokens, i..close, "=>") else {
+                return Err(ParseError::new(tokens[i].span, "Expected `=>`"));
+            };
+            let pattern = i..arrow;
+            if pattern.is_empty() {
+                return Err(ParseError::new(
+                    tokens[arrow].span,
+                    "Expected a pattern before `=>`",
+                ));
+            }
+            let guard = find_top_level_ident(src, tokens, pattern.clone(), "if");
+            let bindings = find_bindings(src, tokens, i..guard.unwrap_or(arrow));
+            if let Some(guard) = guard {
+                // The guard can use the bindings, they're declared by then
+                let guard_uses = find_uses(src, tokens, guard + 1..arrow);
+                uses.extend(
+                    guard_uses
+                        .into_iter()
+                        .filter(|u| !bindings.iter().any(|(name, _)| name == u)),
+                );
+            }
+
+            let body_start = arrow + 1;
+            let (body, block, next) = match tokens.get(body_start) {
+                Some(t) if body_start < close && t.is_open(Delim::Brace) => {
+                    let pair = t.pair().unwrap();
+                    let next = match tokens[pair + 1].is_punct(src, ",") {
+                        true => pair + 2,
+                        false => pair + 1,
+                    };
+                    (body_start + 1..pair, true, next)
+                }
+                _ => {
+                    let end = find_top_level(src, tokens, body_start..close, ",").unwrap_or(close);
+                    // `_ => ()` doesn't do anything
+                    let body = match tokens[body_start].pair() {
+                        Some(pair) if pair == body_start + 1 && pair + 1 == end => end..end,
+                        _ => body_start..end,
+                    };
+                    (body, false, (end + 1).min(close))
+                }
+            };
+            if body_start == close || tokens[body_start].is_punct(src, ",") {
+                return Err(ParseError::new(
+                    tokens[arrow].span,
+                    "Expected an expression after `=>`",
+                ));
+            }
+            parsed.push((pattern, bindings, body, block));
+            i = next;
+        }
+
+        let join = self.new_block();
+        let mut arms = vec![];
+        for (pattern, bindings, _, _) in &parsed {
+            let arm = self.new_block();
+            self.cfg.blocks[arm].decls = bindings
+                .iter()
+                .map(|(name, span)| Local {
+                    name: name.clone(),
+                    mutable: false,
+                    ty: None,
+                    simple: false,
+                    span: *span,
+                })
+                .collect();
+            arms.push((pattern.clone(), arm));
+        }
+        self.end(
+            tokens[head].span.start,
+            Term::Match {
+                scrutinee,
+                arms: arms.clone(),
+            },
+            uses,
+        );
+
+        for ((_, _, body, block), (_, arm)) in parsed.into_iter().zip(arms) {
+            let (start, end) = match block {
+                true => (tokens[body.start - 1].span.end, tokens[body.end].span.start),
+                false if body.is_empty() => {
+                    let pos = tokens[body.start - 1].span.end;
+                    (pos, pos)
+                }
+                false => (tokens[body.start].span.start, tokens[body.end - 1].span.end),
+            };
+            self.start(arm, start);
+            match tail {
+                true => self.lower_body(split_statements(src, tokens, body.clone()), body.end)?,
+                false => {
+                    self.lower(split_statements(src, tokens, body))?;
+                    self.end(end, Term::Goto(join), vec![]);
+                }
+            }
+        }
+
+        self.cur = join;
+        Ok(())
+    }
+
+    /// `loop { .. }` or `while cond { .. }` with an optional label. Leaves
+    /// the block after the loop as the current block.
+    fn lower_loop(&mut self, stmt_start: usize, head: usize) -> Result<(), ParseError> {
+        let (src, tokens) = (self.src, self.tokens);
+        let label = (head >= stmt_start + 2
+            && tokens[head - 2].kind == TokenKind::Lifetime
+            && tokens[head - 1].is_punct(src, ":"))
+        .then(|| tokens[head - 2].text(src).to_string());
+        let loop_start = match label {
+            Some(_) => tokens[head - 2].span.start,
+            None => tokens[head].span.start,
+        };
+        let open = self.first_brace(head + 1..tokens.len()).unwrap();
+        let close = tokens[open].pair().unwrap();
+
+        let loop_head = self.new_block();
+        let exit = self.new_block();
+        self.end(loop_start, Term::Goto(loop_head), vec![]);
+
+        let body = if tokens[head].is_ident(src, "while") {
+            let cond = head + 1..open;
+            if cond.is_empty() {
+                return Err(ParseError::new(
+                    tokens[head].span,
+                    "Expected a condition after `while`",
+                ));
+            }
+            let body = self.new_block();
+            self.cfg.blocks[body].decls = self.let_bindings(cond.clone());
+            let uses = self.cond_uses(cond.clone());
+            self.start(loop_head, tokens[open].span.start);
+            self.end(
+                tokens[open].span.start,
+                Term::If {
+                    cond,
+                    then: body,
+                    els: exit,
+                },
+                uses,
+            );
+            body
+        } else {
+            loop_head
+        };
+
+        self.loops.push(Loop {
+            label,
+            head: loop_head,
+            exit,
+        });
+        self.start(body, tokens[open].span.end);
+        self.lower(split_statements(src, tokens, open + 1..close))?;
+        self.end(tokens[close].span.start, Term::Goto(loop_head), vec![]);
+        self.loops.pop();
+
+        self.cur = exit;
+        Ok(())
+    }
+
+    /// The `break`s and `continue`s in a statement we copy that leave one of
+    /// the loops we took apart
+    fn find_jumps(&self, stmt: Range<usize>) -> Result<Vec<(Range<usize>, usize)>, ParseError> {
+        let (src, tokens) = (self.src, self.tokens);
+        let mut res = vec![];
+        if self.loops.is_empty() {
+            return Ok(res);
+        }
+
+        // The groups we're in
+        let mut groups: Vec<usize> = vec![];
+        for i in stmt.clone() {
+            let tok = &tokens[i];
+            match tok.kind {
+                TokenKind::Open { .. } => groups.push(i),
+                TokenKind::Close { .. } => {
+                    groups.pop();
+                }
+                TokenKind::Ident if tok.is_ident(src, "break") || tok.is_ident(src, "continue") => {
+                    let owners: Vec<Owner> = groups
+                        .iter()
+                        .map(|&g| brace_owner(src, tokens, g))
+                        .collect();
+                    if owners.contains(&Owner::Fn) {
+                        continue;
+                    }
+                    let in_loop = owners.contains(&Owner::Loop);
+
+                    let label = tokens
+                        .get(i + 1)
+                        .filter(|t| i + 1 < stmt.end && t.kind == TokenKind::Lifetime)
+                        .map(|t| t.text(src));
+                    let target = match label {
+                        Some(label) => self
+                            .loops
+                            .iter()
+                            .rev()
+                            .find(|l| l.label.as_deref() == Some(label)),
+                        // Leaves a loop that doesn't have a `wait`
+                        None if in_loop => None,
+                        None => self.loops.last(),
+                    };
+                    let Some(target) = target else { continue };
+                    if in_loop {
+                        return Err(ParseError::new(
+                            tok.span,
+                            format!(
+                                "Jumping from a loop without `{W_KW}` to a labeled loop with one \
+                                 is not supported"
+                            ),
+                        ));
+                    }
+
+                    let mut end = i + 1 + label.is_some() as usize;
+                    let ends_expr = end >= stmt.end
+                        || matches!(tokens[end].kind, TokenKind::Close { .. })
+                        || tokens[end].is_punct(src, ";")
+                        || tokens[end].is_punct(src, ",");
+                    if !ends_expr {
+                        return Err(ParseError::new(
+                            tok.span,
+                            format!(
+                                "`break` with a value is not supported in a loop with `{W_KW}`"
+                            ),
+                        ));
+                    }
+                    if end < stmt.end && tokens[end].is_punct(src, ";") {
+                        end += 1;
+                    }
+
+                    let block = match tok.is_ident(src, "break") {
+                        true => target.exit,
+                        false => target.head,
+                    };
+                    res.push((i..end, block));
+                }
+                _ => (),
+            }
+        }
+        Ok(res)
+    }
+
+    /// The locals bound by `let pat = expr` in an `if` or `while` condition
+    fn let_bindings(&self, cond: Range<usize>) -> Vec<Local> {
+        let (src, tokens) = (self.src, self.tokens);
+        if !tokens[cond.start].is_ident(src, "let") {
+            return vec![];
+        }
+        let eq = find_top_level(src, tokens, cond.clone(), "=").unwrap_or(cond.end);
+        find_bindings(src, tokens, cond.start + 1..eq)
+            .into_iter()
+            .map(|(name, span)| Local {
+                name,
+                mutable: false,
+                ty: None,
+                simple: false,
+                span,
+            })
+            .collect()
+    }
+
+    /// The locals an `if` or `while` condition reads (the pattern of a
+    /// `let` doesn't read anything)
+    fn cond_uses(&self, cond: Range<usize>) -> Vec<String> {
+        let (src, tokens) = (self.src, self.tokens);
+        let expr = match tokens[cond.start].is_ident(src, "let") {
+            true => find_top_level(src, tokens, cond.clone(), "=").map_or(cond.end, |eq| eq + 1),
+            false => cond.start,
+        };
+        find_uses(src, tokens, expr..cond.end)
+    }
+
+    /// The first brace group in `range` that's not nested in another group
+    fn first_brace(&self, range: Range<usize>) -> Option<usize> {
+        let mut i = range.start;
+        while i < range.end {
+            match self.tokens[i].kind {
+                TokenKind::Open {
+                    delim: Delim::Brace,
+                    ..
+                } => return Some(i),
+                TokenKind::Open { pair, .. } => i = pair + 1,
+                _ => i += 1,
+            }
+        }
+        None
+    }
+
+    /// The token after an `if .. else if .. else ..` chain, `None` if it's
+    /// not a complete one
+    fn if_end(&self, if_tok: usize) -> Option<usize> {
+        let (src, tokens) = (self.src, self.tokens);
+        let mut open = self.first_brace(if_tok + 1..tokens.len())?;
+        loop {
+            let close = tokens[open].pair().unwrap();
+            if !tokens
+                .get(close + 1)
+                .is_some_and(|t| t.is_ident(src, "else"))
+            {
+                return Some(close + 1);
+            }
+            open = match tokens.get(close + 2) {
+                Some(t) if t.is_ident(src, "if") => self.first_brace(close + 3..tokens.len())?,
+                Some(t) if t.is_open(Delim::Brace) => close + 2,
+                _ => return None,
+            };
+        }
+    }
+}
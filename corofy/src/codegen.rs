@@ -0,0 +1,1708 @@
+//! Writes the code for a `Program`: the plain functions that replace the
+//! `coroutine fn`s and a state machine for each of them.
+
+use std::borrow::Cow;
+use std::fmt::Write;
+
+use crate::ir::{
+    Awaited, Coroutine, Fragment, Hoisted, Node, Program, Receiver, Step, Storage, Wait,
+};
+use crate::is_ident;
+use crate::sourcemap::SourceMap;
+use crate::target::{Futures, Target};
+
+/// The source of `program` with every coroutine rewritten into a state
+/// machine implementing the `Future` trait of `target`
+pub fn generate(program: &Program, target: Target) -> String {
+    generate_with_map(program, target).0
+}
+
+/// Like `generate`, with the line in `program` each line of the code comes
+/// from. The map has no `source`, we don't know where `program` came from.
+pub fn generate_with_map(program: &Program, target: Target) -> (String, SourceMap) {
+    let mut res = String::new();
+    // The state machines of the methods in an `impl` block that hasn't ended
+    // yet, with the line it ends on
+    let mut after_impl: Vec<(usize, String)> = vec![];
+    let last = program.rest.len() - 1;
+    for (i, (line, text)) in program.rest.iter().enumerate() {
+        let coroutine = program.coroutines.get(i);
+        let pad = {
+            let line = last_line(text);
+            &line[..line.len() - line.trim_start().len()]
+        };
+        // A free coroutine takes its indentation along. In place, the code
+        // goes where it was, indented like it.
+        let text = match coroutine {
+            Some(c) if c.method.is_none() => text.strip_suffix(pad).unwrap_or(text),
+            _ => text,
+        };
+        res.push_str(&fallback(None));
+        res.push_str(&mark_rest(text, *line, i == last, &mut after_impl));
+        // A method stays in its `impl` block, only its state machine can't
+        if let Some(coroutine) = coroutine.filter(|c| c.method.is_some()) {
+            let method = new_async_fn(coroutine, target);
+            res.push_str(&fallback(Some(coroutine.line)));
+            res.push_str(&method.trim_end().replace('\n', &format!("\n{pad}")));
+        }
+        if let Some(coroutine) = coroutine.filter(|_| program.in_place) {
+            let code = render_coroutine(program, coroutine, target);
+            match &coroutine.method {
+                Some(method) => after_impl.push((method.close, code)),
+                None => res.push_str(&pad_lines(&code, pad)),
+            }
+        }
+    }
+    if !program.in_place {
+        for coroutine in &program.coroutines {
+            res.push_str(&render_coroutine(program, coroutine, target));
+        }
+    }
+    let (res, lines) = strip_markers(&res);
+    // The code we generate uses `\n`, the code you wrote might not
+    let res = program.line_ending.apply(&res);
+    (
+        res,
+        SourceMap {
+            source: None,
+            lines,
+        },
+    )
+}
+
+/// The code you wrote between two coroutines, which starts on `line`, with
+/// the state machines in `after_impl` after the `impl` blocks that end in it
+fn mark_rest(
+    text: &str,
+    line: usize,
+    is_last: bool,
+    after_impl: &mut Vec<(usize, String)>,
+) -> String {
+    let mut res = String::new();
+    let (mut start, mut start_line) = (0, line);
+    while let Some((close, _)) = after_impl.first() {
+        // The end of the line the block ends on
+        let n = close.saturating_sub(line);
+        let end = match text.match_indices('\n').nth(n) {
+            Some((end, _)) => end,
+            None if is_last => text.len(),
+            None => break,
+        };
+        let (_, code) = after_impl.remove(0);
+        let close_line = last_line(&text[..end]);
+        let pad = &close_line[..close_line.len() - close_line.trim_start().len()];
+        res.push_str(&mark_lines(&text[start..end], start_line));
+        res.push_str(&pad_lines(&code, pad));
+        res.push_str(&fallback(None));
+        (start, start_line) = (end, line + n);
+    }
+    res.push_str(&mark_lines(&text[start..], start_line));
+    res
+}
+
+/// The original of `coroutine` in a comment and what we rewrite it into:
+/// its state machine, and the plain function unless it's a method
+fn render_coroutine(program: &Program, coroutine: &Coroutine, target: Target) -> String {
+    // Everything we add for the coroutine comes from its `coroutine fn` line
+    let mut res = fallback(Some(coroutine.line));
+    // The original ends with the closing brace, which `comment_orig` adds.
+    // Its lines after the first are indented like the item, which the
+    // closing brace is, and `pad_lines` indents all of them again.
+    let original = &coroutine.original;
+    let close = last_line(original);
+    let pad = &close[..close.len() - close.trim_start().len()];
+    let original: Vec<&str> = original
+        .strip_suffix('}')
+        .unwrap_or(original)
+        .trim_end()
+        .split('\n')
+        .map(|line| line.strip_prefix(pad).unwrap_or(line))
+        .collect();
+    res.push_str(&comment_orig(&mark_lines(
+        &original.join("\n"),
+        coroutine.line,
+    )));
+    if coroutine.method.is_none() {
+        res.push_str(&new_async_fn(coroutine, target));
+    }
+    let (log_cancel, futures) = (program.log_cancel, program.futures);
+    res.push_str(
+        &Machine {
+            coroutine,
+            target,
+            log_cancel,
+            futures,
+        }
+        .render(),
+    );
+    res
+}
+
+/// The plain function that replaces `coroutine`, with its state machine
+/// inside it. That's what the `#[coroutine]` attribute expands to: the
+/// state machines of several coroutines in a module can't clash there. The
+/// state machine is the one `generate` writes with boxed futures and without
+/// `log_cancel`.
+pub fn generate_nested(coroutine: &Coroutine, target: Target) -> String {
+    let (signature, arg_names) = signature(coroutine, target);
+    let id = &coroutine.id;
+    // Nobody reads this, so it isn't indented
+    let machine = Machine {
+        coroutine,
+        target,
+        log_cancel: false,
+        futures: Futures::Boxed,
+    };
+    let (machine, _) = strip_markers(&machine.render());
+
+    format!(
+        "{signature} {{
+{machine}
+
+    Coroutine{id}::new{arg_names}
+}}
+"
+    )
+}
+
+/// Format and comment out the original "async" function
+fn comment_orig(orig: &str) -> String {
+    let mut res = String::new();
+    writeln!(
+        &mut res,
+        "
+
+// =================================
+// We rewrite this:
+// =================================
+"
+    )
+    .unwrap();
+    for line in orig.lines() {
+        writeln!(&mut res, "// {line}").unwrap();
+    }
+    writeln!(
+        &mut res,
+        "// }}
+
+// =================================
+// Into this:
+// =================================
+"
+    )
+    .unwrap();
+
+    res
+}
+
+/// The plain function that replaces the `coroutine fn`
+fn new_async_fn(coroutine: &Coroutine, target: Target) -> String {
+    let (signature, arg_names) = signature(coroutine, target);
+    let Coroutine { id, head, .. } = coroutine;
+    format!(
+        "{head}{signature} {{
+    Coroutine{id}::new{arg_names}
+}}
+        "
+    )
+}
+
+/// `fn name<T>(args) -> impl Future<Output=T> where ..` of the plain
+/// function, and the arguments it passes on to `Coroutine{id}::new`
+fn signature(coroutine: &Coroutine, target: Target) -> (String, String) {
+    let Coroutine {
+        name,
+        args,
+        generics,
+        ..
+    } = coroutine;
+    let output = coroutine.output();
+    let receiver = coroutine.method.as_ref().and_then(|m| m.receiver.as_ref());
+    // It's `self` out here, `this` in the state machine
+    let mut params = format_args_name_and_types(args);
+    let mut arg_names = match args.is_empty() {
+        true => "()".to_string(),
+        false => format_args_names_only(args),
+    };
+    if let Some(receiver) = receiver {
+        let receiver = match receiver {
+            Receiver::Owned { .. } => "self".to_string(),
+            receiver => receiver.to_string(),
+        };
+        let rest = &arg_params(args)[1..];
+        params = match rest.is_empty() {
+            true => receiver,
+            false => format!("{receiver}, {}", rest.join(",")),
+        };
+        arg_names = arg_names.replacen("(this", "(self", 1);
+    }
+
+    let future = target.future_trait();
+    let (decl, where_clause) = (generics.decl(), generics.where_clause());
+    (
+        format!("fn {name}{decl}({params}) -> impl {future}<Output={output}>{where_clause}"),
+        arg_names,
+    )
+}
+
+/// Writes the state machine for a coroutine
+struct Machine<'a> {
+    coroutine: &'a Coroutine,
+    target: Target,
+    /// Say so on stderr when the coroutine is dropped before it resolves
+    log_cancel: bool,
+    futures: Futures,
+}
+
+impl Machine<'_> {
+    fn render(&self) -> String {
+        let Coroutine {
+            id, args, stack, ..
+        } = self.coroutine;
+        let target = self.target;
+        let generics = self.coroutine.machine_generics();
+        let (decl, ty_args) = (generics.decl(), generics.args());
+        let where_clause = generics.where_clause();
+
+        // Write our steps enum. We know it will start with "Start" and end with "Resolved"
+        // but we need to add one step for each state in between
+        let step_args = format_args_types_only(args);
+
+        let mut steps_enum = format!(
+            "
+enum State{id}{decl}{where_clause} {{
+    Start{step_args},"
+        );
+
+        // The output type of a future is its type annotation, a `String` if there is none
+        for step in &self.coroutine.steps[1..] {
+            let name = &step.name;
+            match &step.wait {
+                Some(wait) => {
+                    let fields = self.render_wait_fields(name, wait);
+                    write!(
+                        &mut steps_enum,
+                        "
+    {name}({fields}),"
+                    )
+                    .unwrap();
+                }
+                None => write!(
+                    &mut steps_enum,
+                    "
+    {name},"
+                )
+                .unwrap(),
+            }
+        }
+
+        // Generic parameters the other states don't use are used here
+        let resolved = match generics.params.is_empty() {
+            true => String::new(),
+            false => format!("({})", generics.phantom()),
+        };
+        write!(
+            &mut steps_enum,
+            "
+    Resolved{resolved},
+}}"
+        )
+        .unwrap();
+
+        // So, our `State` enum is finished, we create a coroutine struct and a simple
+        // `new` implementation. Locals that live across a `wait` get a `Stack` struct.
+        let coro_args = format_args_name_and_types(args);
+        let coro_args_names = format_args_names_only(args);
+
+        // A `std` coroutine whose stack borrows from itself must stay where
+        // it is once it's pinned, and so must one with the futures in it
+        let pinned = self.pinned();
+        let (pin_field, pin_init) = match pinned {
+            true => (
+                "\n    _pin: std::marker::PhantomPinned,",
+                "\n            _pin: std::marker::PhantomPinned,",
+            ),
+            false => ("", ""),
+        };
+        let project = match pinned {
+            true => self.render_project(),
+            false => String::new(),
+        };
+
+        let coroutine = if stack.is_empty() {
+            let init = match pinned {
+                false => format!("Self {{ state: State{id}::Start{coro_args_names} }}"),
+                true => format!(
+                    "Self {{
+            state: State{id}::Start{coro_args_names},{pin_init}
+        }}"
+                ),
+            };
+            format!(
+                "
+struct Coroutine{id}{decl}{where_clause} {{
+    state: State{id}{ty_args},{pin_field}
+}}
+
+impl{decl} Coroutine{id}{ty_args}{where_clause} {{
+    fn new({coro_args}) -> Self {{
+        {init}
+    }}{project}
+}}
+"
+            )
+        } else {
+            let mut stack_struct = self.render_stack_struct();
+            if pinned {
+                stack_struct.push_str(&self.render_projection_struct());
+            }
+            format!(
+                "{stack_struct}
+struct Coroutine{id}{decl}{where_clause} {{
+    stack: Stack{id}{ty_args},
+    state: State{id}{ty_args},{pin_field}
+}}
+
+impl{decl} Coroutine{id}{ty_args}{where_clause} {{
+    fn new({coro_args}) -> Self {{
+        Self {{
+            state: State{id}::Start{coro_args_names},
+            stack: Stack{id}::default(),{pin_init}
+        }}
+    }}{project}
+}}
+"
+            )
+        };
+
+        // This is our future implementation. Every state restores a `mut`
+        // local as `mut`, even the ones that don't change it.
+        let output_ty = self.coroutine.output();
+        let allow = match stack.iter().any(|v| v.mutable) {
+            true => "\n    #[allow(unused_mut)]",
+            false => "",
+        };
+        // The function that runs the states creates the futures, so it's
+        // the one that defines the types we infer for them
+        let (aliases, opaque) = self.render_future_aliases();
+        let attrs = match opaque.is_empty() {
+            true => allow.to_string(),
+            false => format!("{allow}\n    #[define_opaque({})]", opaque.join(", ")),
+        };
+        let future = target.future_trait();
+        let poll_fn = target.poll_fn();
+        let state = self.state();
+        // A pinned coroutine gets at its fields through `project`, which
+        // keeps what has to stay pinned behind a `Pin`
+        let projected = match pinned {
+            true => format!("\n        {}", self.render_projected("self")),
+            false => String::new(),
+        };
+        let mut imp = match (pinned, &self.coroutine.error) {
+            (false, None) => format!(
+                "
+impl{decl} {future} for Coroutine{id}{ty_args}{where_clause} {{
+    type Output = {output_ty};
+{attrs}
+    {poll_fn} {{
+        loop {{
+            match self.state {{"
+            ),
+            (true, None) => format!(
+                "
+impl{decl} {future} for Coroutine{id}{ty_args}{where_clause} {{
+    type Output = {output_ty};
+{attrs}
+    fn poll(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {{{projected}
+        loop {{
+            match {state} {{"
+            ),
+            // With `?` in it, the loop runs in `resume`, which returns the
+            // error instead of resolving to it. `poll` does that for it.
+            (false, Some(error)) => {
+                let fail = self.render_fail(target.resume_call());
+                let resume_fn = target.resume_fn(output_ty, error);
+                format!(
+                    "
+impl{decl} {future} for Coroutine{id}{ty_args}{where_clause} {{
+    type Output = {output_ty};
+
+    {poll_fn} {{
+{fail}
+    }}
+}}
+
+impl{decl} Coroutine{id}{ty_args}{where_clause} {{
+    /// Runs the states until one waits or the coroutine resolves. A `?`
+    /// that fails returns the error, and `poll` resolves to it.{attrs}
+    {resume_fn} {{
+        Ok(loop {{
+            match self.state {{"
+                )
+            }
+            (true, Some(error)) => {
+                let fail = self.render_fail("self.as_mut().resume(cx)");
+                format!(
+                    "
+impl{decl} {future} for Coroutine{id}{ty_args}{where_clause} {{
+    type Output = {output_ty};
+
+    {poll_fn} {{
+{fail}
+    }}
+}}
+
+impl{decl} Coroutine{id}{ty_args}{where_clause} {{
+    /// Runs the states until one waits or the coroutine resolves. A `?`
+    /// that fails returns the error, and `poll` resolves to it.{attrs}
+    fn resume(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Result<std::task::Poll<{output_ty}>, {error}> {{{projected}
+        Ok(loop {{
+            match {state} {{"
+                )
+            }
+        };
+
+        // This will receive the input args to the function, `mut` ones too
+        let impl_fut_first_args = match args.is_empty() {
+            true => String::new(),
+            false => {
+                let names: Vec<&str> = args.iter().map(|(name, _)| name.as_str()).collect();
+                format!("({})", names.join(","))
+            }
+        };
+
+        for (i, step) in self.coroutine.steps.iter().enumerate() {
+            let name = &step.name;
+            match &step.wait {
+                // We need to special case the first state since that
+                // happens before we reach an `await` point
+                None if i == 0 => {
+                    let state = indent(&self.render_step(step), 20);
+                    // We can't move arguments out of `self.state`, unless
+                    // they're `Copy`. The others we take out of it.
+                    if args.iter().all(|(_, ty)| is_copy(ty)) {
+                        write!(
+                            &mut imp,
+                            "
+                State{id}::Start{impl_fut_first_args} => {{
+{state}
+                }}
+"
+                        )
+                        .unwrap();
+                        continue;
+                    }
+                    let resolved = self.resolved();
+                    let state_mut = match pinned {
+                        true => "state",
+                        false => "&mut self.state",
+                    };
+                    write!(
+                        &mut imp,
+                        "
+                State{id}::Start(..) => {{
+                    let State{id}::Start{impl_fut_first_args} =
+                        std::mem::replace({state_mut}, {resolved})
+                    else {{
+                        unreachable!()
+                    }};
+{state}
+                }}
+"
+                    )
+                    .unwrap();
+                }
+
+                // `join!`, `select!` and `join_all` poll several futures
+                Some(wait) if !matches!(wait.fut, Awaited::Future(_)) => {
+                    let state = indent(&self.render_step(step), 20);
+                    let n = &name["Wait".len()..];
+                    let (poll, value) = self.render_combinator(wait, n);
+                    let poll = indent(&format!("{poll}\nlet {} = {value};", wait.pattern), 20);
+                    let at = at(wait.line);
+                    write!(
+                        &mut imp,
+                        "
+                State{id}::{name}({}) => {{{at}
+{poll}
+{state}
+                }}
+",
+                        self.render_wait_bindings(wait, n).join(", "),
+                    )
+                    .unwrap();
+                }
+
+                // These states are await-points where we await a future
+                Some(wait) => {
+                    let step = self.render_step(step);
+                    // `value?` leaves `resume` with the error, `poll` cleans up
+                    let (value, state) = match wait.propagate {
+                        true => ("value", format!("let {} = value?;\n{step}", wait.pattern)),
+                        false => (wait.pattern.as_str(), step),
+                    };
+                    let state = indent(&state, 28);
+                    let f = format!("f{}", &name["Wait".len()..]);
+                    let poll = self.poll_call(&f);
+                    let (ready, not_ready) = (target.ready(), target.not_ready());
+                    let at = at(wait.line);
+                    write!(
+                        &mut imp,
+                        "
+                State{id}::{name}(ref mut {f}) => {{{at}
+                    match {poll} {{{at}
+                        {ready}({value}) => {{
+{state}
+                        }}
+                        {not_ready} => break {not_ready},
+                    }}
+                }}
+"
+                    )
+                    .unwrap();
+                }
+
+                // And these are the ones we jump to
+                None => {
+                    let state = indent(&self.render_step(step), 20);
+                    write!(
+                        &mut imp,
+                        "
+                State{id}::{name} => {{
+{state}
+                }}
+"
+                    )
+                    .unwrap();
+                }
+            }
+        }
+
+        // If we poll the future after it has resolved, we panic
+        writeln!(
+            &mut imp,
+            "
+                State{id}::Resolved{} => panic!(\"Polled a resolved future\")
+            }}
+        }}{}
+    }}
+}}",
+            match generics.params.is_empty() {
+                true => "",
+                false => "(_)",
+            },
+            match self.coroutine.error {
+                Some(_) => ")",
+                None => "",
+            }
+        )
+        .unwrap();
+
+        // A generic coroutine isn't `Unpin` by itself when a type parameter
+        // isn't. We never pin anything in it but the boxed futures, so it
+        // can be, and `poll` needs it to get at the fields.
+        if target == Target::Std && !pinned && !generics.params.is_empty() {
+            write!(
+                &mut imp,
+                "
+impl{decl} Unpin for Coroutine{id}{ty_args}{where_clause} {{}}
+"
+            )
+            .unwrap();
+        }
+
+        imp.push_str(&self.render_drop());
+
+        // Format the different parts of the Coroutine implementation to a string
+        format!("{aliases}{steps_enum}\n{coroutine}\n{imp}")
+    }
+
+    /// `Drop` for a coroutine that's dropped before it resolves, which
+    /// cancels it. A state that waits frees what it holds in the order an
+    /// `async fn` would: the references into the stack first, then the
+    /// future, then the locals, the last one declared first. There's no
+    /// `Drop` if no state holds anything and we don't log.
+    fn render_drop(&self) -> String {
+        let coroutine = self.coroutine;
+        let id = &coroutine.id;
+        let generics = coroutine.machine_generics();
+        let (decl, ty_args) = (generics.decl(), generics.args());
+        let where_clause = generics.where_clause();
+
+        let mut arms = vec![];
+        let mut uses_stack = false;
+        for step in &coroutine.steps {
+            let Some(wait) = &step.wait else {
+                continue;
+            };
+            // Pinned locals stay in the stack once they're declared
+            let (refs, locals): (Vec<&Hoisted>, Vec<&Hoisted>) = coroutine
+                .stack
+                .iter()
+                .filter(|v| step.restore.contains(&v.name) || v.storage == Storage::Pinned)
+                .partition(|v| matches!(v.storage, Storage::Ref { .. }));
+            if refs.is_empty() && locals.is_empty() && !self.log_cancel {
+                continue;
+            }
+
+            let mut lines = vec![];
+            if self.log_cancel {
+                lines.push(format!(
+                    "eprintln!(\"`{}` was cancelled while waiting on line {}\");",
+                    coroutine.name, wait.line
+                ));
+            }
+            lines.extend(refs.iter().map(|v| self.set_field(v, "None")));
+            lines.push(format!("{} = {};", self.state(), self.resolved()));
+            lines.extend(locals.iter().rev().map(|v| self.set_field(v, "None")));
+            uses_stack |= !refs.is_empty() || !locals.is_empty();
+            arms.push((&step.name, at(wait.line), lines.join("\n")));
+        }
+
+        let state = self.state();
+        let mut body = match &arms[..] {
+            [] => return String::new(),
+            // Just one state to clean up after
+            [(name, at, code)] => format!(
+                "if let State{id}::{name}(..) = {state} {{{at}\n{}\n}}",
+                indent(code, 4)
+            ),
+            arms => {
+                let mut body = format!("match {state} {{");
+                for (name, at, code) in arms {
+                    write!(
+                        &mut body,
+                        "\n    State{id}::{name}(..) => {{{at}\n{}\n    }}",
+                        indent(code, 8)
+                    )
+                    .unwrap();
+                }
+                body.push_str("\n    _ => (),\n}");
+                body
+            }
+        };
+        // A pinned coroutine is dropped where it's pinned, so `drop` gets at
+        // its fields like `poll` does
+        if self.pinned() {
+            let this = "unsafe { std::pin::Pin::new_unchecked(self) }";
+            let projected = match uses_stack {
+                true => self.render_projected(this),
+                false if coroutine.stack.is_empty() => self.render_projected(this),
+                false => format!("let (_, state) = {this}.project();"),
+            };
+            body = format!(
+                "// SAFETY: `self` is never used again, so it stays pinned\n{projected}\n{body}"
+            );
+        }
+        let body = indent(&body, 8);
+
+        format!(
+            "
+impl{decl} Drop for Coroutine{id}{ty_args}{where_clause} {{
+    fn drop(&mut self) {{
+{body}
+    }}
+}}
+"
+        )
+    }
+
+    /// The `Stack{id}` struct definition
+    fn render_stack_struct(&self) -> String {
+        let id = &self.coroutine.id;
+        let generics = self.coroutine.machine_generics();
+        if generics.params.is_empty() {
+            let mut res = format!(
+                "
+#[derive(Default)]
+struct Stack{id} {{"
+            );
+            for var in &self.coroutine.stack {
+                write!(
+                    &mut res,
+                    "
+    {}: {},",
+                    var.name, var.field_ty
+                )
+                .unwrap();
+            }
+            res.push_str("\n}\n");
+            return res;
+        }
+
+        // `#[derive(Default)]` would want every type parameter to be `Default`
+        let (decl, ty_args) = (generics.decl(), generics.args());
+        let where_clause = generics.where_clause();
+        let mut fields = String::new();
+        let mut init = String::new();
+        for var in &self.coroutine.stack {
+            write!(&mut fields, "\n    {}: {},", var.name, var.field_ty).unwrap();
+            write!(&mut init, "\n            {}: None,", var.name).unwrap();
+        }
+        let phantom = generics.phantom();
+        format!(
+            "
+struct Stack{id}{decl}{where_clause} {{{fields}
+    _generics: {phantom},
+}}
+
+impl{decl} Default for Stack{id}{ty_args}{where_clause} {{
+    fn default() -> Self {{
+        Self {{{init}
+            _generics: std::marker::PhantomData,
+        }}
+    }}
+}}
+"
+        )
+    }
+
+    /// What `project` makes of `Stack{id}`: the locals the stack points into
+    /// behind a `Pin`, a plain `&mut` for the others
+    fn render_projection_struct(&self) -> String {
+        let id = &self.coroutine.id;
+        let generics = self.coroutine.machine_generics();
+        let mut fields = String::new();
+        for var in &self.coroutine.stack {
+            let ty = match var.storage {
+                Storage::Pinned => format!("std::pin::Pin<&'p mut {}>", var.field_ty),
+                _ => format!("&'p mut {}", var.field_ty),
+            };
+            write!(&mut fields, "\n    {}: {ty},", var.name).unwrap();
+        }
+        if !generics.params.is_empty() {
+            write!(&mut fields, "\n    _generics: {},", generics.phantom()).unwrap();
+        }
+        let projection = generics.with_lifetime("'p");
+        format!(
+            "
+struct Projection{id}{}{} {{{fields}
+}}
+",
+            projection.decl(),
+            projection.where_clause()
+        )
+    }
+
+    /// `State{id}::Resolved`, which has a `PhantomData` if there are generic
+    /// parameters
+    fn resolved(&self) -> String {
+        let id = &self.coroutine.id;
+        match self.coroutine.machine_generics().params.is_empty() {
+            true => format!("State{id}::Resolved"),
+            false => format!("State{id}::Resolved(std::marker::PhantomData)"),
+        }
+    }
+
+    /// The body of the arm that runs `step`
+    fn render_step(&self, step: &Step) -> String {
+        // Pinned arguments and values of a `wait` are moved to the stack first
+        let mut restore: Vec<String> = step
+            .pin
+            .iter()
+            .map(|name| self.render_pin(name, name))
+            .collect();
+        restore.extend(step.restore.iter().map(|name| {
+            let var = self.hoisted(name);
+            let field = self.field(name);
+            match var.storage {
+                Storage::Owned if var.mutable => {
+                    format!("let mut {name} = {field}.take().unwrap();")
+                }
+                Storage::Owned => format!("let {name} = {field}.take().unwrap();"),
+                Storage::Ref { mutable: true } => {
+                    format!("let {name} = unsafe {{ &mut *{field}.take().unwrap() }};")
+                }
+                Storage::Ref { mutable: false } => {
+                    format!("let {name} = unsafe {{ &*{field}.take().unwrap() }};")
+                }
+                Storage::Pinned => self.render_pin_ref(var),
+            }
+        }));
+        let restore = section("Restore stack", &restore.join("\n"), 0);
+        format!("{restore}{}", self.render_nodes(&step.body))
+    }
+
+    fn render_nodes(&self, nodes: &[Node]) -> String {
+        let id = &self.coroutine.id;
+        let mut res = String::new();
+        for node in nodes {
+            match node {
+                Node::Code(code) => res.push_str(&format!(
+                    "// ---- Code you actually wrote ----\n{}\n// ---------------------------------\n",
+                    self.render_code(code)
+                )),
+                Node::If {
+                    cond,
+                    line,
+                    then,
+                    els,
+                } => {
+                    let at = at(*line);
+                    let then = indent(&self.render_nodes(then), 4);
+                    let els = self.render_nodes(els);
+                    // `else if` chains
+                    let els = match els.starts_with("if ") {
+                        true => format!(" else {els}"),
+                        false => format!(" else {{{at}\n{}\n}}", indent(&els, 4)),
+                    };
+                    res.push_str(&format!("if {cond} {{{at}\n{then}\n}}{els}"));
+                }
+                Node::Match {
+                    scrutinee,
+                    line,
+                    arms,
+                } => {
+                    res.push_str(&format!("match {scrutinee} {{{}\n", at(*line)));
+                    for (pattern, line, body) in arms {
+                        res.push_str(&format!(
+                            "    {pattern} => {{{}\n{}\n    }}\n",
+                            at(*line),
+                            indent(&self.render_nodes(body), 8)
+                        ));
+                    }
+                    res.push('}');
+                }
+                Node::Await {
+                    fut,
+                    line,
+                    save,
+                    to,
+                } => {
+                    let n = &to["Wait".len()..];
+                    let save = section("Save stack", &self.render_save(save).join("\n"), 0);
+                    let (futs, fields) = self.render_await(fut, to, n);
+                    let futs: Vec<String> = futs.iter().map(|l| format!("{l}{}", at(*line))).collect();
+                    res.push_str(&format!(
+                        "{}\n{save}{} = State{id}::{to}({fields});",
+                        futs.join("\n"),
+                        self.state()
+                    ));
+                }
+                Node::Goto { save, to } => {
+                    let save = section("Save stack", &self.render_save(save).join("\n"), 0);
+                    res.push_str(&format!("{save}{} = State{id}::{to};", self.state()));
+                }
+                Node::Return(value) => {
+                    res.push_str(&self.render_resolve(value, "break").join("\n"));
+                }
+            }
+        }
+        res.trim_end().to_string()
+    }
+
+    /// The code you wrote, with `return`, `break` and `continue` replaced by
+    /// code that switches states
+    fn render_code(&self, code: &[Fragment]) -> String {
+        let mut text = String::new();
+        // The line in the source of the line we're writing
+        let mut line = None;
+        // The indentation of a `let` we moved into the stack, so the code
+        // after it on the same line goes on a line of its own
+        let mut after_pin: Option<String> = None;
+        for fragment in code {
+            let pad = match fragment {
+                Fragment::Line(n) => {
+                    line = Some(*n);
+                    continue;
+                }
+                Fragment::Text(_) => String::new(),
+                // Continuation lines get the indentation of the line we're editing
+                _ => {
+                    let current = last_line(&text);
+                    current[..current.len() - current.trim_start().len()].to_string()
+                }
+            };
+            let mut rendered = self.render_fragment(fragment);
+            if let Some(pin_pad) = after_pin.take() {
+                let rest = rendered.trim_start_matches([' ', '\t']);
+                if !rest.is_empty() && !rest.starts_with(['\r', '\n']) {
+                    mark(&mut text, line);
+                    text.push('\n');
+                    text.push_str(&pin_pad);
+                    rendered = rest.to_string();
+                }
+            }
+            if let Fragment::Pin { .. } = fragment {
+                after_pin = Some(pad.clone());
+            }
+            // Text moves on to the next line in the source, the lines of the
+            // code we replaced it with all come from the same one
+            for (i, part) in rendered.split('\n').enumerate() {
+                if i > 0 {
+                    mark(&mut text, line);
+                    text.push('\n');
+                    text.push_str(&pad);
+                    if let Fragment::Text(_) = fragment {
+                        line = line.map(|n| n + 1);
+                    }
+                }
+                text.push_str(part);
+            }
+        }
+        mark(&mut text, line);
+        text
+    }
+
+    fn render_fragment(&self, fragment: &Fragment) -> String {
+        let id = &self.coroutine.id;
+        match fragment {
+            Fragment::Text(text) => text.clone(),
+            Fragment::Line(_) => String::new(),
+            Fragment::Jump { save, to } => {
+                let state = self.state();
+                match save.is_empty() {
+                    true => format!("{{ {state} = State{id}::{to}; continue; }}"),
+                    false => format!(
+                        "{{ {} {state} = State{id}::{to}; continue; }}",
+                        self.render_save(save).join(" ")
+                    ),
+                }
+            }
+            Fragment::Return(value) => {
+                format!("{{ {} }}", self.render_resolve(value, "return").join(" "))
+            }
+            Fragment::Pin { name, init } => {
+                let init: String = init.iter().map(|f| self.render_fragment(f)).collect();
+                self.render_pin(name, &init)
+            }
+        }
+    }
+
+    /// The fields of the `Wait` state that polls `wait.fut`: the boxed
+    /// futures, and for `join!` and `join_all` the values of the ones that
+    /// are ready
+    fn render_wait_fields(&self, name: &str, wait: &Wait) -> String {
+        let target = self.target;
+        let lifetime = self.coroutine.borrows_for();
+        let outputs = wait
+            .fut
+            .outputs(&wait.output)
+            .expect("the parser checks the output types");
+        if self.futures == Futures::Concrete {
+            let types = self.future_types(name, wait);
+            let fields: Vec<String> = match &wait.fut {
+                Awaited::Future(_) | Awaited::Select(_) => types,
+                Awaited::Join(_) => {
+                    let values = outputs.iter().map(|ty| format!("Option<{ty}>"));
+                    types.into_iter().chain(values).collect()
+                }
+                Awaited::JoinAll(_) => vec![
+                    format!("Vec<{}>", types[0]),
+                    format!("Vec<Option<{}>>", outputs[0]),
+                ],
+            };
+            return fields.join(", ");
+        }
+        let fields: Vec<String> = match &wait.fut {
+            Awaited::Future(_) | Awaited::Select(_) => outputs
+                .iter()
+                .map(|ty| target.boxed(ty, lifetime))
+                .collect(),
+            Awaited::Join(_) => {
+                let values = outputs.iter().map(|ty| format!("Option<{ty}>"));
+                outputs
+                    .iter()
+                    .map(|ty| target.boxed(ty, lifetime))
+                    .chain(values)
+                    .collect()
+            }
+            Awaited::JoinAll(_) => vec![
+                format!("Vec<{}>", target.boxed(&outputs[0], lifetime)),
+                format!("Vec<Option<{}>>", outputs[0]),
+            ],
+        };
+        fields.join(", ")
+    }
+
+    /// The names the poll arm of the `Wait{n}` state gives its fields
+    fn render_wait_bindings(&self, wait: &Wait, n: &str) -> Vec<String> {
+        let names: Vec<String> = match &wait.fut {
+            Awaited::Future(_) | Awaited::JoinAll(_) => vec![format!("f{n}"), format!("v{n}")],
+            Awaited::Join(futs) | Awaited::Select(futs) => (0..futs.len())
+                .map(|i| format!("f{n}_{i}"))
+                .chain((0..futs.len()).map(|i| format!("v{n}_{i}")))
+                .collect(),
+        };
+        let fields = match &wait.fut {
+            Awaited::Future(_) => 1,
+            Awaited::Select(futs) => futs.len(),
+            Awaited::Join(_) | Awaited::JoinAll(_) => names.len(),
+        };
+        names[..fields]
+            .iter()
+            .map(|name| format!("ref mut {name}"))
+            .collect()
+    }
+
+    /// The code that polls the futures of a combinator, which either breaks
+    /// out with `NotReady` or leaves us with the expression for its value
+    fn render_combinator(&self, wait: &Wait, n: &str) -> (String, String) {
+        let target = self.target;
+        let (ready, not_ready) = (target.ready(), target.not_ready());
+        // The value of a future that's ready, if it is
+        let poll_value = |fut: &str| {
+            format!(
+                "match {} {{\n    {ready}(value) => Some(value),\n    {not_ready} => None,\n}}",
+                self.poll_call(fut)
+            )
+        };
+        match &wait.fut {
+            Awaited::Future(_) => unreachable!("a single future is polled in a `match`"),
+            Awaited::Join(futs) => {
+                let mut poll = "// Poll the futures that aren't ready yet".to_string();
+                for i in 0..futs.len() {
+                    let value = poll_value(&format!("f{n}_{i}"));
+                    write!(
+                        &mut poll,
+                        "\nif v{n}_{i}.is_none() {{\n    *v{n}_{i} = {};\n}}",
+                        indent(&value, 4).trim_start()
+                    )
+                    .unwrap();
+                }
+                let pending: Vec<String> = (0..futs.len())
+                    .map(|i| format!("v{n}_{i}.is_none()"))
+                    .collect();
+                write!(
+                    &mut poll,
+                    "\nif {} {{\n    break {not_ready};\n}}",
+                    pending.join(" || ")
+                )
+                .unwrap();
+                let values: Vec<String> = (0..futs.len())
+                    .map(|i| format!("v{n}_{i}.take().unwrap()"))
+                    .collect();
+                (poll, format!("({})", values.join(", ")))
+            }
+            Awaited::Select(futs) => {
+                let mut value = String::new();
+                for i in 0..futs.len() {
+                    let poll = self.poll_call(&format!("f{n}_{i}"));
+                    write!(
+                        &mut value,
+                        "if let {ready}(value) = {poll} {{\n    value\n}} else "
+                    )
+                    .unwrap();
+                }
+                value.push_str(&format!("{{\n    break {not_ready};\n}}"));
+                let poll = "// The first future that's ready wins, the others are dropped";
+                (poll.to_string(), value)
+            }
+            Awaited::JoinAll(_) => {
+                let value = poll_value("fut");
+                let poll = format!(
+                    "// Poll the futures that aren't ready yet
+for (fut, value) in f{n}.iter_mut().zip(v{n}.iter_mut()) {{
+    if value.is_none() {{
+        *value = {};
+    }}
+}}
+if v{n}.iter().any(Option::is_none) {{
+    break {not_ready};
+}}",
+                    indent(&value, 8).trim_start()
+                );
+                let value = format!(
+                    "v{n}.iter_mut().map(|value| value.take().unwrap()).collect::<Vec<_>>()"
+                );
+                (poll, value)
+            }
+        }
+    }
+
+    /// The statements that create the future(s) `fut` and the fields of the
+    /// `Wait{n}` state `to` they go in
+    fn render_await(&self, fut: &Awaited, to: &str, n: &str) -> (Vec<String>, String) {
+        let target = self.target;
+        if self.futures == Futures::Concrete {
+            return self.render_await_concrete(fut, to, n);
+        }
+        match fut {
+            Awaited::Future(fut) => (
+                vec![format!("let fut{n} = {};", target.box_new(fut))],
+                format!("fut{n}"),
+            ),
+            Awaited::Join(futs) | Awaited::Select(futs) => {
+                let lets = futs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, fut)| format!("let fut{n}_{i} = {};", target.box_new(fut)))
+                    .collect();
+                let mut fields: Vec<String> =
+                    (0..futs.len()).map(|i| format!("fut{n}_{i}")).collect();
+                if let Awaited::Join(_) = fut {
+                    fields.extend(futs.iter().map(|_| "None".to_string()));
+                }
+                (lets, fields.join(", "))
+            }
+            Awaited::JoinAll(futs) => {
+                let step = self.coroutine.steps.iter().find(|s| s.name == to);
+                let wait = step.and_then(|s| s.wait.as_ref()).unwrap();
+                let boxed = target.boxed(
+                    &wait.fut.outputs(&wait.output).unwrap()[0],
+                    self.coroutine.borrows_for(),
+                );
+                (
+                    vec![
+                        format!(
+                            "let fut{n}: Vec<_> = IntoIterator::into_iter({futs}).map(|fut| {} as {boxed}).collect();",
+                            target.box_new("fut")
+                        ),
+                        format!("let values{n} = fut{n}.iter().map(|_| None).collect();"),
+                    ],
+                    format!("fut{n}, values{n}"),
+                )
+            }
+        }
+    }
+
+    /// `render_await` for futures stored as their own types. The type on the
+    /// `let` is what an inferred type is inferred from.
+    fn render_await_concrete(&self, fut: &Awaited, to: &str, n: &str) -> (Vec<String>, String) {
+        let step = self.coroutine.steps.iter().find(|s| s.name == to);
+        let wait = step.and_then(|s| s.wait.as_ref()).unwrap();
+        let types = self.future_types(to, wait);
+        match fut {
+            Awaited::Future(fut) => (
+                vec![format!("let fut{n}: {} = {fut};", types[0])],
+                format!("fut{n}"),
+            ),
+            Awaited::Join(futs) | Awaited::Select(futs) => {
+                let lets = futs
+                    .iter()
+                    .zip(&types)
+                    .enumerate()
+                    .map(|(i, (fut, ty))| format!("let fut{n}_{i}: {ty} = {fut};"))
+                    .collect();
+                let mut fields: Vec<String> =
+                    (0..futs.len()).map(|i| format!("fut{n}_{i}")).collect();
+                if let Awaited::Join(_) = fut {
+                    fields.extend(futs.iter().map(|_| "None".to_string()));
+                }
+                (lets, fields.join(", "))
+            }
+            Awaited::JoinAll(futs) => (
+                vec![
+                    // The closure's return type is what the type of the
+                    // futures is inferred from, `collect` wouldn't do that
+                    format!(
+                        "let fut{n}: Vec<{ty}> = IntoIterator::into_iter({futs}).map(|fut| -> {ty} {{ fut }}).collect();",
+                        ty = types[0]
+                    ),
+                    format!("let values{n} = fut{n}.iter().map(|_| None).collect();"),
+                ],
+                format!("fut{n}, values{n}"),
+            ),
+        }
+    }
+
+    /// The type of each future the `Wait` state `name` stores (of each one
+    /// in the `Vec` for `join_all`) when they're stored as their own types:
+    /// the one you gave it, or the type alias we infer it with
+    fn future_types(&self, name: &str, wait: &Wait) -> Vec<String> {
+        let ty_args = self.coroutine.machine_generics().args();
+        let aliases = self.future_aliases(name, wait);
+        wait.types
+            .iter()
+            .zip(aliases)
+            .map(|(ty, alias)| match written_type(wait, ty.as_deref()) {
+                Some(ty) => ty.to_string(),
+                None => format!("{alias}{ty_args}"),
+            })
+            .collect()
+    }
+
+    /// The names of the type aliases for the futures of the `Wait` state
+    /// `name`, whether they're used or not: `Fut0_1`, or `Fut0_1_0` and so
+    /// on for `join!` and `select!`
+    fn future_aliases(&self, name: &str, wait: &Wait) -> Vec<String> {
+        let id = &self.coroutine.id;
+        let n = &name["Wait".len()..];
+        match &wait.fut {
+            Awaited::Future(_) | Awaited::JoinAll(_) => vec![format!("Fut{id}_{n}")],
+            Awaited::Join(futs) | Awaited::Select(futs) => (0..futs.len())
+                .map(|i| format!("Fut{id}_{n}_{i}"))
+                .collect(),
+        }
+    }
+
+    /// The `impl Future` type aliases for the futures whose types we infer,
+    /// and their names
+    fn render_future_aliases(&self) -> (String, Vec<String>) {
+        let mut res = String::new();
+        let mut names = vec![];
+        if self.futures == Futures::Boxed {
+            return (res, names);
+        }
+        let generics = self.coroutine.machine_generics();
+        let (decl, where_clause) = (generics.decl(), generics.where_clause());
+        let future = self.target.future_trait();
+        for step in &self.coroutine.steps {
+            let Some(wait) = &step.wait else {
+                continue;
+            };
+            let outputs = wait.fut.outputs(&wait.output).unwrap();
+            let aliases = self.future_aliases(&step.name, wait);
+            for (i, (alias, ty)) in aliases.into_iter().zip(&wait.types).enumerate() {
+                if written_type(wait, ty.as_deref()).is_some() {
+                    continue;
+                }
+                let output = outputs.get(i).unwrap_or(&outputs[0]);
+                writeln!(
+                    &mut res,
+                    "type {alias}{decl}{where_clause} = impl {future}<Output = {output}>;"
+                )
+                .unwrap();
+                names.push(alias);
+            }
+        }
+        if !res.is_empty() {
+            res.insert_str(
+                0,
+                "\n// The futures we wait on, their types are inferred from where we create\n\
+                 // them\n",
+            );
+        }
+        (res, names)
+    }
+
+    /// Polls the future in `fut`, a field of a `Wait` state
+    fn poll_call(&self, fut: &str) -> String {
+        match self.futures {
+            Futures::Boxed => self.target.poll_call(fut),
+            Futures::Concrete => self.target.poll_in_place(fut),
+        }
+    }
+
+    /// Moves a freshly declared pinned local (or its initializer `init`)
+    /// into the stack and borrows it back out
+    fn render_pin(&self, name: &str, init: &str) -> String {
+        let var = self.hoisted(name);
+        format!(
+            "{}\n{}",
+            self.set_field(var, &format!("Some({init})")),
+            self.render_pin_ref(var)
+        )
+    }
+
+    /// Borrows the pinned local `var` back out of the stack
+    fn render_pin_ref(&self, var: &Hoisted) -> String {
+        let name = &var.name;
+        let field = self.field(name);
+        // `let mut` so `&mut buffer` works like it did before hoisting
+        match (var.mutable, self.pinned()) {
+            (true, false) => format!("let mut {name} = {field}.as_mut().unwrap();"),
+            (false, false) => format!("let {name} = {field}.as_ref().unwrap();"),
+            (true, true) => format!(
+                "// SAFETY: the code you wrote can't move `{name}` while it's borrowed\n\
+                 let mut {name} = unsafe {{ {field}.as_mut().get_unchecked_mut() }}.as_mut().unwrap();"
+            ),
+            (false, true) => format!("let {name} = {field}.as_ref().get_ref().as_ref().unwrap();"),
+        }
+    }
+
+    /// The statements that resolve the coroutine to `value`. `exit` is `break`
+    /// in the poll loop and `return` anywhere else.
+    fn render_resolve(&self, value: &str, exit: &str) -> Vec<String> {
+        let ready = self.target.ready();
+        // `return` leaves `resume` for a coroutine that uses `?`, which
+        // returns a `Result`
+        let exit_with = |value: &str| match (exit, &self.coroutine.error) {
+            ("return", Some(_)) => format!("return Ok({ready}({value}));"),
+            _ => format!("{exit} {ready}({value});"),
+        };
+        let resolve = format!("{} = {};", self.state(), self.resolved());
+        if self.coroutine.stack.is_empty() {
+            return vec![resolve, exit_with(value)];
+        }
+        // `value` might use the hoisted locals, so evaluate it before we free them
+        let mut lines = vec![format!("let output = {value};"), resolve];
+        lines.extend(self.render_reset());
+        lines.push(exit_with("output"));
+        lines
+    }
+
+    /// The body of `poll` for a coroutine that uses `?`: it calls `resume`
+    /// with `call` and resolves to the error if a `?` failed
+    fn render_fail(&self, call: &str) -> String {
+        let ready = self.target.ready();
+        let mut fail = vec![];
+        if self.pinned() {
+            fail.push(self.render_projected("self"));
+        }
+        fail.push(format!("{} = {};", self.state(), self.resolved()));
+        if !self.coroutine.stack.is_empty() {
+            fail.extend(self.render_reset());
+        }
+        fail.push(format!("{ready}(Err(e))"));
+        let fail = indent(&fail.join("\n"), 16);
+        format!(
+            "        match {call} {{
+            Ok(poll) => poll,
+            Err(e) => {{
+{fail}
+            }}
+        }}"
+        )
+    }
+
+    /// Code that saves the hoisted locals in `save`
+    fn render_save(&self, save: &[String]) -> Vec<String> {
+        save.iter()
+            .map(|name| self.set_field(self.hoisted(name), &format!("Some({name})")))
+            .collect()
+    }
+
+    /// Code that frees every hoisted local
+    fn render_reset(&self) -> Vec<String> {
+        let id = &self.coroutine.id;
+        match self.pinned() {
+            true => self
+                .coroutine
+                .stack
+                .iter()
+                .map(|var| self.set_field(var, "None"))
+                .collect(),
+            false => vec![format!("self.stack = Stack{id}::default();")],
+        }
+    }
+
+    /// Whether the coroutine gets at its fields through `project`. A `std`
+    /// coroutine whose stack borrows from itself must stay where it is once
+    /// it's pinned, and so must one with the futures in it.
+    fn pinned(&self) -> bool {
+        self.target == Target::Std
+            && (self.coroutine.is_self_referential() || self.futures == Futures::Concrete)
+    }
+
+    /// The state the coroutine is in, as a place
+    fn state(&self) -> &'static str {
+        match self.pinned() {
+            true => "*state",
+            false => "self.state",
+        }
+    }
+
+    /// The hoisted local `name` in the stack, to call a method on
+    fn field(&self, name: &str) -> String {
+        match self.pinned() {
+            true => format!("stack.{name}"),
+            false => format!("self.stack.{name}"),
+        }
+    }
+
+    /// Sets the hoisted local `var` in the stack to `value`
+    fn set_field(&self, var: &Hoisted, value: &str) -> String {
+        let name = &var.name;
+        match (self.pinned(), var.storage) {
+            (false, _) => format!("self.stack.{name} = {value};"),
+            (true, Storage::Pinned) => format!("stack.{name}.set({value});"),
+            (true, _) => format!("*stack.{name} = {value};"),
+        }
+    }
+
+    /// Projects the pinned coroutine `this` onto its stack and its state
+    fn render_projected(&self, this: &str) -> String {
+        let stack = &self.coroutine.stack;
+        match stack.iter().any(|v| v.storage == Storage::Pinned) {
+            _ if stack.is_empty() => format!("let state = {this}.project();"),
+            true => format!("let (mut stack, state) = {this}.project();"),
+            false => format!("let (stack, state) = {this}.project();"),
+        }
+    }
+
+    /// `project` for a pinned coroutine, which is the only way to get at its
+    /// fields. The locals the stack points into stay behind a `Pin`, the
+    /// others and the state are plain `&mut`.
+    fn render_project(&self) -> String {
+        let Coroutine { id, stack, .. } = self.coroutine;
+        let generics = self.coroutine.machine_generics();
+        let ty_args = generics.args();
+        let (why, so) = match self.coroutine.is_self_referential() {
+            true => (
+                "The stack holds pointers into",
+                "itself, so the locals they point to stay pinned.",
+            ),
+            false => (
+                "The states poll the futures",
+                "where they are, and nothing here moves them.",
+            ),
+        };
+        if stack.is_empty() {
+            return format!(
+                "
+
+    /// The state of the pinned coroutine. {why}
+    /// {so}
+    fn project(self: std::pin::Pin<&mut Self>) -> &mut State{id}{ty_args} {{
+        // SAFETY: we never move the coroutine out of the `Pin`
+        unsafe {{ &mut self.get_unchecked_mut().state }}
+    }}"
+            );
+        }
+        let mut fields = String::new();
+        for var in stack {
+            let name = &var.name;
+            match var.storage {
+                Storage::Pinned => write!(
+                    &mut fields,
+                    "
+            // SAFETY: `{name}` never leaves the stack until it's dropped
+            {name}: unsafe {{ std::pin::Pin::new_unchecked(&mut this.stack.{name}) }},"
+                ),
+                _ => write!(&mut fields, "\n            {name}: &mut this.stack.{name},"),
+            }
+            .unwrap();
+        }
+        if !generics.params.is_empty() {
+            fields.push_str("\n            _generics: std::marker::PhantomData,");
+        }
+        let projection_args = generics.with_lifetime("'_").args();
+        format!(
+            "
+
+    /// The fields of the pinned coroutine. {why}
+    /// {so}
+    fn project(
+        self: std::pin::Pin<&mut Self>,
+    ) -> (Projection{id}{projection_args}, &mut State{id}{ty_args}) {{
+        // SAFETY: we never move the coroutine out of the `Pin`, and the
+        // locals that have to stay pinned only leave it in a `Pin`
+        let this = unsafe {{ self.get_unchecked_mut() }};
+        let stack = Projection{id} {{{fields}
+        }};
+        (stack, &mut this.state)
+    }}"
+        )
+    }
+
+    fn hoisted(&self, name: &str) -> &Hoisted {
+        self.coroutine.hoisted(name).unwrap()
+    }
+}
+
+/// The type of a future in `wait` if `ty` is the type you gave it, the
+/// type of the futures in it if it's the `Vec` for `join_all`
+fn written_type<'a>(wait: &Wait, ty: Option<&'a str>) -> Option<&'a str> {
+    match wait.fut {
+        Awaited::JoinAll(_) => vec_element(ty?),
+        _ => ty,
+    }
+}
+
+/// `T` of `Vec<T>`
+fn vec_element(ty: &str) -> Option<&str> {
+    let ty = ty.trim();
+    let ty = ty.strip_prefix("std::vec::").unwrap_or(ty);
+    Some(ty.strip_prefix("Vec<")?.strip_suffix('>')?.trim())
+}
+
+/// A commented block of generated code on its own lines, or nothing if
+/// there's no code
+fn section(title: &str, code: &str, width: usize) -> String {
+    if code.is_empty() {
+        return String::new();
+    }
+    let pad = " ".repeat(width);
+    format!("{pad}// {title}\n{}\n", indent(code, width))
+}
+
+/// Marks the line it's on as coming from `line` in the source.
+/// `strip_markers` takes the marks out again.
+fn at(line: usize) -> String {
+    format!("\u{1}{line}\u{1}")
+}
+
+/// Lines without a mark that come after this come from `line`
+fn fallback(line: Option<usize>) -> String {
+    format!("\u{2}{}\u{2}", line.unwrap_or(0))
+}
+
+/// Marks the last line of `text` as coming from `line`, unless it's blank
+fn mark(text: &mut String, line: Option<usize>) {
+    if let Some(line) = line
+        && !last_line(text).trim().is_empty()
+    {
+        text.push_str(&at(line));
+    }
+}
+
+/// Marks each line of `text`, which starts on `line` in the source
+fn mark_lines(text: &str, line: usize) -> String {
+    let mut res = String::new();
+    for (i, part) in text.split('\n').enumerate() {
+        if i > 0 {
+            res.push('\n');
+        }
+        res.push_str(part);
+        mark(&mut res, Some(line + i));
+    }
+    res
+}
+
+fn last_line(text: &str) -> &str {
+    &text[text.rfind('\n').map_or(0, |i| i + 1)..]
+}
+
+/// Takes the marks out of `text`, and returns it with the source line of
+/// each of its lines
+fn strip_markers(text: &str) -> (String, Vec<Option<usize>>) {
+    let mut res = String::with_capacity(text.len());
+    let mut lines = vec![];
+    let mut default = None;
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            res.push('\n');
+        }
+        let start = res.len();
+        let mut marked = None;
+        let mut parts = line.split(['\u{1}', '\u{2}']);
+        res.push_str(parts.next().unwrap_or_default());
+        // Between two markers there's a line number, then code again
+        let mut delimiters = line.matches(['\u{1}', '\u{2}']);
+        while let (Some(n), Some(code)) = (parts.next(), parts.next()) {
+            let n: usize = n.parse().unwrap_or_default();
+            match delimiters.next() {
+                Some("\u{1}") => marked = marked.or(Some(n)),
+                _ => default = Some(n).filter(|n| *n > 0),
+            }
+            delimiters.next();
+            res.push_str(code);
+        }
+        let blank = res[start..].trim().is_empty();
+        lines.push(if blank { None } else { marked.or(default) });
+    }
+    // There's no line after the last line ending
+    if text.ends_with('\n') {
+        lines.pop();
+    }
+    (res, lines)
+}
+
+/// Puts `pad` in front of every line of `code` that isn't blank, markers
+/// aside
+fn pad_lines(code: &str, pad: &str) -> String {
+    if pad.is_empty() {
+        return code.to_string();
+    }
+    code.split('\n')
+        .map(|line| {
+            let blank = line
+                .split(['\u{1}', '\u{2}'])
+                .step_by(2)
+                .all(|code| code.trim().is_empty());
+            match blank {
+                true => line.to_string(),
+                false => format!("{pad}{line}"),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Indents every non-empty line in `code` by `width` spaces
+fn indent(code: &str, width: usize) -> String {
+    let pad = " ".repeat(width);
+    code.lines()
+        .map(|l| {
+            if l.is_empty() {
+                String::new()
+            } else {
+                format!("{pad}{l}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Gets:
+/// `&[(txt, String), (i: usize)]`
+/// Outputs
+/// `(String, usize)`
+/// If there are no args it returns: ""
+fn format_args_types_only(args: &[(String, String)]) -> String {
+    if args.is_empty() {
+        String::new()
+    } else {
+        let mut args_fmt: String = args.iter().map(|(_n, ty)| format!("{ty},")).collect();
+        // remove last `,`
+        args_fmt.pop();
+        format!("({args_fmt})")
+    }
+}
+
+/// Gets:
+/// `&[(txt, String), (i, usize)]`
+/// Outputs
+/// `txt: String, i: usize`
+/// If there are no args it returns: ""
+fn format_args_name_and_types(args: &[(String, String)]) -> String {
+    arg_params(args).join(",")
+}
+
+/// `txt: String` for each of `args`
+fn arg_params(args: &[(String, String)]) -> Vec<String> {
+    args.iter()
+        .enumerate()
+        .map(|(i, (n, ty))| format!("{}: {ty}", arg_name(i, n)))
+        .collect()
+}
+
+/// Gets:
+/// `&[(txt, String), (i: usize)]`
+/// Outputs
+/// `(txt, i)`
+/// If there are no args it returns: ""
+fn format_args_names_only(args: &[(String, String)]) -> String {
+    if args.is_empty() {
+        String::new()
+    } else {
+        let mut args_fmt: String = args
+            .iter()
+            .enumerate()
+            .map(|(i, (n, _ty))| format!("{},", arg_name(i, n)))
+            .collect();
+        // remove last `,`
+        args_fmt.pop();
+        format!("({args_fmt})")
+    }
+}
+
+/// `x` of `mut x`. The function and `new` don't change their arguments, only
+/// the state machine might. An argument that's a pattern like `(a, b)` or `_`
+/// is `arg{i}` until `Start` destructures it.
+fn arg_name(i: usize, name: &str) -> Cow<'_, str> {
+    let name = name.strip_prefix("mut ").map_or(name, str::trim_start);
+    match is_ident(name) {
+        true => Cow::Borrowed(name),
+        false => Cow::Owned(format!("arg{i}")),
+    }
+}
+
+/// Whether `ty` is one of the types we know are `Copy`: the primitive types
+/// and shared references
+fn is_copy(ty: &str) -> bool {
+    const COPY: [&str; 17] = [
+        "bool", "char", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64",
+        "i128", "isize", "f32", "f64", "()",
+    ];
+    let ty = ty.trim();
+    match ty.strip_prefix('&') {
+        Some(referent) => {
+            let referent = referent.trim_start();
+            // `&'a mut T`
+            let referent = match referent.strip_prefix('\'') {
+                Some(rest) => rest.trim_start_matches(|c: char| c.is_alphanumeric() || c == '_'),
+                None => referent,
+            };
+            !referent.trim_start().starts_with("mut ")
+        }
+        None => COPY.contains(&ty),
+    }
+}
@@ -0,0 +1,151 @@
+//! The errors we report to whoever runs corofy.
+//!
+//! The parser works with `ParseError`, which only knows where in the source
+//! something went wrong. `CorofyError` adds the file and the line of code the
+//! error is about, so it can show them the way rustc does:
+//!
+//! ```text
+//! error: `count` is used after a `wait`, so it has to be stored in the coroutine. [..]
+//!   ┌─ src/main.rs:4:9
+//!   │
+//! 4 │     let count = 1;
+//!   │         ^^^^^
+//! ```
+
+use std::fmt::{self, Display};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{ParseError, Span};
+
+#[derive(Debug)]
+pub enum CorofyError {
+    /// The source isn't something we can rewrite
+    Parse {
+        /// Where the source came from, if it came from a file
+        file: Option<PathBuf>,
+        /// The line the error points at, without its line ending
+        line: Option<String>,
+        error: ParseError,
+    },
+    /// Reading the source or writing the result failed
+    Io {
+        file: Option<PathBuf>,
+        error: io::Error,
+    },
+}
+
+impl CorofyError {
+    /// `error` from parsing `src`
+    pub fn parse(src: &str, error: ParseError) -> Self {
+        let line = error
+            .span
+            .and_then(|span| line_at(src, span))
+            .map(str::to_string);
+        CorofyError::Parse {
+            file: None,
+            line,
+            error,
+        }
+    }
+
+    /// The same error, in `path`
+    pub fn in_file(mut self, path: impl AsRef<Path>) -> Self {
+        match &mut self {
+            CorofyError::Parse { file, .. } | CorofyError::Io { file, .. } => {
+                *file = Some(path.as_ref().to_path_buf());
+            }
+        }
+        self
+    }
+
+    /// Where in the source the error is, if we know
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CorofyError::Parse { error, .. } => error.span,
+            CorofyError::Io { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for CorofyError {
+    fn from(error: io::Error) -> Self {
+        CorofyError::Io { file: None, error }
+    }
+}
+
+/// The line `span` starts on, `None` if it isn't in `src`
+fn line_at(src: &str, span: Span) -> Option<&str> {
+    let before = src.get(..span.start)?;
+    let start = before.rfind('\n').map_or(0, |i| i + 1);
+    let end = src[span.start..]
+        .find('\n')
+        .map_or(src.len(), |i| span.start + i);
+    Some(src[start..end].trim_end_matches('\r'))
+}
+
+impl Display for CorofyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (file, msg) = match self {
+            CorofyError::Parse { file, error, .. } => (file, error.msg.clone()),
+            CorofyError::Io { file, error } => (file, error.to_string()),
+        };
+        write!(f, "error: {msg}")?;
+
+        let span = self.span();
+        let location = match (file, span) {
+            (Some(file), Some(span)) => format!("{}:{}:{}", file.display(), span.line, span.col),
+            (Some(file), None) => file.display().to_string(),
+            (None, Some(span)) => format!("{}:{}", span.line, span.col),
+            (None, None) => return Ok(()),
+        };
+        // The gutter is as wide as the line number
+        let gutter = span.map_or(1, |span| span.line.to_string().len());
+        write!(f, "\n{:gutter$} ┌─ {location}", "")?;
+
+        let (
+            Some(span),
+            CorofyError::Parse {
+                line: Some(line), ..
+            },
+        ) = (span, self)
+        else {
+            return Ok(());
+        };
+        // Tabs stay tabs so the carets line up with the code above them
+        let skip = span.col.saturating_sub(1);
+        let indent: String = line
+            .chars()
+            .take(skip)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let len = span.end.saturating_sub(span.start);
+        let width = line
+            .chars()
+            .skip(skip)
+            .scan(0, |bytes, c| {
+                *bytes += c.len_utf8();
+                Some(*bytes)
+            })
+            .take_while(|&bytes| bytes <= len)
+            .count()
+            .max(1);
+        write!(
+            f,
+            "\n{:gutter$} │\n{} │ {line}\n{:gutter$} │ {indent}{}",
+            "",
+            span.line,
+            "",
+            "^".repeat(width)
+        )
+    }
+}
+
+impl std::error::Error for CorofyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CorofyError::Parse { error, .. } => Some(error),
+            CorofyError::Io { error, .. } => Some(error),
+        }
+    }
+}
@@ -0,0 +1,406 @@
+//! Draws the state machine of each coroutine, for `--explain`: a node for
+//! each state with the code it runs, an edge for each way to get to the next
+//! one, labeled with the future we wait on if it's a `wait`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::ir::{Coroutine, Fragment, Node, Program, Step, Wait};
+use crate::lexer::tokenize;
+
+/// The language the diagram is written in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Diagram {
+    /// Graphviz, `dot -Tsvg` draws it
+    #[default]
+    Dot,
+    /// Mermaid flowcharts, which github and most markdown viewers draw
+    Mermaid,
+}
+
+impl Diagram {
+    pub const ALL: [Diagram; 2] = [Diagram::Dot, Diagram::Mermaid];
+}
+
+impl fmt::Display for Diagram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Diagram::Dot => "dot",
+            Diagram::Mermaid => "mermaid",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Diagram {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Diagram::ALL
+            .into_iter()
+            .find(|diagram| diagram.to_string() == s)
+            .ok_or_else(|| format!("Unknown `--explain` `{s}`, expected `dot` or `mermaid`"))
+    }
+}
+
+/// Where a state can go next
+struct Edge<'a> {
+    from: &'a str,
+    /// A step, or `Resolved`
+    to: &'a str,
+    /// The future we wait on to get there
+    label: Option<String>,
+    /// Only if a `?` fails
+    error: bool,
+}
+
+/// A diagram of the states of every coroutine in `program`
+pub fn explain(program: &Program, diagram: Diagram) -> String {
+    let mut res = match diagram {
+        Diagram::Dot => {
+            "digraph corofy {\n    node [shape = box, fontname = \"monospace\"];\n".to_string()
+        }
+        Diagram::Mermaid => "flowchart TD\n".to_string(),
+    };
+    for coroutine in &program.coroutines {
+        res.push_str(&match diagram {
+            Diagram::Dot => render_dot(coroutine),
+            Diagram::Mermaid => render_mermaid(coroutine),
+        });
+    }
+    if diagram == Diagram::Dot {
+        res.push_str("}\n");
+    }
+    res
+}
+
+fn render_dot(coroutine: &Coroutine) -> String {
+    let id = &coroutine.id;
+    let mut res = format!(
+        "\n    subgraph cluster_{id} {{\n        label = \"{}\";\n",
+        dot_escape(&title(coroutine))
+    );
+    for step in &coroutine.steps {
+        let label: String = label(coroutine, step)
+            .iter()
+            .map(|line| format!("{}\\l", dot_escape(line)))
+            .collect();
+        res.push_str(&format!(
+            "        c{id}_{} [label = \"{label}\"];\n",
+            step.name
+        ));
+    }
+    res.push_str(&format!(
+        "        c{id}_Resolved [label = \"Resolved\", shape = doublecircle];\n"
+    ));
+    for edge in edges(coroutine) {
+        let mut attrs = vec![];
+        if let Some(label) = &edge.label {
+            attrs.push(format!("label = \"{}\"", dot_escape(label)));
+        }
+        if edge.error {
+            attrs.push("style = dashed".to_string());
+        }
+        let attrs = match attrs.is_empty() {
+            true => String::new(),
+            false => format!(" [{}]", attrs.join(", ")),
+        };
+        res.push_str(&format!(
+            "        c{id}_{} -> c{id}_{}{attrs};\n",
+            edge.from, edge.to
+        ));
+    }
+    res.push_str("    }\n");
+    res
+}
+
+fn render_mermaid(coroutine: &Coroutine) -> String {
+    let id = &coroutine.id;
+    let mut res = format!(
+        "    subgraph c{id} [\"{}\"]\n",
+        mermaid_escape(&title(coroutine))
+    );
+    for step in &coroutine.steps {
+        let label: Vec<String> = label(coroutine, step)
+            .iter()
+            .map(|l| mermaid_escape(l))
+            .collect();
+        res.push_str(&format!(
+            "        c{id}_{}[\"{}\"]\n",
+            step.name,
+            label.join("<br>")
+        ));
+    }
+    res.push_str(&format!("        c{id}_Resolved([\"Resolved\"])\n"));
+    for edge in edges(coroutine) {
+        let arrow = match edge.error {
+            true => "-.->",
+            false => "-->",
+        };
+        let label = edge
+            .label
+            .map(|label| format!("|\"{}\"|", mermaid_escape(&label)))
+            .unwrap_or_default();
+        res.push_str(&format!(
+            "        c{id}_{} {arrow}{label} c{id}_{}\n",
+            edge.from, edge.to
+        ));
+    }
+    res.push_str("    end\n");
+    res
+}
+
+/// `coroutine fn name(args) -> Output`
+fn title(coroutine: &Coroutine) -> String {
+    let args: Vec<String> = coroutine
+        .args
+        .iter()
+        .map(|(name, ty)| format!("{name}: {ty}"))
+        .collect();
+    format!(
+        "coroutine fn {}({}) -> {}",
+        coroutine.name,
+        args.join(", "),
+        coroutine.output()
+    )
+}
+
+/// The name of the state, with what it binds the value of its `wait` to,
+/// and the code it runs
+fn label(coroutine: &Coroutine, step: &Step) -> Vec<String> {
+    let mut lines = vec![match &step.wait {
+        Some(wait) => format!(
+            "{}({}: {})",
+            step.name,
+            wait.pattern,
+            binding_type(coroutine, wait)
+        ),
+        None => step.name.clone(),
+    }];
+    let mut code = vec![];
+    code_lines(&step.body, 0, &mut code);
+    if !code.is_empty() {
+        lines.push(String::new());
+        lines.extend(code);
+    }
+    lines
+}
+
+/// The type of what a `wait` binds, which for `fut.wait?` is what's in the
+/// `Ok` of the future's output
+fn binding_type<'a>(coroutine: &Coroutine, wait: &'a Wait) -> &'a str {
+    let error = coroutine.error.as_deref().filter(|_| wait.propagate);
+    error
+        .and_then(|error| {
+            wait.output
+                .strip_prefix("Result<")?
+                .strip_suffix(&format!(", {error}>"))
+        })
+        .unwrap_or(&wait.output)
+}
+
+/// The code of `nodes` as you'd write it, with `goto State` where we switch
+/// states
+fn code_lines(nodes: &[Node], width: usize, lines: &mut Vec<String>) {
+    let pad = " ".repeat(width);
+    for node in nodes {
+        match node {
+            Node::Code(code) => {
+                let text: String = code.iter().map(fragment_text).collect();
+                lines.extend(
+                    text.lines()
+                        .filter(|line| !line.trim().is_empty())
+                        .map(|line| format!("{pad}{line}")),
+                );
+            }
+            Node::If {
+                cond, then, els, ..
+            } => {
+                lines.push(format!("{pad}if {cond} {{"));
+                code_lines(then, width + 4, lines);
+                let mut els = els;
+                loop {
+                    match &els[..] {
+                        [] => break,
+                        // `else if` chains
+                        [
+                            Node::If {
+                                cond,
+                                then,
+                                els: next,
+                                ..
+                            },
+                        ] => {
+                            lines.push(format!("{pad}}} else if {cond} {{"));
+                            code_lines(then, width + 4, lines);
+                            els = next;
+                        }
+                        _ => {
+                            lines.push(format!("{pad}}} else {{"));
+                            code_lines(els, width + 4, lines);
+                            break;
+                        }
+                    }
+                }
+                lines.push(format!("{pad}}}"));
+            }
+            Node::Match {
+                scrutinee, arms, ..
+            } => {
+                lines.push(format!("{pad}match {scrutinee} {{"));
+                for (pattern, _, body) in arms {
+                    lines.push(format!("{pad}    {pattern} => {{"));
+                    code_lines(body, width + 8, lines);
+                    lines.push(format!("{pad}    }}"));
+                }
+                lines.push(format!("{pad}}}"));
+            }
+            Node::Await { fut, to, .. } => lines.push(format!("{pad}{fut}.wait -> {to}")),
+            Node::Goto { to, .. } => lines.push(format!("{pad}goto {to}")),
+            Node::Return(value) => lines.push(format!("{pad}return {value}")),
+        }
+    }
+}
+
+fn fragment_text(fragment: &Fragment) -> String {
+    match fragment {
+        Fragment::Text(text) => text.clone(),
+        Fragment::Line(_) => String::new(),
+        Fragment::Jump { to, .. } => format!("goto {to}"),
+        Fragment::Return(value) => format!("return {value}"),
+        Fragment::Pin { name, init } => {
+            let init: String = init.iter().map(fragment_text).collect();
+            format!("let {name} = {init};")
+        }
+    }
+}
+
+/// Every edge out of every state, each once, in the order they appear in
+/// the code
+fn edges(coroutine: &Coroutine) -> Vec<Edge<'_>> {
+    let mut edges = vec![];
+    for step in &coroutine.steps {
+        if step.wait.as_ref().is_some_and(|wait| wait.propagate) {
+            edges.push(Edge {
+                from: &step.name,
+                to: "Resolved",
+                label: Some("?".to_string()),
+                error: true,
+            });
+        }
+        node_edges(&step.name, &step.body, &mut edges);
+    }
+
+    let mut unique: Vec<Edge> = vec![];
+    for edge in edges {
+        let seen = unique.iter().any(|e| {
+            (e.from, e.to, &e.label, e.error) == (edge.from, edge.to, &edge.label, edge.error)
+        });
+        if !seen {
+            unique.push(edge);
+        }
+    }
+    unique
+}
+
+fn node_edges<'a>(from: &'a str, nodes: &'a [Node], edges: &mut Vec<Edge<'a>>) {
+    let edge = |to, label| Edge {
+        from,
+        to,
+        label,
+        error: false,
+    };
+    // A `?` in the code fails before it goes anywhere else
+    let try_edge = |code: &str, edges: &mut Vec<Edge<'a>>| {
+        if has_try(code) {
+            edges.push(Edge {
+                from,
+                to: "Resolved",
+                label: Some("?".to_string()),
+                error: true,
+            });
+        }
+    };
+    for node in nodes {
+        match node {
+            Node::Code(code) => {
+                try_edge(&code.iter().map(fragment_text).collect::<String>(), edges);
+                fragment_edges(from, code, edges);
+            }
+            Node::If {
+                cond, then, els, ..
+            } => {
+                try_edge(cond, edges);
+                node_edges(from, then, edges);
+                node_edges(from, els, edges);
+            }
+            Node::Match {
+                scrutinee, arms, ..
+            } => {
+                try_edge(scrutinee, edges);
+                for (_, _, body) in arms {
+                    node_edges(from, body, edges);
+                }
+            }
+            Node::Await { fut, to, .. } => {
+                try_edge(&fut.to_string(), edges);
+                edges.push(edge(to, Some(fut.to_string())));
+            }
+            Node::Goto { to, .. } => edges.push(edge(to, None)),
+            Node::Return(value) => {
+                try_edge(value, edges);
+                edges.push(edge("Resolved", None));
+            }
+        }
+    }
+}
+
+/// Whether `code` has a `?` that returns from the coroutine, and not from a
+/// closure in it
+fn has_try(code: &str) -> bool {
+    tokenize(code).is_ok_and(|tokens| crate::find_try(code, &tokens, 0..tokens.len()).is_some())
+}
+
+fn fragment_edges<'a>(from: &'a str, code: &'a [Fragment], edges: &mut Vec<Edge<'a>>) {
+    for fragment in code {
+        let to = match fragment {
+            Fragment::Jump { to, .. } => to,
+            Fragment::Return(_) => "Resolved",
+            Fragment::Pin { init, .. } => {
+                fragment_edges(from, init, edges);
+                continue;
+            }
+            Fragment::Text(_) | Fragment::Line(_) => continue,
+        };
+        edges.push(Edge {
+            from,
+            to,
+            label: None,
+            error: false,
+        });
+    }
+}
+
+/// A string in a DOT file
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// A label in a Mermaid file, which may hold HTML. The indentation is kept
+/// with non-breaking spaces.
+fn mermaid_escape(text: &str) -> String {
+    let trimmed = text.trim_start();
+    let indent = "#160;".repeat(text.len() - trimmed.len());
+    let escaped: String = trimmed
+        .chars()
+        .map(|c| match c {
+            '#' => "#35;".to_string(),
+            '"' => "#34;".to_string(),
+            '&' => "#38;".to_string(),
+            '<' => "#60;".to_string(),
+            '>' => "#62;".to_string(),
+            c => c.to_string(),
+        })
+        .collect();
+    format!("{indent}{escaped}")
+}
@@ -0,0 +1,383 @@
+//! Finds the locals that are used after a `wait` and have to be stored in
+//! the coroutine between polls.
+//!
+//! Each state of the state machine runs in its own `match` arm, so a local
+//! declared in one arm is gone by the time the next arm runs. We mimic what
+//! the compiler does for `async fn`: such locals are "hoisted" into a
+//! `Stack{id}` struct that's part of the coroutine. An arm restores the ones
+//! it needs before running the code you wrote, and saves the ones later
+//! states need before it moves on.
+//!
+//! The analysis works on the blocks of the function body (see `cfg`). A state
+//! runs one block and every block it continues to that isn't a state itself.
+//!
+//! A reference to something outside the coroutine is stored as it is, with
+//! the lifetime it was declared with, so the compiler still checks it. One
+//! that borrows a local of the coroutine is stored as a raw pointer, and the
+//! local it borrows stays in the stack so the pointer stays valid. This is
+//! exactly what `Coroutine0` in the `pinning` crate does by hand, and it has
+//! the same problem: the coroutine must not move once it has been polled.
+//! Only the `std` target can promise that, because its `poll` takes a
+//! `Pin<&mut Self>`. There the coroutine gets a `PhantomPinned` so it can't
+//! be moved out of the `Pin` once it's self-referential, and `poll` only gets
+//! at the stack through a projection that keeps the borrowed locals behind a
+//! `Pin` of their own. `check` rejects such a reference for the other
+//! targets.
+
+use std::collections::BTreeSet;
+
+use crate::{ParseError, Span};
+
+/// A local variable introduced by a `let`, the value of a `wait` or one of
+/// the function arguments
+#[derive(Debug, Clone)]
+pub struct Local {
+    pub name: String,
+    pub mutable: bool,
+    /// The declared type. The value of a `wait` defaults to the output type
+    /// of the future.
+    pub ty: Option<String>,
+    /// `false` for bindings in destructuring patterns, which we can't hoist
+    pub simple: bool,
+    pub span: Span,
+}
+
+/// Something that happens to a local in a state, in the order it happens
+#[derive(Debug, Clone)]
+pub enum Event {
+    Def(Local),
+    Use(String),
+}
+
+/// How a hoisted local is stored in the `Stack` struct
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    /// Moved into the stack when a state ends and back out when the next
+    /// state that needs it starts: `Option<T>`
+    Owned,
+    /// A reference into the stack: `Option<*const T>` or `Option<*mut T>`
+    Ref { mutable: bool },
+    /// Borrowed by a hoisted reference, so it never leaves the stack:
+    /// `Option<T>`, accessed through `as_ref`/`as_mut`
+    Pinned,
+}
+
+/// A local that's stored in the coroutine
+#[derive(Debug, Clone)]
+pub struct Hoisted {
+    pub name: String,
+    pub mutable: bool,
+    /// The field type in the `Stack` struct
+    pub field_ty: String,
+    pub storage: Storage,
+    /// Where the local is declared
+    pub span: Span,
+}
+
+#[derive(Debug, Default)]
+pub struct Stack {
+    pub vars: Vec<Hoisted>,
+    /// Hoisted locals each state restores before the code you wrote runs
+    pub restore: Vec<Vec<String>>,
+    /// Hoisted locals each block has in scope when it ends (restored or declared)
+    available: Vec<BTreeSet<String>>,
+    /// Hoisted locals that are alive when a block starts
+    live_in: Vec<BTreeSet<String>>,
+}
+
+impl Stack {
+    /// Works out which locals have to be hoisted. `blocks` holds the events
+    /// of each block, `succs` the blocks each block can continue to and
+    /// `is_state` which blocks start a state (the first block always does).
+    /// `borrows` maps a local to the locals used to initialize it, so we know
+    /// which locals are borrowed by a hoisted reference.
+    pub fn analyze(
+        blocks: &[Vec<Event>],
+        succs: &[Vec<usize>],
+        is_state: &[bool],
+        borrows: &[(String, Vec<String>)],
+    ) -> Result<Stack, ParseError> {
+        // Locals used in a block before it declares them, and the ones it declares
+        let mut uses = vec![];
+        let mut defs = vec![];
+        for events in blocks {
+            let mut used = BTreeSet::new();
+            let mut defined = BTreeSet::new();
+            for event in events {
+                match event {
+                    Event::Use(name) if !defined.contains(name) => {
+                        used.insert(name.clone());
+                    }
+                    Event::Use(_) => (),
+                    Event::Def(local) => {
+                        defined.insert(local.name.clone());
+                    }
+                }
+            }
+            uses.push(used);
+            defs.push(defined);
+        }
+
+        // Classic backwards liveness, iterated until nothing changes
+        let mut live_in: Vec<BTreeSet<String>> = uses.clone();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (0..blocks.len()).rev() {
+                let live_out: BTreeSet<String> = succs[i]
+                    .iter()
+                    .flat_map(|&s| live_in[s].iter().cloned())
+                    .collect();
+                for name in live_out.difference(&defs[i]) {
+                    changed |= live_in[i].insert(name.clone());
+                }
+            }
+        }
+
+        // A local that's alive when a state (other than the first) starts
+        // lives across a `wait` (or a jump between states) and has to be
+        // hoisted. Anything alive when the first state starts isn't declared
+        // at all, so it's not ours.
+        let hoisted: BTreeSet<String> = live_in
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i > 0 && is_state[i])
+            .flat_map(|(_, live)| live.iter().cloned())
+            .collect();
+        let decls = blocks.iter().flatten().filter_map(|e| match e {
+            Event::Def(local) => Some(local),
+            Event::Use(_) => None,
+        });
+
+        // The type of each local, and whether it's ever declared `mut`
+        let declared = |name: &str| -> Result<Option<(&str, bool)>, ParseError> {
+            let mut ty: Option<&str> = None;
+            let mut mutable = false;
+            for local in decls.clone().filter(|l| l.name == name) {
+                if !local.simple {
+                    return Err(ParseError::new(
+                        local.span,
+                        format!(
+                            "`{name}` is used after a `wait`, but locals bound in a pattern \
+                             can't be stored in the coroutine. Bind it with its own `let`."
+                        ),
+                    ));
+                }
+                let Some(local_ty) = &local.ty else {
+                    return Err(ParseError::new(
+                        local.span,
+                        format!(
+                            "`{name}` is used after a `wait`, so it has to be stored in the \
+                             coroutine. Add a type annotation: `let {name}: Type = ...`"
+                        ),
+                    ));
+                };
+                match ty {
+                    Some(ty) if normalize(ty) != normalize(local_ty) => {
+                        return Err(ParseError::new(
+                            local.span,
+                            format!(
+                                "`{name}` is used after a `wait` and declared with different \
+                                 types (`{ty}` and `{local_ty}`). Rename one of them."
+                            ),
+                        ));
+                    }
+                    _ => ty = Some(local_ty),
+                }
+                mutable |= local.mutable;
+            }
+            Ok(ty.map(|ty| (ty, mutable)))
+        };
+
+        // A reference that borrows one of our own locals, or another such
+        // reference, points into the coroutine: it's stored as a raw pointer
+        // and what it borrows stays in the stack. Any other reference points
+        // outside and is stored with its lifetime, like any other value.
+        let is_ref = |name: &str| {
+            decls.clone().any(|l| {
+                l.name == name && l.ty.as_deref().is_some_and(|ty| parse_ref(ty).is_some())
+            })
+        };
+        let borrowed = |name: &str| -> BTreeSet<&str> {
+            borrows
+                .iter()
+                .filter(|(local, _)| local == name)
+                .flat_map(|(_, used)| used.iter().map(String::as_str))
+                .filter(|used| decls.clone().any(|l| l.name == *used))
+                .collect()
+        };
+        let refs: BTreeSet<&str> = decls
+            .clone()
+            .map(|l| l.name.as_str())
+            .filter(|name| is_ref(name))
+            .collect();
+        let mut self_refs: BTreeSet<&str> = BTreeSet::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &name in &refs {
+                let points_in = borrowed(name)
+                    .iter()
+                    .any(|used| !is_ref(used) || self_refs.contains(used));
+                if points_in {
+                    changed |= self_refs.insert(name);
+                }
+            }
+        }
+        // What the hoisted ones borrow, through the references in between
+        let mut pinned = BTreeSet::new();
+        let mut todo: Vec<&str> = hoisted
+            .iter()
+            .map(String::as_str)
+            .filter(|name| self_refs.contains(name))
+            .collect();
+        let mut seen = BTreeSet::new();
+        while let Some(name) = todo.pop() {
+            for used in borrowed(name) {
+                match self_refs.contains(used) {
+                    true if seen.insert(used) => todo.push(used),
+                    true => (),
+                    false if !is_ref(used) => {
+                        pinned.insert(used);
+                    }
+                    false => (),
+                }
+            }
+        }
+
+        // The locals a self-referential one borrows are stored even if
+        // nothing after the `wait` uses them but the reference
+        let mut vars = vec![];
+        for name in hoisted
+            .iter()
+            .map(String::as_str)
+            .chain(pinned.iter().copied())
+        {
+            if vars.iter().any(|v: &Hoisted| v.name == name) {
+                continue;
+            }
+            // Only happens for names that are never declared (globals, or
+            // locals from a pattern we don't look into)
+            let Some((ty, mutable)) = declared(name)? else {
+                continue;
+            };
+            let (storage, field_ty) = match parse_ref(ty) {
+                Some((mutable, pointee)) if self_refs.contains(name) => {
+                    let ptr = if mutable { "*mut" } else { "*const" };
+                    (Storage::Ref { mutable }, format!("Option<{ptr} {pointee}>"))
+                }
+                Some((_, pointee)) if ref_lifetime(ty).is_none() => {
+                    let local = decls.clone().find(|l| l.name == name).unwrap();
+                    return Err(ParseError::new(
+                        local.span,
+                        format!(
+                            "`{name}` is a reference that's used after a `wait`, so it has to \
+                             be stored in the coroutine with its lifetime. Name it: \
+                             `&'a {pointee}` with one of the coroutine's lifetimes, or \
+                             `&'static {pointee}`"
+                        ),
+                    ));
+                }
+                _ if pinned.contains(name) => (Storage::Pinned, format!("Option<{ty}>")),
+                _ => (Storage::Owned, format!("Option<{ty}>")),
+            };
+
+            vars.push(Hoisted {
+                name: name.to_string(),
+                mutable,
+                field_ty,
+                storage,
+                span: decls.clone().find(|l| l.name == name).unwrap().span,
+            });
+        }
+
+        // A state restores the hoisted locals it (or a block it continues
+        // to) uses before declaring them. The others stay in the stack.
+        let hoisted: BTreeSet<String> = vars.iter().map(|v| v.name.clone()).collect();
+        let mut restore = vec![vec![]; blocks.len()];
+        let mut available = vec![BTreeSet::new(); blocks.len()];
+        for state in (0..blocks.len()).filter(|&i| is_state[i]) {
+            let used = region_uses(state, &uses, &defs, succs, is_state);
+            let restored: BTreeSet<String> = used.intersection(&hoisted).cloned().collect();
+            restore[state] = restored.iter().cloned().collect();
+
+            let mut todo = vec![(state, restored)];
+            while let Some((block, mut avail)) = todo.pop() {
+                avail.extend(defs[block].intersection(&hoisted).cloned());
+                for &next in &succs[block] {
+                    if !is_state[next] {
+                        todo.push((next, avail.clone()));
+                    }
+                }
+                available[block] = avail;
+            }
+        }
+
+        Ok(Stack {
+            vars,
+            restore,
+            available,
+            live_in,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Hoisted> {
+        self.vars.iter().find(|v| v.name == name)
+    }
+
+    /// The hoisted locals that have to be saved when we go from block `from`
+    /// to the state `to`. Pinned locals never leave the stack.
+    pub fn save(&self, from: usize, to: usize) -> Vec<String> {
+        self.available[from]
+            .intersection(&self.live_in[to])
+            .filter(|name| self.get(name).unwrap().storage != Storage::Pinned)
+            .cloned()
+            .collect()
+    }
+}
+
+/// The locals `block` and the blocks it continues to in the same state use
+/// before they're declared
+fn region_uses(
+    block: usize,
+    uses: &[BTreeSet<String>],
+    defs: &[BTreeSet<String>],
+    succs: &[Vec<usize>],
+    is_state: &[bool],
+) -> BTreeSet<String> {
+    let mut res = uses[block].clone();
+    for &next in succs[block].iter().filter(|&&next| !is_state[next]) {
+        let used = region_uses(next, uses, defs, succs, is_state);
+        res.extend(used.difference(&defs[block]).cloned());
+    }
+    res
+}
+
+/// `'a` of `&'a T`, `None` if it's elided or `'_`
+fn ref_lifetime(ty: &str) -> Option<&str> {
+    let rest = ty.trim().strip_prefix('&')?.trim_start();
+    let name = rest
+        .strip_prefix('\'')?
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()?;
+    (!name.is_empty() && name != "_").then(|| &rest[..name.len() + 1])
+}
+
+/// `&'a mut T` -> `Some((true, T))`
+fn parse_ref(ty: &str) -> Option<(bool, &str)> {
+    let rest = ty.trim().strip_prefix('&')?.trim_start();
+    // Drop the lifetime, the pointer doesn't have one
+    let rest = match rest.strip_prefix('\'') {
+        Some(lt) => lt.trim_start_matches(|c: char| c.is_alphanumeric() || c == '_'),
+        None => rest,
+    }
+    .trim_start();
+    match rest.strip_prefix("mut") {
+        Some(pointee) if pointee.starts_with(char::is_whitespace) => Some((true, pointee.trim())),
+        _ => Some((false, rest)),
+    }
+}
+
+fn normalize(ty: &str) -> String {
+    ty.split_whitespace().collect()
+}
@@ -0,0 +1,614 @@
+//! What `parse` turns a source file into, and `generate` turns into code.
+//!
+//! Each `coroutine fn` becomes a [`Coroutine`]: its signature, the locals it
+//! keeps in its stack and the steps of its state machine. The code you wrote
+//! is kept as text, split where we switch states, so the IR doesn't depend
+//! on the `Future` trait we generate code for.
+
+use std::fmt;
+
+pub use crate::hoist::{Hoisted, Storage};
+use crate::target::Futures;
+
+/// A source file with its `coroutine fn`s taken out
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    /// The source without the coroutines: the text around them and the
+    /// line it starts on. It's written first, the coroutines go at the end.
+    pub rest: Vec<(usize, String)>,
+    pub coroutines: Vec<Coroutine>,
+    /// What the generated code ends its lines with
+    pub line_ending: LineEnding,
+    /// A coroutine that's dropped before it resolves says so on stderr, with
+    /// the line of the `wait` it was cancelled at
+    pub log_cancel: bool,
+    /// How the states store the futures they wait on
+    pub futures: Futures,
+    /// Each coroutine is rewritten where it is instead of at the end of the
+    /// file. The state machine of a method goes right after its `impl` block.
+    pub in_place: bool,
+}
+
+/// Since both text editors and github can change the line endings of files
+/// we need to account for `\r\n` line endings as well as `\n`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    /// The line ending most lines in `src` end with. The whole output gets
+    /// that one, so a file with mixed line endings comes out with the one it
+    /// uses the most (the first one if it's a tie).
+    pub fn detect(src: &str) -> LineEnding {
+        let lines = src.matches('\n').count();
+        let crlf = src.matches("\r\n").count();
+        let first = src.find('\n').map(|i| match src[..i].ends_with('\r') {
+            true => LineEnding::CrLf,
+            false => LineEnding::Lf,
+        });
+        match (crlf * 2).cmp(&lines) {
+            std::cmp::Ordering::Greater => LineEnding::CrLf,
+            std::cmp::Ordering::Less => LineEnding::Lf,
+            std::cmp::Ordering::Equal => first.unwrap_or({
+                // No lines at all, use what's common on the platform
+                if cfg!(windows) {
+                    LineEnding::CrLf
+                } else {
+                    LineEnding::Lf
+                }
+            }),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+
+    /// `text` with every line ending replaced by this one
+    pub fn apply(self, text: &str) -> String {
+        let text = text.replace("\r\n", "\n");
+        match self {
+            LineEnding::Lf => text,
+            LineEnding::CrLf => text.replace('\n', "\r\n"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Coroutine {
+    /// Numbers the generated types: `Coroutine0`, `State0`, `Stack0`
+    pub id: String,
+    pub name: String,
+    /// The line `coroutine fn` is on
+    pub line: usize,
+    /// `(name, type)` of each argument, `mut name` if it's declared `mut`.
+    /// A method's receiver comes first, as `this`.
+    pub args: Vec<(String, String)>,
+    /// The return type, `None` if there's no `-> Type`
+    pub ret: Option<String>,
+    /// The generic parameters of the function itself
+    pub generics: Generics,
+    /// Set if the coroutine is in an `impl` block
+    pub method: Option<Method>,
+    /// `E` if the coroutine returns `Result<T, E>` and uses `?`. The code you
+    /// wrote then runs in a method that returns `Result` as well, and an
+    /// error resolves the coroutine.
+    pub error: Option<String>,
+    /// The doc comments, attributes and visibility in front of a free
+    /// coroutine, they go on the plain function. A method keeps them where
+    /// they are.
+    pub head: String,
+    /// The source of the `coroutine fn`
+    pub original: String,
+    /// Locals that live across a `wait`
+    pub stack: Vec<Hoisted>,
+    /// `Start` first, then the others in the order they appear in the source
+    pub steps: Vec<Step>,
+}
+
+impl Coroutine {
+    /// The output type, `String` if there's no `-> Type`
+    pub fn output(&self) -> &str {
+        self.ret.as_deref().unwrap_or(crate::DEFAULT_OUTPUT)
+    }
+
+    /// The generic parameters of the state machine: the lifetime of the
+    /// receiver if it's elided, the ones of the `impl` block and the ones of
+    /// the function
+    pub fn machine_generics(&self) -> Generics {
+        let mut generics = Generics::default();
+        if let Some(method) = &self.method {
+            if let Some(Receiver::Ref { lifetime: None, .. }) = method.receiver {
+                generics.params.push(RECEIVER_LIFETIME.to_string());
+            }
+            generics
+                .params
+                .extend(method.generics.params.iter().cloned());
+            generics
+                .predicates
+                .extend(method.generics.predicates.iter().cloned());
+        }
+        generics.params.extend(self.generics.params.iter().cloned());
+        generics
+            .predicates
+            .extend(self.generics.predicates.iter().cloned());
+        generics
+    }
+
+    /// The lifetime the futures we wait on may borrow for: the receiver's,
+    /// if it's borrowed
+    pub fn borrows_for(&self) -> Option<&str> {
+        match &self.method.as_ref()?.receiver {
+            Some(Receiver::Ref { lifetime, .. }) => {
+                Some(lifetime.as_deref().unwrap_or(RECEIVER_LIFETIME))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn hoisted(&self, name: &str) -> Option<&Hoisted> {
+        self.stack.iter().find(|v| v.name == name)
+    }
+
+    /// Whether some hoisted reference points into the stack itself
+    pub fn is_self_referential(&self) -> bool {
+        self.stack.iter().any(|v| v.storage == Storage::Pinned)
+    }
+}
+
+/// The lifetime we give a receiver that doesn't name one
+pub const RECEIVER_LIFETIME: &str = "'this";
+
+/// `<'a, T: Clone>` and `where T: Send`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Generics {
+    /// Each parameter with its bounds, `T: Clone`
+    pub params: Vec<String>,
+    /// The predicates of the `where` clause
+    pub predicates: Vec<String>,
+}
+
+impl Generics {
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty() && self.predicates.is_empty()
+    }
+
+    /// The parameters as they're declared: `<'a, T: Clone>`
+    pub fn decl(&self) -> String {
+        match self.params.is_empty() {
+            true => String::new(),
+            false => format!("<{}>", self.params.join(", ")),
+        }
+    }
+
+    /// The parameters as they're used: `<'a, T>`
+    pub fn args(&self) -> String {
+        match self.params.is_empty() {
+            true => String::new(),
+            false => format!("<{}>", self.names().join(", ")),
+        }
+    }
+
+    /// The same parameters after `lifetime`: `<'p, 'a, T: Clone>`
+    pub fn with_lifetime(&self, lifetime: &str) -> Generics {
+        let mut generics = self.clone();
+        generics.params.insert(0, lifetime.to_string());
+        generics
+    }
+
+    /// ` where T: Send`, or nothing
+    pub fn where_clause(&self) -> String {
+        match self.predicates.is_empty() {
+            true => String::new(),
+            false => format!(" where {}", self.predicates.join(", ")),
+        }
+    }
+
+    /// A `PhantomData` that uses every lifetime and type parameter, for the
+    /// types that might not use them otherwise
+    pub fn phantom(&self) -> String {
+        let used: Vec<String> = self
+            .params
+            .iter()
+            .filter(|param| !param.trim_start().starts_with("const "))
+            .map(|param| match param_name(param) {
+                lifetime if lifetime.starts_with('\'') => format!("&{lifetime} ()"),
+                ty => ty.to_string(),
+            })
+            .collect();
+        format!("std::marker::PhantomData<({},)>", used.join(", "))
+    }
+
+    fn names(&self) -> Vec<&str> {
+        self.params.iter().map(|param| param_name(param)).collect()
+    }
+}
+
+/// `T` of `T: Clone`, `N` of `const N: usize`
+fn param_name(param: &str) -> &str {
+    let param = param.trim();
+    let param = param.strip_prefix("const ").unwrap_or(param).trim_start();
+    let end = param.find([':', '=']).unwrap_or(param.len());
+    param[..end].trim()
+}
+
+/// The `impl` block a coroutine is in
+#[derive(Debug, Clone)]
+pub struct Method {
+    /// The type the block is for, what `Self` stands for
+    pub self_ty: String,
+    /// The generic parameters of the block
+    pub generics: Generics,
+    /// `None` for an associated function without `self`
+    pub receiver: Option<Receiver>,
+    /// The line of the `}` that closes the block
+    pub close: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Receiver {
+    /// `self` or `mut self`, the coroutine owns the value
+    Owned { mutable: bool },
+    /// `&self`, `&mut self` or `&'a self`, the coroutine borrows it
+    Ref {
+        mutable: bool,
+        lifetime: Option<String>,
+    },
+}
+
+impl fmt::Display for Receiver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Receiver::Owned { mutable: false } => f.write_str("self"),
+            Receiver::Owned { mutable: true } => f.write_str("mut self"),
+            Receiver::Ref { mutable, lifetime } => {
+                f.write_str("&")?;
+                if let Some(lifetime) = lifetime {
+                    write!(f, "{lifetime} ")?;
+                }
+                if *mutable {
+                    f.write_str("mut ")?;
+                }
+                f.write_str("self")
+            }
+        }
+    }
+}
+
+/// A state of the state machine
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// `Start`, `Wait{n}` for the ones that wait on a future and `Block{n}`
+    /// for the ones we jump to
+    pub name: String,
+    /// What a `Wait` step waits for
+    pub wait: Option<Wait>,
+    /// Pinned locals that are moved to the stack as soon as the step starts
+    /// (arguments and the value of a `wait`)
+    pub pin: Vec<String>,
+    /// Hoisted locals the step takes out of the stack
+    pub restore: Vec<String>,
+    pub body: Vec<Node>,
+}
+
+/// The future a `Wait` step polls
+#[derive(Debug, Clone)]
+pub struct Wait {
+    /// What the future was made of, the step before created it
+    pub fut: Awaited,
+    /// The pattern the value is bound to
+    pub pattern: String,
+    /// The output type of the future
+    pub output: String,
+    /// `fut.wait?`: the future resolves to a `Result` and the pattern is
+    /// bound to what `?` makes of it
+    pub propagate: bool,
+    /// The type of each future (each `Vec` for `join_all`) if it's a local
+    /// or an argument you gave a type
+    pub types: Vec<Option<String>>,
+    /// The line of the `wait`
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum Node {
+    /// Code you wrote
+    Code(Vec<Fragment>),
+    If {
+        cond: String,
+        /// The line of the condition
+        line: usize,
+        then: Vec<Node>,
+        els: Vec<Node>,
+    },
+    Match {
+        scrutinee: String,
+        /// The line of the scrutinee
+        line: usize,
+        /// `(pattern, line, body)`, with the line of the pattern
+        arms: Vec<(String, usize, Vec<Node>)>,
+    },
+    /// Saves `save` and moves on to the `Wait` step `to`, which polls `fut`
+    Await {
+        fut: Awaited,
+        /// The line of the `wait`
+        line: usize,
+        save: Vec<String>,
+        to: String,
+    },
+    /// Saves `save` and moves on to the step `to`
+    Goto { save: Vec<String>, to: String },
+    /// Resolves the coroutine to a value
+    Return(String),
+}
+
+/// What a `wait` waits on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Awaited {
+    /// `fut.wait`
+    Future(String),
+    /// `join!(a, b).wait`: all of them, the value is a tuple of their values
+    Join(Vec<String>),
+    /// `select!(a, b).wait`: the first one that's ready, the value is its
+    /// value. The others are dropped.
+    Select(Vec<String>),
+    /// `join_all(futures).wait`: every future in a `Vec`, the value is a
+    /// `Vec` of their values
+    JoinAll(String),
+}
+
+impl Awaited {
+    /// The output type when there's no type annotation
+    pub fn default_output(&self) -> String {
+        let output = crate::DEFAULT_OUTPUT;
+        match self {
+            Awaited::Future(_) | Awaited::Select(_) => output.to_string(),
+            Awaited::Join(futs) => format!("({})", vec![output; futs.len()].join(", ")),
+            Awaited::JoinAll(_) => format!("Vec<{output}>"),
+        }
+    }
+
+    /// The expression of each future, the `Vec` of them for `join_all`
+    pub fn exprs(&self) -> Vec<&str> {
+        match self {
+            Awaited::Future(fut) | Awaited::JoinAll(fut) => vec![fut],
+            Awaited::Join(futs) | Awaited::Select(futs) => {
+                futs.iter().map(String::as_str).collect()
+            }
+        }
+    }
+
+    /// The output types of the futures we poll, if `output` fits them
+    pub fn outputs(&self, output: &str) -> Option<Vec<String>> {
+        match self {
+            Awaited::Future(_) => Some(vec![output.to_string()]),
+            Awaited::Select(futs) => Some(vec![output.to_string(); futs.len()]),
+            Awaited::Join(futs) => {
+                let inner = output.trim().strip_prefix('(')?.strip_suffix(')')?;
+                let types = split_top_level(inner);
+                (types.len() == futs.len()).then_some(types)
+            }
+            Awaited::JoinAll(_) => {
+                let inner = output.trim().strip_prefix("Vec<")?.strip_suffix('>')?;
+                Some(vec![inner.trim().to_string()])
+            }
+        }
+    }
+}
+
+/// `E` of a `Result<T, E>` (or `std::result::Result<T, E>`). We can't tell
+/// what the error type of an alias such as `io::Result<T>` is.
+pub(crate) fn result_error(ty: &str) -> Option<String> {
+    let (path, args) = ty.trim().strip_suffix('>')?.split_once('<')?;
+    let path = path.trim();
+    if path != "Result" && !path.ends_with("::Result") {
+        return None;
+    }
+    match &split_top_level(args)[..] {
+        [_, error] => Some(error.clone()),
+        _ => None,
+    }
+}
+
+/// The comma separated parts of `list` that aren't nested in brackets. A
+/// trailing comma doesn't make another part.
+fn split_top_level(list: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in list.char_indices() {
+        match c {
+            '(' | '[' | '{' | '<' => depth += 1,
+            // `->` in `Box<dyn Fn() -> T>` doesn't close anything
+            '>' if list[..i].ends_with('-') => (),
+            ')' | ']' | '}' | '>' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(list[start..i].trim().to_string());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    if !list[start..].trim().is_empty() {
+        parts.push(list[start..].trim().to_string());
+    }
+    parts
+}
+
+/// A piece of code you wrote
+#[derive(Debug, Clone)]
+pub enum Fragment {
+    Text(String),
+    /// The text after this starts on `line` in the source
+    Line(usize),
+    /// `break` or `continue` to the step `to`
+    Jump {
+        save: Vec<String>,
+        to: String,
+    },
+    /// `return value`
+    Return(String),
+    /// `let name = init;` for a pinned local, which is initialized in the stack
+    Pin {
+        name: String,
+        init: Vec<Fragment>,
+    },
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, coroutine) in self.coroutines.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{coroutine}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Something like:
+///
+/// ```text
+/// coroutine 0: fn async_main() -> String
+///   stack:
+///     mut buffer: Option<String> (pinned)
+///   Start:
+///     code:
+///       println!("Program starting");
+///     await Http::get("/600/HelloWorld") -> Wait1
+///   Wait1(txt: String):
+///     ...
+/// ```
+impl fmt::Display for Coroutine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args: Vec<String> = self
+            .args
+            .iter()
+            .map(|(name, ty)| format!("{name}: {ty}"))
+            .collect();
+        writeln!(
+            f,
+            "coroutine {}: fn {}({}) -> {}",
+            self.id,
+            self.name,
+            args.join(", "),
+            self.output()
+        )?;
+
+        if !self.stack.is_empty() {
+            writeln!(f, "  stack:")?;
+            for var in &self.stack {
+                let mutable = if var.mutable { "mut " } else { "" };
+                let storage = match var.storage {
+                    Storage::Owned => "",
+                    Storage::Ref { .. } => " (ref)",
+                    Storage::Pinned => " (pinned)",
+                };
+                writeln!(f, "    {mutable}{}: {}{storage}", var.name, var.field_ty)?;
+            }
+        }
+
+        for step in &self.steps {
+            match &step.wait {
+                Some(wait) => {
+                    let propagate = if wait.propagate { "?" } else { "" };
+                    writeln!(
+                        f,
+                        "  {}({}: {}{propagate}):",
+                        step.name, wait.pattern, wait.output
+                    )?
+                }
+                None => writeln!(f, "  {}:", step.name)?,
+            }
+            if !step.pin.is_empty() {
+                writeln!(f, "    pin {}", step.pin.join(", "))?;
+            }
+            if !step.restore.is_empty() {
+                writeln!(f, "    restore {}", step.restore.join(", "))?;
+            }
+            fmt_nodes(f, &step.body, 4)?;
+        }
+        Ok(())
+    }
+}
+
+fn fmt_nodes(f: &mut fmt::Formatter<'_>, nodes: &[Node], width: usize) -> fmt::Result {
+    let pad = " ".repeat(width);
+    for node in nodes {
+        match node {
+            Node::Code(code) if code.is_empty() => (),
+            Node::Code(code) => {
+                writeln!(f, "{pad}code:")?;
+                let text: String = code.iter().map(ToString::to_string).collect();
+                for line in text.lines() {
+                    writeln!(f, "{pad}  {line}")?;
+                }
+            }
+            Node::If {
+                cond, then, els, ..
+            } => {
+                writeln!(f, "{pad}if {cond}:")?;
+                fmt_nodes(f, then, width + 2)?;
+                writeln!(f, "{pad}else:")?;
+                fmt_nodes(f, els, width + 2)?;
+            }
+            Node::Match {
+                scrutinee, arms, ..
+            } => {
+                writeln!(f, "{pad}match {scrutinee}:")?;
+                for (pattern, _, body) in arms {
+                    writeln!(f, "{pad}  {pattern} =>")?;
+                    fmt_nodes(f, body, width + 4)?;
+                }
+            }
+            Node::Await { fut, save, to, .. } => {
+                writeln!(f, "{pad}await {fut} -> {to}{}", fmt_save(save))?
+            }
+            Node::Goto { save, to } => writeln!(f, "{pad}goto {to}{}", fmt_save(save))?,
+            Node::Return(value) => writeln!(f, "{pad}return {value}")?,
+        }
+    }
+    Ok(())
+}
+
+fn fmt_save(save: &[String]) -> String {
+    match save.is_empty() {
+        true => String::new(),
+        false => format!(" (save {})", save.join(", ")),
+    }
+}
+
+impl fmt::Display for Awaited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Awaited::Future(fut) => f.write_str(fut),
+            Awaited::Join(futs) => write!(f, "join!({})", futs.join(", ")),
+            Awaited::Select(futs) => write!(f, "select!({})", futs.join(", ")),
+            Awaited::JoinAll(futs) => write!(f, "join_all({futs})"),
+        }
+    }
+}
+
+/// Fragments are shown as the code they stand for, with `<< >>` around the
+/// parts we replace
+impl fmt::Display for Fragment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fragment::Text(text) => f.write_str(text),
+            Fragment::Line(_) => Ok(()),
+            Fragment::Jump { save, to } => write!(f, "<<goto {to}{}>>", fmt_save(save)),
+            Fragment::Return(value) => write!(f, "<<return {value}>>"),
+            Fragment::Pin { name, init } => {
+                let init: String = init.iter().map(ToString::to_string).collect();
+                write!(f, "<<pin {name} = {init}>>")
+            }
+        }
+    }
+}
@@ -0,0 +1,460 @@
+//! A small Rust tokenizer.
+//!
+//! It only knows as much about Rust as we need to find `coroutine` functions
+//! and split their bodies into statements: identifiers, lifetimes, literals
+//! (including raw strings), punctuation and balanced delimiters. Comments and
+//! whitespace are skipped, but every token keeps its byte span so we can
+//! always copy the original source text (comments included) back out.
+
+use std::fmt::{self, Display};
+
+/// Location of a token in the source. `line` and `col` are 1-based and
+/// point at the first character of the token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    /// A span covering both `self` and `other`, positioned at `self`
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delim {
+    Paren,
+    Bracket,
+    Brace,
+}
+
+impl Delim {
+    fn open(self) -> char {
+        match self {
+            Delim::Paren => '(',
+            Delim::Bracket => '[',
+            Delim::Brace => '{',
+        }
+    }
+
+    fn close(self) -> char {
+        match self {
+            Delim::Paren => ')',
+            Delim::Bracket => ']',
+            Delim::Brace => '}',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Identifiers and keywords, including raw identifiers (`r#type`)
+    Ident,
+    /// `'a`
+    Lifetime,
+    /// Strings, raw strings, byte strings, chars and numbers
+    Literal,
+    /// Operators and other punctuation. Common multi-character operators
+    /// such as `::`, `->` and `=>` are a single token.
+    Punct,
+    /// An opening delimiter. `pair` is the index of the matching `Close`
+    Open { delim: Delim, pair: usize },
+    /// A closing delimiter. `pair` is the index of the matching `Open`
+    Close { delim: Delim, pair: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn text<'a>(&self, src: &'a str) -> &'a str {
+        &src[self.span.start..self.span.end]
+    }
+
+    pub fn is_ident(&self, src: &str, ident: &str) -> bool {
+        self.kind == TokenKind::Ident && self.text(src) == ident
+    }
+
+    pub fn is_punct(&self, src: &str, punct: &str) -> bool {
+        self.kind == TokenKind::Punct && self.text(src) == punct
+    }
+
+    pub fn is_open(&self, delim: Delim) -> bool {
+        matches!(self.kind, TokenKind::Open { delim: d, .. } if d == delim)
+    }
+
+    /// Index of the matching delimiter if this is an `Open` or `Close` token
+    pub fn pair(&self) -> Option<usize> {
+        match self.kind {
+            TokenKind::Open { pair, .. } | TokenKind::Close { pair, .. } => Some(pair),
+            _ => None,
+        }
+    }
+}
+
+/// An error with a location in the source we're rewriting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub span: Option<Span>,
+    pub msg: String,
+}
+
+impl ParseError {
+    pub fn new(span: Span, msg: impl Into<String>) -> Self {
+        Self {
+            span: Some(span),
+            msg: msg.into(),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}:{}: {}", span.line, span.col, self.msg),
+            None => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Longest first, so `..=` wins over `..`
+const MULTI_CHAR_PUNCT: [&str; 19] = [
+    "..=", "...", "::", "->", "=>", "..", "==", "!=", "<=", "&&", "||", "+=", "-=", "*=", "/=",
+    "%=", "^=", "&=", "|=",
+];
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    line: usize,
+    col: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.src[self.pos..].chars().nth(n)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn bump_while(&mut self, f: impl Fn(char) -> bool) {
+        while self.peek().is_some_and(&f) {
+            self.bump();
+        }
+    }
+
+    fn here(&self) -> Span {
+        Span {
+            start: self.pos,
+            end: self.pos,
+            line: self.line,
+            col: self.col,
+        }
+    }
+
+    fn finish(&self, start: Span) -> Span {
+        Span {
+            end: self.pos,
+            ..start
+        }
+    }
+
+    /// Skips whitespace and comments. Block comments nest like they do in Rust.
+    fn skip_trivia(&mut self) -> Result<(), ParseError> {
+        loop {
+            match (self.peek(), self.peek_nth(1)) {
+                (Some(c), _) if c.is_whitespace() => {
+                    self.bump();
+                }
+                (Some('/'), Some('/')) => self.bump_while(|c| c != '\n'),
+                (Some('/'), Some('*')) => {
+                    let start = self.here();
+                    self.bump();
+                    self.bump();
+                    let mut depth = 1;
+                    while depth > 0 {
+                        match (self.peek(), self.peek_nth(1)) {
+                            (Some('/'), Some('*')) => {
+                                self.bump();
+                                self.bump();
+                                depth += 1;
+                            }
+                            (Some('*'), Some('/')) => {
+                                self.bump();
+                                self.bump();
+                                depth -= 1;
+                            }
+                            (Some(_), _) => {
+                                self.bump();
+                            }
+                            (None, _) => {
+                                return Err(ParseError::new(start, "Unterminated block comment"));
+                            }
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Reads a quoted string body after the opening `"`, honoring escapes
+    fn quoted(&mut self, start: Span) -> Result<(), ParseError> {
+        loop {
+            match self.bump() {
+                Some('\\') => {
+                    self.bump();
+                }
+                Some('"') => return Ok(()),
+                Some(_) => (),
+                None => return Err(ParseError::new(start, "Unterminated string literal")),
+            }
+        }
+    }
+
+    /// Reads a raw string after its prefix (`r`, `br` or `cr`), i.e. starting
+    /// at the `#`s or the opening `"`
+    fn raw_string(&mut self, start: Span) -> Result<(), ParseError> {
+        let mut hashes = 0;
+        while self.peek() == Some('#') {
+            self.bump();
+            hashes += 1;
+        }
+        if self.bump() != Some('"') {
+            return Err(ParseError::new(start, "Malformed raw string literal"));
+        }
+        loop {
+            match self.bump() {
+                Some('"') => {
+                    let mut closing = 0;
+                    while closing < hashes && self.peek() == Some('#') {
+                        self.bump();
+                        closing += 1;
+                    }
+                    if closing == hashes {
+                        return Ok(());
+                    }
+                }
+                Some(_) => (),
+                None => return Err(ParseError::new(start, "Unterminated raw string literal")),
+            }
+        }
+    }
+
+    /// Reads a char (or byte) literal after the opening `'`
+    fn char_literal(&mut self, start: Span) -> Result<(), ParseError> {
+        if self.bump() == Some('\\') {
+            // `'\u{1F600}'`, `'\n'`, `'\''`
+            self.bump();
+            self.bump_while(|c| c != '\'' && c != '\n');
+        }
+        match self.bump() {
+            Some('\'') => Ok(()),
+            _ => Err(ParseError::new(start, "Unterminated character literal")),
+        }
+    }
+
+    fn number(&mut self) {
+        let hex = self.peek() == Some('0') && matches!(self.peek_nth(1), Some('x' | 'X'));
+        self.bump_while(|c| c.is_alphanumeric() || c == '_');
+        // `1.5`, but not `0..5` or `1.max(2)`
+        if self.peek() == Some('.') && self.peek_nth(1).is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+            self.bump_while(|c| c.is_alphanumeric() || c == '_');
+        }
+        // `1e-3`
+        let exp = self.src[..self.pos].ends_with(['e', 'E']);
+        if !hex && exp && matches!(self.peek(), Some('+' | '-')) {
+            self.bump();
+            self.bump_while(|c| c.is_alphanumeric() || c == '_');
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Option<(TokenKind, Span)>, ParseError> {
+        self.skip_trivia()?;
+        let start = self.here();
+        let Some(c) = self.peek() else {
+            return Ok(None);
+        };
+
+        let kind = match (c, self.peek_nth(1), self.peek_nth(2)) {
+            // Raw strings, byte strings and C strings
+            ('r', Some('"'), _) | ('r', Some('#'), Some('"' | '#')) => {
+                self.bump();
+                self.raw_string(start)?;
+                TokenKind::Literal
+            }
+            ('b' | 'c', Some('r'), Some('"' | '#')) => {
+                self.bump();
+                self.bump();
+                self.raw_string(start)?;
+                TokenKind::Literal
+            }
+            ('b' | 'c', Some('"'), _) => {
+                self.bump();
+                self.bump();
+                self.quoted(start)?;
+                TokenKind::Literal
+            }
+            ('b', Some('\''), _) => {
+                self.bump();
+                self.bump();
+                self.char_literal(start)?;
+                TokenKind::Literal
+            }
+            // Raw identifiers
+            ('r', Some('#'), Some(n)) if n.is_alphabetic() || n == '_' => {
+                self.bump();
+                self.bump();
+                self.bump_while(|c| c.is_alphanumeric() || c == '_');
+                TokenKind::Ident
+            }
+            (c, _, _) if c.is_alphabetic() || c == '_' => {
+                self.bump_while(|c| c.is_alphanumeric() || c == '_');
+                TokenKind::Ident
+            }
+            (c, _, _) if c.is_ascii_digit() => {
+                self.number();
+                TokenKind::Literal
+            }
+            ('"', _, _) => {
+                self.bump();
+                self.quoted(start)?;
+                TokenKind::Literal
+            }
+            // `'\n'` and `'a'` are chars, `'a` and `'static` are lifetimes
+            ('\'', Some('\\'), _) => {
+                self.bump();
+                self.char_literal(start)?;
+                TokenKind::Literal
+            }
+            ('\'', Some(_), Some('\'')) => {
+                self.bump();
+                self.char_literal(start)?;
+                TokenKind::Literal
+            }
+            ('\'', Some(n), _) if n.is_alphabetic() || n == '_' => {
+                self.bump();
+                self.bump_while(|c| c.is_alphanumeric() || c == '_');
+                TokenKind::Lifetime
+            }
+            _ => {
+                let rest = &self.src[self.pos..];
+                let len = MULTI_CHAR_PUNCT
+                    .iter()
+                    .find(|p| rest.starts_with(*p))
+                    .map_or(1, |p| p.len());
+                for _ in 0..len {
+                    self.bump();
+                }
+                TokenKind::Punct
+            }
+        };
+
+        Ok(Some((kind, self.finish(start))))
+    }
+}
+
+/// Splits `src` into tokens and matches up delimiters. Unbalanced or
+/// mismatched delimiters are reported with the span of the offending token.
+pub fn tokenize(src: &str) -> Result<Vec<Token>, ParseError> {
+    let mut lexer = Lexer {
+        src,
+        pos: 0,
+        line: 1,
+        col: 1,
+    };
+    let mut tokens: Vec<Token> = vec![];
+    // Indices of the currently open delimiters
+    let mut open: Vec<usize> = vec![];
+
+    while let Some((kind, span)) = lexer.next_token()? {
+        let delim = match &src[span.start..span.end] {
+            "(" | ")" => Some(Delim::Paren),
+            "[" | "]" => Some(Delim::Bracket),
+            "{" | "}" => Some(Delim::Brace),
+            _ => None,
+        };
+
+        let kind = match (kind, delim) {
+            (TokenKind::Punct, Some(delim)) if src[span.start..].starts_with(delim.open()) => {
+                open.push(tokens.len());
+                // The real pair is filled in when we see the closing delimiter
+                TokenKind::Open { delim, pair: 0 }
+            }
+            (TokenKind::Punct, Some(delim)) => {
+                let Some(open_idx) = open.pop() else {
+                    return Err(ParseError::new(
+                        span,
+                        format!("Unexpected closing delimiter `{}`", delim.close()),
+                    ));
+                };
+                let opening = tokens[open_idx];
+                match opening.kind {
+                    TokenKind::Open { delim: d, .. } if d == delim => (),
+                    TokenKind::Open { delim: d, .. } => {
+                        return Err(ParseError::new(
+                            span,
+                            format!(
+                                "Mismatched closing delimiter `{}`, expected `{}` to close `{}` at {}:{}",
+                                delim.close(),
+                                d.close(),
+                                d.open(),
+                                opening.span.line,
+                                opening.span.col
+                            ),
+                        ));
+                    }
+                    _ => unreachable!("only `Open` tokens are pushed on the stack"),
+                }
+                tokens[open_idx].kind = TokenKind::Open {
+                    delim,
+                    pair: tokens.len(),
+                };
+                TokenKind::Close {
+                    delim,
+                    pair: open_idx,
+                }
+            }
+            (kind, _) => kind,
+        };
+
+        tokens.push(Token { kind, span });
+    }
+
+    if let Some(idx) = open.pop() {
+        let tok = tokens[idx];
+        return Err(ParseError::new(
+            tok.span,
+            format!("Unclosed delimiter `{}`", tok.text(src)),
+        ));
+    }
+
+    Ok(tokens)
+}
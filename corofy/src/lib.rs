@@ -1,486 +1,1914 @@
-use once_cell::sync::OnceCell;
-use std::error::Error;
-use std::fmt::{Display, Write as WriteFmt};
-use std::fs::File;
+use std::fmt::Write as WriteFmt;
 use std::io::Write;
+use std::ops::Range;
+
+mod cfg;
+mod codegen;
+mod error;
+mod explain;
+mod hoist;
+pub mod ir;
+mod lexer;
+mod sourcemap;
+mod target;
+
+use cfg::{Builder, Cfg, Term};
+pub use codegen::{generate, generate_nested, generate_with_map};
+pub use error::CorofyError;
+pub use explain::{Diagram, explain};
+use hoist::{Event, Local, Stack, Storage};
+use ir::{
+    Awaited, Coroutine, Fragment, Generics, LineEnding, Method, Node, Program, Receiver, Step,
+    Wait, result_error,
+};
+use lexer::{Delim, Token, TokenKind, tokenize};
+pub use lexer::{ParseError, Span};
+pub use sourcemap::SourceMap;
+pub use target::{Futures, Target};
 
 const FN_KW: &str = "coroutine";
 const W_KW: &str = "wait";
-static L_TRM_LN: OnceCell<usize> = OnceCell::new();
+/// The output of a coroutine without `-> Type` and of a `wait` whose value
+/// has no type annotation
+const DEFAULT_OUTPUT: &str = "String";
+
+/// `(name, type)` of each function argument
+type Args = Vec<(String, String)>;
 
-fn l_trm_len() -> usize {
-    *L_TRM_LN.get().expect("Line terminator len not set")
+/// Rewrites every `coroutine fn` in `src` into a state machine implementing
+/// the `Future` trait of `target` and writes the result to `dest`
+pub fn rewrite(src: String, mut dest: impl Write, target: Target) -> Result<(), CorofyError> {
+    // Parse everything before we write anything so we don't leave a half
+    // written file behind if one of the functions is malformed
+    let program = parse(&src).map_err(|e| CorofyError::parse(&src, e))?;
+    check(&program, target).map_err(|e| CorofyError::parse(&src, e))?;
+    dest.write_all(generate(&program, target).as_bytes())?;
+    Ok(())
 }
 
-pub fn rewrite(src: String, dest: File) -> Result<(), impl Display> {
-    detect_line_ending(&src);
-    let mut dest = dest;
+/// Parses every `coroutine fn` in `src` into a `Coroutine`. Nothing is
+/// generated yet, that's what `generate` is for.
+pub fn parse(src: &str) -> Result<Program, ParseError> {
+    let tokens = tokenize(src)?;
     // Find the start point of async blocks
-    let start_points = find_kw_start_points(&src);
+    let start_points = find_kw_start_points(src, &tokens);
 
     // No keywords, no async functions, do nothing
     if start_points.is_empty() {
-        return Err(format!("No `{FN_KW}` function found."));
+        return Err(ParseError {
+            span: None,
+            msg: format!("No `{FN_KW}` function found."),
+        });
     }
 
     let mut async_start_end = vec![];
+    // Where the text we take out for each coroutine starts
+    let mut cuts = vec![];
+    let mut coroutines = vec![];
 
-    for start in start_points {
-        // find the end
-        // Find the end of the async function
-        let mut brackets_counter = 0;
-        let mut end = start;
-
-        for char in src[start..].chars() {
-            end += 1;
-            match char {
-                '{' => brackets_counter += 1,
-                '}' => {
-                    brackets_counter -= 1;
-                    if brackets_counter == 0 {
-                        break;
-                    }
-                }
-                _ => (),
-            }
+    for (i, start) in start_points.into_iter().enumerate() {
+        if let Some(&(_, prev_end)) = async_start_end.last()
+            && start < prev_end
+        {
+            return Err(ParseError::new(
+                tokens[start].span,
+                format!("`{FN_KW}` functions can't be nested"),
+            ));
         }
 
+        let id = i.to_string();
+        let sig = parse_signature(src, &tokens, start)?;
+        let end = sig.body.end;
+        let mut cut = tokens[start].span.start;
+        let coroutine = match enclosing_impl(src, &tokens, start)? {
+            Some(method) => parse_method(src, &tokens, sig, method, &id)?,
+            None => match sig.receiver {
+                Some((_, span)) => {
+                    return Err(ParseError::new(
+                        span,
+                        format!("A `{FN_KW}` with a `self` receiver has to be in an `impl` block"),
+                    ));
+                }
+                // A free coroutine moves, and what's in front of it moves along
+                None => {
+                    cut = item_head(src, &tokens, start);
+                    let mut coroutine = parse_coroutine(src, &tokens, sig, &id)?;
+                    // It goes in front of the plain function, which isn't indented
+                    coroutine.head = src[cut..tokens[start].span.start]
+                        .split_inclusive('\n')
+                        .map(str::trim_start)
+                        .collect();
+                    coroutine
+                }
+            },
+        };
+        coroutines.push(coroutine);
+
         // store the locations
         async_start_end.push((start, end));
+        cuts.push(cut);
     }
 
-    // Write everything except the async functions back to the file
-    // (we put the rewritten code last in the file since it's easier
-    // to see)
-    let mut pos_tracker = 0;
-    for (start, end) in &async_start_end {
-        dest.write_all(&src[pos_tracker..*start].as_bytes())
-            .unwrap();
-        pos_tracker = *end;
+    // Everything except the async functions
+    let mut rest = vec![];
+    let (mut pos_tracker, mut line) = (0, 1);
+    for (&(_, end), &cut) in async_start_end.iter().zip(&cuts) {
+        rest.push((line, src[pos_tracker..cut].to_string()));
+        pos_tracker = tokens[end].span.end;
+        line = tokens[end].span.line;
     }
-    // Write everything after the last async fn
-    dest.write_all(&src[pos_tracker..].as_bytes()).unwrap();
+    // Everything after the last async fn
+    rest.push((line, src[pos_tracker..].to_string()));
 
-    // transform the async functions and write them to the file
+    Ok(Program {
+        rest,
+        coroutines,
+        line_ending: LineEnding::detect(src),
+        log_cancel: false,
+        futures: Futures::Boxed,
+        in_place: false,
+    })
+}
 
-    for (i, (start, end)) in async_start_end.into_iter().enumerate() {
-        let id = i.to_string();
+/// Whether `target` can run every coroutine in `program`. A reference into
+/// the coroutine's own stack only stays valid if the coroutine doesn't move
+/// between polls, and only `std` pins it.
+pub fn check(program: &Program, target: Target) -> Result<(), ParseError> {
+    if target == Target::Std {
+        return Ok(());
+    }
+    let self_ref = program
+        .coroutines
+        .iter()
+        .flat_map(|c| &c.stack)
+        .find(|v| matches!(v.storage, Storage::Ref { .. }));
+    match self_ref {
+        Some(var) => Err(ParseError::new(
+            var.span,
+            format!(
+                "`{}` borrows a local of the coroutine across a `wait`, which only works \
+                 if the coroutine can't move between polls. Use the `std` target \
+                 (`--target std`), where the coroutine is pinned.",
+                var.name
+            ),
+        )),
+        None => Ok(()),
+    }
+}
 
-        let async_fn = String::from(&src[start..end - 1]);
+/// Whether `src` has a `coroutine fn` to rewrite. A `coroutine fn` in a
+/// comment or a string doesn't count.
+pub fn has_coroutines(src: &str) -> bool {
+    match tokenize(src) {
+        Ok(tokens) => !find_kw_start_points(src, &tokens).is_empty(),
+        // Let `parse` tell what's wrong with it
+        Err(_) => src.contains(FN_KW),
+    }
+}
 
-        // transfrom the async fn
-        let transformed = transform(&async_fn, &id);
+/// Returns the index of every `coroutine` token that starts a `coroutine fn`.
+/// Since we work on tokens, `coroutine` in comments, strings or as part of a
+/// longer identifier is never mistaken for the keyword.
+fn find_kw_start_points(src: &str, tokens: &[Token]) -> Vec<usize> {
+    tokens
+        .windows(2)
+        .enumerate()
+        .filter(|(_, w)| w[0].is_ident(src, FN_KW) && w[1].is_ident(src, "fn"))
+        .map(|(i, _)| i)
+        .collect()
+}
 
-        // Write the coroutine implementation to file
-        dest.write_all(transformed.as_bytes()).unwrap();
+/// Where the item with the `coroutine` keyword at `start` starts: its doc
+/// comments, attributes and visibility come first
+fn item_head(src: &str, tokens: &[Token], start: usize) -> usize {
+    let mut head = start;
+    while head > 0 {
+        let prev = &tokens[head - 1];
+        head = match prev.kind {
+            _ if prev.is_ident(src, "pub") => head - 1,
+            // `pub(crate)` and `#[attr]`
+            TokenKind::Close {
+                delim: Delim::Paren,
+                pair,
+            } if pair > 0 && tokens[pair - 1].is_ident(src, "pub") => pair - 1,
+            TokenKind::Close {
+                delim: Delim::Bracket,
+                pair,
+            } if pair > 0 && tokens[pair - 1].is_punct(src, "#") => pair - 1,
+            _ => break,
+        };
     }
 
-    Ok(())
+    // Doc comments aren't tokens, they're the lines right before the head
+    let gap_start = head.checked_sub(1).map_or(0, |prev| tokens[prev].span.end);
+    let gap = &src[gap_start..tokens[head].span.start];
+    let mut cut = tokens[head].span.start;
+    // The indentation of the head isn't part of it
+    let lines = &gap[..gap.rfind('\n').map_or(0, |i| i + 1)];
+    let mut line_start = gap_start + lines.len();
+    for line in lines.split_inclusive('\n').rev() {
+        line_start -= line.len();
+        let doc = line.trim_start();
+        if !doc.starts_with("///") || doc.starts_with("////") {
+            break;
+        }
+        cut = line_start + line.len() - doc.len();
+    }
+    cut
+}
+
+/// The parts of a `coroutine fn` we need to rewrite it
+struct Signature {
+    /// Token index of the `coroutine` keyword
+    start: usize,
+    name: String,
+    generics: Generics,
+    /// `&self` and where it is, `args` has the other arguments
+    receiver: Option<(Receiver, Span)>,
+    args: Args,
+    /// The return type, `None` if there's no `-> Type`
+    ret: Option<String>,
+    /// Token range of the function body (from the opening to the closing brace)
+    body: Range<usize>,
 }
 
-fn find_kw_start_points(s: &str) -> Vec<usize> {
-    let mut start_points = vec![];
-    let mut index = 0;
+// Parses the signature of the coroutine fn starting at `start`
+fn parse_signature(src: &str, tokens: &[Token], start: usize) -> Result<Signature, ParseError> {
+    // The definition is expected to be `keyword fn name(args) -> ReturnType {`
+    // but it can span several lines. Skip `keyword fn`.
+    let name = expect(
+        tokens,
+        start + 2,
+        "Expected a function name after `fn`",
+        |t| t.kind == TokenKind::Ident,
+    )?;
+    let fn_name = name.text(src);
+
+    // `fn name<'a, T: Clone>(..)`
+    let (params, after) = match tokens.get(start + 3) {
+        Some(t) if t.is_punct(src, "<") => parse_generics(src, tokens, start + 3)?,
+        _ => (vec![], start + 3),
+    };
+    let open = expect(tokens, after, "Expected `(`", |t| t.is_open(Delim::Paren))?;
+    let close = open.pair().unwrap();
+
+    // The receiver is the first argument, if there is one
+    let mut args = split_list(src, tokens, after + 1..close);
+    let receiver = match args.first() {
+        Some(first)
+            if tokens[first.clone()]
+                .iter()
+                .any(|t| t.is_ident(src, "self")) =>
+        {
+            let span = tokens[first.start].span.to(tokens[first.end - 1].span);
+            let receiver = parse_receiver(src, tokens, first.clone())?;
+            args.remove(0);
+            Some((receiver, span))
+        }
+        _ => None,
+    };
+    let args = args
+        .into_iter()
+        .map(|arg| get_arg(src, tokens, arg))
+        .collect::<Result<_, _>>()?;
+
+    // The body is the first brace group after the arguments
+    let body_open = (close + 1..tokens.len())
+        .find(|&i| tokens[i].is_open(Delim::Brace) || tokens[i].is_punct(src, ";"))
+        .filter(|&i| tokens[i].is_open(Delim::Brace))
+        .ok_or_else(|| {
+            ParseError::new(
+                name.span,
+                format!("Expected a body for `{FN_KW} fn {fn_name}`"),
+            )
+        })?;
+    let body_close = tokens[body_open].pair().unwrap();
+
+    // `-> Type` and `where ..`, anything else between the arguments and the
+    // body is ignored
+    let where_kw = find_top_level_ident(src, tokens, close + 1..body_open, "where");
+    let ret = tokens
+        .get(close + 1)
+        .filter(|t| t.is_punct(src, "->") && close + 2 < where_kw.unwrap_or(body_open))
+        .map(|_| {
+            let end = where_kw.unwrap_or(body_open);
+            src[tokens[close + 2].span.start..tokens[end - 1].span.end].to_string()
+        });
+    let predicates = match where_kw {
+        Some(kw) => split_list(src, tokens, kw + 1..body_open),
+        None => vec![],
+    };
+    let text =
+        |r: Range<usize>| src[tokens[r.start].span.start..tokens[r.end - 1].span.end].to_string();
+
+    Ok(Signature {
+        start,
+        name: fn_name.to_string(),
+        generics: Generics {
+            params,
+            predicates: predicates.into_iter().map(text).collect(),
+        },
+        receiver,
+        args,
+        ret,
+        body: body_open..body_close,
+    })
+}
+
+fn expect<'t>(
+    tokens: &'t [Token],
+    idx: usize,
+    msg: &str,
+    f: impl Fn(&Token) -> bool,
+) -> Result<&'t Token, ParseError> {
+    match tokens.get(idx) {
+        Some(t) if f(t) => Ok(t),
+        Some(t) => Err(ParseError::new(t.span, msg)),
+        None => Err(ParseError::new(
+            tokens.last().map(|t| t.span).unwrap_or_default(),
+            msg,
+        )),
+    }
+}
 
-    for line in s.lines() {
-        // Remove everything that's commented
-        let (txt, _) = match line.split_once("//") {
-            Some((txt, commented)) => (txt, commented),
-            None => (line, ""),
+/// The parameters in the `<..>` that starts at `open`, and the index of the
+/// token after it
+fn parse_generics(
+    src: &str,
+    tokens: &[Token],
+    open: usize,
+) -> Result<(Vec<String>, usize), ParseError> {
+    let mut depth = 0;
+    let mut i = open;
+    while i < tokens.len() {
+        if tokens[i].is_punct(src, "<") {
+            depth += 1;
+        } else if tokens[i].is_punct(src, ">") {
+            depth -= 1;
+            if depth == 0 {
+                let params = split_list(src, tokens, open + 1..i)
+                    .into_iter()
+                    .map(|r| {
+                        src[tokens[r.start].span.start..tokens[r.end - 1].span.end].to_string()
+                    })
+                    .collect();
+                return Ok((params, i + 1));
+            }
+        }
+        i = match tokens[i].kind {
+            TokenKind::Open { pair, .. } => pair + 1,
+            _ => i + 1,
         };
+    }
+    Err(ParseError::new(tokens[open].span, "Expected `>`"))
+}
 
-        // Search in the text that's not commented
-        match txt.find(FN_KW) {
-            Some(kw_start) => {
-                start_points.push(index + kw_start);
-                index += line.len() + l_trm_len();
+/// `self`, `mut self`, `&self`, `&mut self`, `&'a self` or `&'a mut self`
+fn parse_receiver(
+    src: &str,
+    tokens: &[Token],
+    range: Range<usize>,
+) -> Result<Receiver, ParseError> {
+    let is = |t: &Token, text: &str| t.text(src) == text && t.kind != TokenKind::Literal;
+    let receiver = match &tokens[range.clone()] {
+        [s] if is(s, "self") => Receiver::Owned { mutable: false },
+        [m, s] if is(m, "mut") && is(s, "self") => Receiver::Owned { mutable: true },
+        [amp, rest @ ..] if is(amp, "&") => {
+            let (lifetime, rest) = match rest {
+                [lt, rest @ ..] if lt.kind == TokenKind::Lifetime => {
+                    (Some(lt.text(src).to_string()), rest)
+                }
+                rest => (None, rest),
+            };
+            match rest {
+                [s] if is(s, "self") => Receiver::Ref {
+                    mutable: false,
+                    lifetime,
+                },
+                [m, s] if is(m, "mut") && is(s, "self") => Receiver::Ref {
+                    mutable: true,
+                    lifetime,
+                },
+                _ => return Err(receiver_error(tokens, range)),
             }
+        }
+        _ => return Err(receiver_error(tokens, range)),
+    };
+    Ok(receiver)
+}
+
+fn receiver_error(tokens: &[Token], range: Range<usize>) -> ParseError {
+    ParseError::new(
+        tokens[range.start].span.to(tokens[range.end - 1].span),
+        format!("`{FN_KW}` methods take `self`, `&self` or `&mut self`"),
+    )
+}
+
+/// The `impl` block the `coroutine fn` at `start` is in, if it's right in
+/// one and not in a function or a module
+fn enclosing_impl(src: &str, tokens: &[Token], start: usize) -> Result<Option<Method>, ParseError> {
+    // The brace group around it
+    let mut open = start;
+    loop {
+        if open == 0 {
+            return Ok(None);
+        }
+        open -= 1;
+        match tokens[open].kind {
+            TokenKind::Close { pair, .. } => open = pair,
+            TokenKind::Open {
+                delim: Delim::Brace,
+                ..
+            } => break,
+            TokenKind::Open { .. } => return Ok(None),
+            _ => (),
+        }
+    }
 
-            None => {
-                // An empty line will only be a `\n`
-                let len = if line.len() == 0 {
-                    l_trm_len()
-                } else {
-                    line.len() + l_trm_len()
-                };
-                index += len;
+    // `impl<T> Type<T> where .. {` or `impl<T> Trait for Type<T> {`
+    let mut kw = open;
+    while kw > 0 {
+        kw -= 1;
+        match tokens[kw].kind {
+            TokenKind::Close {
+                delim: Delim::Brace,
+                ..
             }
+            | TokenKind::Open { .. } => return Ok(None),
+            TokenKind::Close { pair, .. } => kw = pair,
+            _ if tokens[kw].is_punct(src, ";") => return Ok(None),
+            _ if tokens[kw].is_ident(src, "impl") => break,
+            _ => (),
         }
     }
-    start_points
+    if !tokens[kw].is_ident(src, "impl") {
+        return Ok(None);
+    }
+    let (params, ty_start) = match tokens.get(kw + 1) {
+        Some(t) if t.is_punct(src, "<") => parse_generics(src, tokens, kw + 1)?,
+        _ => (vec![], kw + 1),
+    };
+    let where_kw = find_top_level_ident(src, tokens, ty_start..open, "where");
+    let ty_end = where_kw.unwrap_or(open);
+    let ty_start =
+        find_top_level_ident(src, tokens, ty_start..ty_end, "for").map_or(ty_start, |f| f + 1);
+    if ty_start >= ty_end {
+        return Ok(None);
+    }
+    let text =
+        |r: Range<usize>| src[tokens[r.start].span.start..tokens[r.end - 1].span.end].to_string();
+    let predicates = match where_kw {
+        Some(kw) => split_list(src, tokens, kw + 1..open),
+        None => vec![],
+    };
+    Ok(Some(Method {
+        self_ty: text(ty_start..ty_end),
+        generics: Generics {
+            params,
+            predicates: predicates.into_iter().map(text).collect(),
+        },
+        receiver: None,
+        close: tokens[tokens[open].pair().unwrap_or(open)].span.line,
+    }))
 }
 
-// Transforms an async function into a state machine, "mimmicing"
-// what happens when compiling an async function in Rust
-fn transform(async_fn: &str, id: &str) -> String {
-    // first Comment out the async function
-    let commented = comment_orig(&async_fn);
-    // Then  rewrite the async function itself
-    let (args, new_async_fn) = create_new_async_fn(&async_fn, &id);
-    // Rewrite the async function to a state machine
-    let rewritten = rewrite_async_fn(&async_fn, &id, args).unwrap();
-    format!("{commented}{new_async_fn}{rewritten}")
+/// Parses a `coroutine fn` in an `impl` block. The state machine is outside
+/// of the block, so in there `self` is the `this` argument and `Self` is the
+/// type of the block.
+fn parse_method(
+    src: &str,
+    tokens: &[Token],
+    sig: Signature,
+    mut method: Method,
+    id: &str,
+) -> Result<Coroutine, ParseError> {
+    let Signature {
+        start,
+        body,
+        receiver,
+        ..
+    } = sig;
+    if let Some(this) = tokens[body.clone()]
+        .iter()
+        .find(|t| t.is_ident(src, "this"))
+    {
+        return Err(ParseError::new(
+            this.span,
+            format!(
+                "`this` is what `self` is called in the state machine of a `{FN_KW}` method. Rename it."
+            ),
+        ));
+    }
+
+    // The arguments and the return type need the whole type, expressions
+    // like `Self::new()` or `Self { .. }` can't have its generic arguments
+    let self_ty = method.self_ty.clone();
+    let self_path = self_ty.split('<').next().unwrap_or_default().trim();
+    let mut rewritten = String::new();
+    let mut pos = 0;
+    for (i, tok) in tokens.iter().enumerate().take(body.end).skip(start) {
+        let replacement = match (tok.text(src), i > body.start) {
+            ("self", true) if tok.kind == TokenKind::Ident => "this",
+            ("Self", false) if tok.kind == TokenKind::Ident => &self_ty,
+            ("Self", true) if tok.kind == TokenKind::Ident => self_path,
+            _ => continue,
+        };
+        rewritten.push_str(&src[pos..tok.span.start]);
+        rewritten.push_str(replacement);
+        pos = tok.span.end;
+    }
+    rewritten.push_str(&src[pos..]);
+
+    let rewritten_tokens = tokenize(&rewritten)?;
+    let mut sig = parse_signature(&rewritten, &rewritten_tokens, start)?;
+    if let Some((receiver, _)) = &receiver {
+        let ty = match receiver {
+            Receiver::Owned { .. } => self_ty.clone(),
+            Receiver::Ref { mutable, lifetime } => {
+                let lifetime = lifetime.as_deref().unwrap_or(ir::RECEIVER_LIFETIME);
+                let mutable = if *mutable { "mut " } else { "" };
+                format!("&{lifetime} {mutable}{self_ty}")
+            }
+        };
+        let name = match receiver {
+            Receiver::Owned { mutable: true } => "mut this",
+            _ => "this",
+        };
+        sig.args.insert(0, (name.to_string(), ty));
+    }
+    let mut coroutine = parse_coroutine(&rewritten, &rewritten_tokens, sig, id)?;
+    coroutine.original = src[tokens[start].span.start..tokens[body.end].span.end].to_string();
+    method.receiver = receiver.map(|(receiver, _)| receiver);
+    coroutine.method = Some(method);
+    Ok(coroutine)
 }
 
-/// Format and comment out the original "async" function
-fn comment_orig(orig: &str) -> String {
-    let mut res = String::new();
-    writeln!(
-        &mut res,
-        "
+/// A statement without a `wait` that we copy as it is
+struct Stmt {
+    range: Range<usize>,
+    /// Locals this statement declares if it's a `let`
+    decls: Vec<Local>,
+    /// The initializer if this is a `let` we know how to hoist
+    init: Option<Range<usize>>,
+    /// Locals (or anything else that looks like one) this statement reads
+    uses: Vec<String>,
+}
 
-// =================================
-// We rewrite this:
-// =================================
-    "
-    )
-    .unwrap();
-    for line in orig.lines() {
-        writeln!(&mut res, "// {line}").unwrap();
-    }
-    writeln!(
-        &mut res,
-        "
-// }}
-
-// =================================
-// Into this:
-// =================================
-"
-    )
-    .unwrap();
+/// An await point: `let pat: Ty = fut.wait;` or `fut.wait;`
+struct AwaitPoint {
+    /// The pattern the value is bound to, `_` if it isn't bound
+    pattern: String,
+    decls: Vec<Local>,
+    fut: Awaited,
+    /// Where the future starts, for errors about its output type
+    span: Span,
+    /// The output type of the future, `Ty` if it's annotated. With `?` it's
+    /// the type of the value `?` gets out of it.
+    output: String,
+    /// `fut.wait?`
+    propagate: bool,
+    uses: Vec<String>,
+    /// The line the statement starts on
+    line: usize,
+}
 
-    res
+impl AwaitPoint {
+    /// Fails if `output` can't be the value of a `join!` or `join_all`
+    fn check_output(&self) -> Result<(), ParseError> {
+        if self.fut.outputs(&self.output).is_some() {
+            return Ok(());
+        }
+        let expected = match &self.fut {
+            Awaited::Join(futs) => format!("a tuple of {} types", futs.len()),
+            _ => "a `Vec<T>`".to_string(),
+        };
+        Err(ParseError::new(
+            self.span,
+            format!(
+                "The value of `{}` is {expected}, not `{}`",
+                self.fut, self.output
+            ),
+        ))
+    }
+}
+
+/// Splits the coroutine into the steps of a state machine (this is very
+/// brittle, but does the job for our example)
+fn parse_coroutine(
+    src: &str,
+    tokens: &[Token],
+    sig: Signature,
+    id: &str,
+) -> Result<Coroutine, ParseError> {
+    let Signature {
+        start,
+        name,
+        generics,
+        args,
+        ret,
+        body,
+        ..
+    } = sig;
+
+    // `?` would return from `poll`, so a coroutine that uses it runs the code
+    // you wrote in a method that returns a `Result` with the same error type
+    let error = match find_try(src, tokens, body.start + 1..body.end) {
+        Some(q) => Some(ret.as_deref().and_then(result_error).ok_or_else(|| {
+            ParseError::new(
+                tokens[q].span,
+                format!(
+                    "`?` is only supported in a `{FN_KW}` that returns `Result<T, E>`, with the \
+                     error type written out"
+                ),
+            )
+        })?),
+        None => None,
+    };
+
+    let mut arg_decls = vec![];
+    for (name, ty) in &args {
+        let span = tokens[body.start].span;
+        let (mutable, name) = match name.strip_prefix("mut ") {
+            Some(name) => (true, name.trim_start()),
+            None => (false, name.as_str()),
+        };
+        if is_ident(name) {
+            arg_decls.push(Local {
+                name: name.to_string(),
+                mutable,
+                ty: Some(ty.clone()),
+                simple: true,
+                span,
+            });
+            continue;
+        }
+        // A pattern like `(a, b)` is destructured in `Start`. As with a `let`
+        // like that, its bindings can't be stored across a `wait`.
+        let pattern = tokenize(name)?;
+        for (name, _) in find_bindings(name, &pattern, 0..pattern.len()) {
+            let span = tokens[start..body.start]
+                .iter()
+                .find(|t| t.is_ident(src, &name))
+                .map_or(span, |t| t.span);
+            arg_decls.push(Local {
+                name,
+                mutable: false,
+                ty: None,
+                simple: false,
+                span,
+            });
+        }
+    }
+
+    // Split the body into blocks at the await points and the control flow
+    // around them
+    let stmts = split_statements(src, tokens, body.start + 1..body.end);
+    let start_pos = tokens[body.start].span.end;
+    let mut builder = Builder::new(src, tokens, start_pos, arg_decls, ret.as_deref());
+    builder.lower_body(stmts, body.end)?;
+    let cfg = builder.finish();
+
+    // Anything that looks like `.wait` but isn't one of the await points
+    // we found above is somewhere we can't split the function
+    for i in body.start + 1..body.end {
+        if is_wait(src, tokens, i) && !cfg.wait_tokens.contains(&i) {
+            return Err(ParseError::new(
+                tokens[i].span,
+                format!(
+                    "`{W_KW}` is only supported at the end of a statement in the function body \
+                     or in the body of an `if`, `match`, `loop` or `while`"
+                ),
+            ));
+        }
+    }
+
+    let reachable = cfg.reachable();
+    let states = cfg.states(&reachable);
+    for (block, reachable) in cfg.blocks.iter().zip(&reachable) {
+        if *reachable && matches!(block.term, Term::Return(None)) {
+            return Err(ParseError::new(
+                tokens[body.end].span,
+                format!(
+                    "Expected a tail expression or a `return` to resolve to a `{}`",
+                    ret.as_deref().unwrap_or(DEFAULT_OUTPUT)
+                ),
+            ));
+        }
+    }
+
+    // Record what each block does with the locals so we know which ones
+    // live across a `wait`
+    let mut events = vec![];
+    let mut borrows = vec![];
+    for block in &cfg.blocks {
+        let mut ev: Vec<Event> = block.decls.iter().cloned().map(Event::Def).collect();
+        for stmt in &block.stmts {
+            ev.extend(stmt.uses.iter().cloned().map(Event::Use));
+            ev.extend(stmt.decls.iter().cloned().map(Event::Def));
+            if let ([local], Some(_)) = (&stmt.decls[..], &stmt.init) {
+                borrows.push((local.name.clone(), stmt.uses.clone()));
+            }
+        }
+        ev.extend(block.term_uses.iter().cloned().map(Event::Use));
+        events.push(ev);
+    }
+    let stack = Stack::analyze(&events, &cfg.succs(), &states, &borrows)?;
+
+    let live: Vec<bool> = states
+        .iter()
+        .zip(&reachable)
+        .map(|(s, r)| *s && *r)
+        .collect();
+    let mut steps = Machine::new(src, tokens, &cfg, &stack, &live, ret.as_deref()).steps();
+    // The future of a `fut.wait?` fails with the error type of the coroutine
+    if let Some(error) = &error {
+        for wait in steps.iter_mut().filter_map(|step| step.wait.as_mut()) {
+            if wait.propagate {
+                wait.output = format!("Result<{}, {error}>", wait.output);
+            }
+        }
+    }
+
+    Ok(Coroutine {
+        id: id.to_string(),
+        name,
+        line: tokens[start].span.line,
+        args,
+        ret,
+        generics,
+        method: None,
+        error,
+        head: String::new(),
+        original: src[tokens[start].span.start..tokens[body.end].span.end].to_string(),
+        stack: stack.vars,
+        steps,
+    })
 }
 
-// Returns the new async function and it's arguments
-fn create_new_async_fn(func: &str, coro_id: &str) -> (Vec<(String, String)>, String) {
-    // first line is expected to be the function definition `keyword fn name() -> ReturnType`
-    // remove the keyword
-    let def = &func.lines().nth(0).unwrap()[FN_KW.len() + 1..];
+fn parse_stmt(src: &str, tokens: &[Token], range: Range<usize>) -> Result<Stmt, ParseError> {
+    if !tokens[range.start].is_ident(src, "let") {
+        return Ok(Stmt {
+            uses: find_uses(src, tokens, range.clone()),
+            range,
+            decls: vec![],
+            init: None,
+        });
+    }
 
-    // get the name of the function
-    let (fn_name, arg_start) = def.split_once("(").expect("Expected `(`").clone();
-    let (args, _) = arg_start.split_once(")").expect("Expected `)`");
+    let Let { decls, init, .. } = parse_let(src, tokens, range.clone())?;
+    let uses = init
+        .clone()
+        .map(|init| find_uses(src, tokens, init))
+        .unwrap_or_default();
+    // `let a = b else { .. }` can't be hoisted
+    let hoistable = init
+        .clone()
+        .is_some_and(|init| init.end == range.end || tokens[init.end].is_punct(src, ";"));
 
-    let args = get_args(&args);
+    Ok(Stmt {
+        range,
+        decls,
+        init: init.filter(|_| hoistable),
+        uses,
+    })
+}
 
-    let args_fmt = format_args_name_and_types(&args);
-    let arg_names = if args.is_empty() {
-        format!("()")
+fn parse_await(
+    src: &str,
+    tokens: &[Token],
+    stmt: Range<usize>,
+    dot: usize,
+) -> Result<AwaitPoint, ParseError> {
+    let point = parse_await_point(src, tokens, stmt, dot)?;
+    let combinator = match point.fut {
+        Awaited::Future(_) => return Ok(point),
+        Awaited::Join(_) => "join!",
+        Awaited::Select(_) => "select!",
+        Awaited::JoinAll(_) => "join_all",
+    };
+    match point.propagate {
+        true => Err(ParseError::new(
+            tokens[dot + 2].span,
+            format!(
+                "`?` on the value of `{combinator}` isn't supported. Bind the value with `let` \
+                 and use `?` on the `Result`s in it."
+            ),
+        )),
+        false => Ok(point),
+    }
+}
+
+/// `parse_await` for any future, `?` or not
+fn parse_await_point(
+    src: &str,
+    tokens: &[Token],
+    stmt: Range<usize>,
+    dot: usize,
+) -> Result<AwaitPoint, ParseError> {
+    let propagate = tokens.get(dot + 2).is_some_and(|t| t.is_punct(src, "?"));
+    if tokens[stmt.start].is_ident(src, "let") {
+        let Let {
+            decls,
+            pattern,
+            ty,
+            init,
+        } = parse_let(src, tokens, stmt.start..dot)?;
+        let Some(init) = init.filter(|init| !init.is_empty()) else {
+            return Err(ParseError::new(
+                tokens[stmt.start].span,
+                format!("Expected `=` followed by the future to `{W_KW}` on"),
+            ));
+        };
+        let fut = parse_awaited(src, tokens, init.start..dot)?;
+        // We can't know the output type of the future unless it's annotated
+        let output = ty.unwrap_or_else(|| fut.default_output());
+        let mut point = AwaitPoint {
+            pattern,
+            decls,
+            fut,
+            span: tokens[init.start].span,
+            output,
+            propagate,
+            uses: find_uses(src, tokens, init),
+            line: tokens[stmt.start].span.line,
+        };
+        point.check_output()?;
+        for local in point.decls.iter_mut() {
+            local.ty.get_or_insert_with(|| point.output.clone());
+        }
+        // `let (a, mut b) = join!(..).wait` binds locals like their own `let`s
+        // would, each with the output type of its future
+        if let Some(outputs) = point.fut.outputs(&point.output)
+            && let Awaited::Join(_) = point.fut
+            && let Some(decls) = tuple_locals(src, tokens, stmt.start + 1, &outputs)
+        {
+            point.decls = decls;
+        }
+        Ok(point)
+    } else if let Some(eq) = find_top_level(src, tokens, stmt.start..dot, "=") {
+        Err(ParseError::new(
+            tokens[eq].span,
+            format!("Only `let` bindings can receive the value of a `{W_KW}`"),
+        ))
     } else {
-        format_args_names_only(&args)
+        let fut = parse_awaited(src, tokens, stmt.start..dot)?;
+        Ok(AwaitPoint {
+            pattern: "_".to_string(),
+            decls: vec![],
+            output: fut.default_output(),
+            fut,
+            span: tokens[stmt.start].span,
+            propagate,
+            uses: find_uses(src, tokens, stmt.start..dot),
+            line: tokens[stmt.start].span.line,
+        })
+    }
+}
+
+/// The locals in a `(a, mut b, _)` pattern at `open`, if it's that simple
+/// and has one element for each type in `types`
+fn tuple_locals(src: &str, tokens: &[Token], open: usize, types: &[String]) -> Option<Vec<Local>> {
+    let TokenKind::Open {
+        delim: Delim::Paren,
+        pair,
+    } = tokens[open].kind
+    else {
+        return None;
+    };
+    if !tokens[pair + 1].is_punct(src, ":") && !tokens[pair + 1].is_punct(src, "=") {
+        return None;
+    }
+    let mut decls = vec![];
+    let mut start = open + 1;
+    let mut elements = 0;
+    while start < pair {
+        let end = find_top_level(src, tokens, start..pair, ",").unwrap_or(pair);
+        let (mutable, name) = match &tokens[start..end] {
+            [name] => (false, name),
+            [m, name] if m.is_ident(src, "mut") => (true, name),
+            _ => return None,
+        };
+        if name.kind != TokenKind::Ident || elements == types.len() {
+            return None;
+        }
+        if name.text(src) != "_" {
+            decls.push(Local {
+                name: name.text(src).to_string(),
+                mutable,
+                ty: Some(types[elements].clone()),
+                simple: true,
+                span: name.span,
+            });
+        }
+        elements += 1;
+        start = end + 1;
+    }
+    (elements == types.len()).then_some(decls)
+}
+
+/// What the future in `range` (the tokens before `.wait`) is. `join!(..)`,
+/// `select!(..)` and `join_all(..)` are ours, only a path like
+/// `future::join_all(..)` calls a function you wrote.
+fn parse_awaited(src: &str, tokens: &[Token], range: Range<usize>) -> Result<Awaited, ParseError> {
+    if range.is_empty() {
+        return Err(ParseError::new(
+            tokens[range.end].span,
+            format!("Expected a future before `.{W_KW}`"),
+        ));
+    }
+    let text = |r: Range<usize>| &src[tokens[r.start].span.start..tokens[r.end - 1].span.end];
+    let args = |open: usize| match tokens[open].kind {
+        TokenKind::Open {
+            delim: Delim::Paren,
+            pair,
+        } if pair == range.end - 1 => Some(open + 1..pair),
+        _ => None,
+    };
+    let name = tokens[range.start].text(src);
+    let (combinator, args) = match &tokens[range.clone()] {
+        [_, bang, ..] if bang.is_punct(src, "!") && matches!(name, "join" | "select") => {
+            (name, args(range.start + 2))
+        }
+        [_, ..] if name == "join_all" => (name, args(range.start + 1)),
+        _ => ("", None),
+    };
+    let Some(args) = args else {
+        return Ok(Awaited::Future(text(range).to_string()));
     };
 
-    let new_async_fn = format!(
-        "{fn_name}({args_fmt}) -> impl Future<Output=String> {{
-    Coroutine{coro_id}::new{arg_names}
-}}
-        "
-    );
+    // The futures, each up to a `,` that isn't nested in anything
+    let mut futs = vec![];
+    let mut start = args.start;
+    while start < args.end {
+        let end = find_top_level(src, tokens, start..args.end, ",").unwrap_or(args.end);
+        if start < end {
+            futs.push(text(start..end).to_string());
+        }
+        start = end + 1;
+    }
+    match (combinator, futs.len()) {
+        (_, 0) => Err(ParseError::new(
+            tokens[range.start].span,
+            format!("`{}` needs at least one future", text(range)),
+        )),
+        ("join_all", 1) => Ok(Awaited::JoinAll(futs.remove(0))),
+        ("join_all", _) => Err(ParseError::new(
+            tokens[range.start].span,
+            "`join_all` takes a single `Vec` of futures",
+        )),
+        ("join", _) => Ok(Awaited::Join(futs)),
+        _ => Ok(Awaited::Select(futs)),
+    }
+}
 
-    (args, new_async_fn)
+/// `let pat: Ty = init`
+struct Let {
+    /// The locals the pattern declares
+    decls: Vec<Local>,
+    pattern: String,
+    /// The type annotation
+    ty: Option<String>,
+    /// Token range of the initializer
+    init: Option<Range<usize>>,
 }
 
-/// Rewrite the async function (this is very brittle, but does
-/// the job for our example)
-fn rewrite_async_fn(
-    s: &str,
-    id: &str,
-    args: Vec<(String, String)>,
-) -> Result<String, Box<dyn Error>> {
-    let w_kw_len = W_KW.len();
-
-    // Store the code in each "step" in this variable
-    let mut steps = vec![];
-    // Store the future call that we yield on
-    let mut futures = vec![];
-
-    let mut buffer = String::new();
-    // Skip the first line since that's the function definition
-    for line in s.lines().skip(1) {
-        // If the line contains the keyword it's an await-point
-        if line.contains(W_KW) {
-            // Store the steps since last await point as a "step"
-            steps.push(buffer.clone());
-            buffer.clear();
-            // Remove the keyword itself (i.e. `.await`)
-            let l = &line.trim_end()[..line.len() - 1 - w_kw_len - 1];
-            // we need both the future call and the variable name since
-            // we most likely reference this variable name in the next "step"
-            // This could be:
-            // `let txt = Http::get("...").await`
-            // or simply
-            // `join_all(futures).await`
-            match l.split_once("=") {
-                Some((var, fut)) => {
-                    // This could fail in so many ways...
-                    let varname = &var[var.find("let").unwrap() + 3..].trim();
-                    futures.push((varname.to_string(), fut.to_string()));
+/// Parses `let pat: Ty = init` in `range` (a trailing `;` or `else { .. }` is
+/// left alone)
+fn parse_let(src: &str, tokens: &[Token], range: Range<usize>) -> Result<Let, ParseError> {
+    let text = |r: Range<usize>| &src[tokens[r.start].span.start..tokens[r.end - 1].span.end];
+    let end = match tokens[range.end - 1].is_punct(src, ";") {
+        true => range.end - 1,
+        false => range.end,
+    };
+
+    let eq = find_top_level(src, tokens, range.start..end, "=");
+    let pat_end = find_top_level(src, tokens, range.start..eq.unwrap_or(end), ":")
+        .or(eq)
+        .unwrap_or(end);
+    let pattern = range.start + 1..pat_end;
+    if pattern.is_empty() {
+        return Err(ParseError::new(
+            tokens[range.start].span,
+            "Expected a pattern after `let`",
+        ));
+    }
+    let ty_end = eq.unwrap_or(end);
+    let ty = match pat_end < ty_end {
+        true if pat_end + 1 == ty_end => {
+            return Err(ParseError::new(
+                tokens[pat_end].span,
+                "Expected a type after `:`",
+            ));
+        }
+        true => Some(text(pat_end + 1..ty_end)),
+        false => None,
+    };
+
+    // `let x`, `let mut x`
+    let simple = match &tokens[pattern.clone()] {
+        [name] => Some((false, name)),
+        [m, name] if m.is_ident(src, "mut") => Some((true, name)),
+        _ => None,
+    }
+    .filter(|(_, name)| name.kind == TokenKind::Ident);
+
+    let decls = match simple {
+        Some((mutable, name)) => vec![Local {
+            name: name.text(src).to_string(),
+            mutable,
+            ty: ty.map(str::to_string),
+            simple: true,
+            span: name.span,
+        }],
+        None => find_bindings(src, tokens, pattern.clone())
+            .into_iter()
+            .map(|(name, span)| Local {
+                name,
+                mutable: false,
+                ty: None,
+                simple: false,
+                span,
+            })
+            .collect(),
+    };
+
+    let init = eq.map(|eq| {
+        let init_end = find_top_level_ident(src, tokens, eq + 1..end, "else").unwrap_or(end);
+        eq + 1..init_end
+    });
+
+    Ok(Let {
+        decls,
+        pattern: text(pattern).to_string(),
+        ty: ty.map(str::to_string),
+        init,
+    })
+}
+
+/// The names a destructuring pattern binds. This is a heuristic: lowercase
+/// identifiers that aren't paths, enum variants or field names.
+fn find_bindings(src: &str, tokens: &[Token], range: Range<usize>) -> Vec<(String, Span)> {
+    tokens[range.clone()]
+        .iter()
+        .enumerate()
+        .filter(|(i, t)| {
+            let next = tokens
+                .get(range.start + i + 1)
+                .filter(|_| i + 1 < range.len());
+            t.kind == TokenKind::Ident
+                && !["mut", "ref", "_"].contains(&t.text(src))
+                && t.text(src)
+                    .starts_with(|c: char| c.is_lowercase() || c == '_')
+                && !next.is_some_and(|n| {
+                    n.is_open(Delim::Paren)
+                        || n.is_open(Delim::Brace)
+                        || ["::", ":", "!"].iter().any(|p| n.is_punct(src, p))
+                })
+        })
+        .map(|(_, t)| (t.text(src).to_string(), t.span))
+        .collect()
+}
+
+/// Every identifier in `range` that could be a local: not a field, method,
+/// macro or path segment. Variables captured by format strings (`"{txt}"`)
+/// count as well.
+fn find_uses(src: &str, tokens: &[Token], range: Range<usize>) -> Vec<String> {
+    let mut uses = vec![];
+    for i in range.clone() {
+        let tok = &tokens[i];
+        let prev = (i > range.start).then(|| &tokens[i - 1]);
+        let next = tokens.get(i + 1).filter(|_| i + 1 < range.end);
+        match tok.kind {
+            TokenKind::Ident => {
+                let is_local = !prev.is_some_and(|p| p.is_punct(src, ".") || p.is_punct(src, "::"))
+                    && !next.is_some_and(|n| ["::", ":", "!"].iter().any(|p| n.is_punct(src, p)));
+                if is_local {
+                    uses.push(tok.text(src).to_string());
                 }
-                None => futures.push(("_".to_string(), l.trim().to_string())),
             }
+            TokenKind::Literal => uses.extend(format_captures(tok.text(src))),
+            _ => (),
+        }
+    }
+    uses
+}
 
-            // We store the variable name and the future as a tuple since they're connected
-        } else {
-            buffer.push_str(line);
-            buffer.push_str("\n");
+/// `"{txt} and {n:>5}"` -> `["txt", "n"]`
+fn format_captures(lit: &str) -> Vec<String> {
+    if !lit.ends_with('"') {
+        return vec![];
+    }
+    let mut res = vec![];
+    let mut rest = lit;
+    while let Some(open) = rest.find('{') {
+        rest = &rest[open + 1..];
+        if let Some(escaped) = rest.strip_prefix('{') {
+            rest = escaped;
+            continue;
+        }
+        let name: String = rest
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .collect();
+        let after = rest[name.len()..].chars().next();
+        if is_ident(&name) && matches!(after, Some('}' | ':')) {
+            res.push(name);
         }
     }
+    res
+}
 
-    steps.push(buffer);
+fn is_ident(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && s != "_"
+}
 
-    // Write our steps enum. We know it will start with "Start" and end with "Resolved"
-    // but we need to add one step for each await point
+/// Splits the tokens in `range` into statements. A statement ends with a `;`
+/// or, for block-like statements (`if`, `loop`, `fn` items, ...), with the
+/// brace group that ends it. The last statement may be a tail expression.
+fn split_statements(src: &str, tokens: &[Token], range: Range<usize>) -> Vec<Range<usize>> {
+    const BLOCK_LIKE: [&str; 15] = [
+        "if",
+        "match",
+        "loop",
+        "while",
+        "for",
+        "unsafe",
+        "fn",
+        "struct",
+        "enum",
+        "union",
+        "impl",
+        "trait",
+        "mod",
+        "macro_rules",
+        "async",
+    ];
 
-    let step_args = format_args_types_only(&args);
+    let mut stmts = vec![];
+    let mut start = range.start;
+    let mut i = range.start;
+    while i < range.end {
+        let tok = &tokens[i];
+        if tok.is_punct(src, ";") {
+            stmts.push(start..i + 1);
+            start = i + 1;
+            i += 1;
+            continue;
+        }
 
-    let mut steps_enum = format!(
-        "
-enum State{id} {{
-    Start{step_args},"
-    );
+        if let TokenKind::Open { delim, pair } = tok.kind {
+            let head = stmt_head(src, tokens, start..range.end);
+            let block_like = delim == Delim::Brace
+                && (head == i
+                    || BLOCK_LIKE.iter().any(|kw| tokens[head].is_ident(src, kw))
+                    // `println! { .. }`
+                    || tokens[i - 1].is_punct(src, "!"));
+            // A block-like statement ends with its block unless it continues
+            // with `else` (or is used as an expression like `match a {}.len()`)
+            let continues = pair + 1 < range.end
+                && ["else", ".", "?", ";"]
+                    .iter()
+                    .any(|t| tokens[pair + 1].text(src) == *t);
+            i = pair + 1;
+            if block_like && !continues {
+                stmts.push(start..i);
+                start = i;
+            }
+            continue;
+        }
 
-    // We only support this kind of future
-    for i in 1..steps.len() {
-        write!(
-            &mut steps_enum,
-            "
-    Wait{i}(Box<dyn Future<Output = String>>),"
-        )?;
+        i += 1;
     }
 
-    write!(
-        &mut steps_enum,
-        "
-    Resolved,
-}}"
-    )?;
+    if start < range.end {
+        stmts.push(start..range.end);
+    }
 
-    // So, our `State` enum is finished, we create a coroutine struct and a simple
-    // `new` implementation
-    let coro_args = format_args_name_and_types(&args);
-    let coro_args_names = format_args_names_only(&args);
-
-    let coroutine = format!(
-        "
-struct Coroutine{id} {{
-    state: State{id},
-}}
-
-impl Coroutine{id} {{
-    fn new({coro_args}) -> Self {{
-        Self {{ state: State{id}::Start{coro_args_names} }}
-    }}
-}}
-"
-    );
-
-    // This is our future implementation
-    // NB! Notice how we force all futures to return a string even if they
-    // don't (if not we this get's very complicated without type information available)
-    let mut imp = format!(
-        "
-impl Future for Coroutine{id} {{
-    type Output = String;
-
-    fn poll(&mut self) -> PollState<Self::Output> {{
-        loop {{"
-    );
-
-    for (i, step) in steps.iter().enumerate() {
-        // This is the index for the next step in the state machine
-        let next = i + 1;
-
-        // We need to special case the first call since that
-        // happens before we reach an `await` point
-
-        // This will receive the input args to the function
-        let impl_fut_first_args = format_args_names_only(&args);
-
-        if i == 0 {
-            // if there are no futures in this "coro" fn we only have Start -> Resolved
-            if futures.is_empty() {
-                write!(
-                    &mut imp,
-                    "
-        match self.state {{
-                State{id}::Start{impl_fut_first_args} => {{
-                    // ---- Code you actually wrote ----
-                {step}
-                    // ---------------------------------
-                    self.state = State{id}::Resolved;
-                    break PollState::Ready(String::new());
-                }}
-"
-                )?;
-                continue;
-            }
+    stmts
+}
 
-            let futname = &futures[i].1;
-            write!(
-                &mut imp,
-                "
-        match self.state {{
-                State{id}::Start{impl_fut_first_args} => {{
-                    // ---- Code you actually wrote ----
-                {step}
-                    // ---------------------------------
-                    let fut{next} = Box::new({futname});
-                    self.state = State{id}::Wait{next}(fut{next});
-                }}
-"
-            )?;
-
-        // These steps are await-points where we await a future
-        } else if i < steps.len() - 1 {
-            let varname = &futures[i - 1].0;
-            let fut = &futures[i].1;
-            write!(
-                &mut imp,
-                "
-                State{id}::Wait{i}(ref mut f{i}) => {{
-                    match f{i}.poll() {{
-                        PollState::Ready({varname}) => {{
-                            // ---- Code you actually wrote ----
-                        {step}
-                            // ---------------------------------
-                            let fut{next} = Box::new({fut});
-                            self.state = State{id}::Wait{next}(fut{next});
-                        }}
-                        PollState::NotReady => break PollState::NotReady,
-                    }}
-                }}
-"
-            )?;
-
-        // This is the part after the last await point. There is no need to yield any more
+/// Index of the first token of a statement that's not an attribute, a loop
+/// label or a visibility modifier
+fn stmt_head(src: &str, tokens: &[Token], range: Range<usize>) -> usize {
+    let mut head = range.start;
+    while head < range.end {
+        let tok = &tokens[head];
+        let next = tokens.get(head + 1);
+        if tok.is_punct(src, "#") {
+            // `#[attr]` or `#![attr]`
+            let bracket = if next.is_some_and(|t| t.is_punct(src, "!")) {
+                head + 2
+            } else {
+                head + 1
+            };
+            match tokens.get(bracket).and_then(|t| t.pair()) {
+                Some(pair) if tokens[bracket].is_open(Delim::Bracket) => head = pair + 1,
+                _ => break,
+            }
+        } else if tok.kind == TokenKind::Lifetime && next.is_some_and(|t| t.is_punct(src, ":")) {
+            head += 2;
+        } else if tok.is_ident(src, "pub") {
+            // `pub(crate)`
+            head = match next {
+                Some(t) if t.is_open(Delim::Paren) => t.pair().unwrap() + 1,
+                _ => head + 1,
+            };
         } else {
-            let varname = &futures[i - 1].0;
-            write!(
-                &mut imp,
-                "
-                State{id}::Wait{i}(ref mut f{i}) => {{
-                    match f{i}.poll() {{
-                        PollState::Ready({varname}) => {{
-                            // ---- Code you actually wrote ----
-                        {step}
-                            // ---------------------------------
-                            self.state = State{id}::Resolved;
-                            break PollState::Ready(String::new());
-                        }}
-                        PollState::NotReady => break PollState::NotReady,
-                    }}
-                }}
-"
-            )?;
-        }
-    }
-
-    // If we poll the future after it has resolved, we panic
-    writeln!(
-        &mut imp,
-        "
-                State{id}::Resolved => panic!(\"Polled a resolved future\")
-            }}
-        }}
-    }}
-}}"
-    )?;
+            break;
+        }
+    }
+    head.min(range.end.saturating_sub(1))
+}
 
-    // Format the different parts of the Coroutine implementation to a string
-    Ok(format!("{steps_enum}\n{coroutine}\n{imp}"))
+/// If `stmt` ends with `.wait` (followed by an optional `;`) this returns the
+/// index of the `.` token
+fn await_point(src: &str, tokens: &[Token], stmt: &Range<usize>) -> Option<usize> {
+    let mut last = stmt.end - 1;
+    if tokens[last].is_punct(src, ";") {
+        last = last.checked_sub(1)?;
+    }
+    if tokens[last].is_punct(src, "?") {
+        last = last.checked_sub(1)?;
+    }
+    (last > stmt.start && is_wait(src, tokens, last)).then_some(last - 1)
 }
 
-// this expects something like `txt: String, i: usize` or an empty string
-fn get_args(s: &str) -> Vec<(String, String)> {
+/// Whether the last statement of the body is the value the coroutine resolves
+/// to: a `return` or an expression without a `;`. A block-like statement such
+/// as `for x in y {}` only counts if the coroutine has a return type.
+fn is_tail(src: &str, tokens: &[Token], stmt: &Range<usize>, typed: bool) -> bool {
+    const ITEMS: [&str; 12] = [
+        "let",
+        "fn",
+        "struct",
+        "enum",
+        "union",
+        "impl",
+        "trait",
+        "mod",
+        "macro_rules",
+        "use",
+        "const",
+        "static",
+    ];
+
+    let head = &tokens[stmt_head(src, tokens, stmt.clone())];
+    let last = &tokens[stmt.end - 1];
+    if head.is_ident(src, "return") {
+        return true;
+    }
+    if last.is_punct(src, ";") || ITEMS.iter().any(|kw| head.is_ident(src, kw)) {
+        return false;
+    }
+    typed
+        || !matches!(
+            last.kind,
+            TokenKind::Close {
+                delim: Delim::Brace,
+                ..
+            }
+        )
+}
+
+/// Finds the `return` expressions in `range` that return from the coroutine
+/// (not the ones in closures, `async` blocks or nested functions). Each one
+/// is the token range from `return` to the end of the returned expression.
+fn find_returns(src: &str, tokens: &[Token], range: Range<usize>) -> Vec<Range<usize>> {
     let mut res = vec![];
-    if s.trim().is_empty() {
-        return res;
+    // The brace groups we're in
+    let mut groups: Vec<usize> = vec![];
+    for i in range.clone() {
+        match tokens[i].kind {
+            TokenKind::Open { .. } => groups.push(i),
+            TokenKind::Close { .. } => {
+                groups.pop();
+            }
+            TokenKind::Ident if tokens[i].is_ident(src, "return") => {
+                if groups
+                    .iter()
+                    .any(|&g| brace_owner(src, tokens, g) == Owner::Fn)
+                {
+                    continue;
+                }
+                // The expression ends with the statement, the match arm or
+                // the group we're in
+                let group_end = groups
+                    .last()
+                    .map_or(range.end, |&g| tokens[g].pair().unwrap());
+                let mut end = i + 1;
+                while end < group_end
+                    && !tokens[end].is_punct(src, ";")
+                    && !tokens[end].is_punct(src, ",")
+                {
+                    end = tokens[end].pair().map_or(end + 1, |pair| pair + 1);
+                }
+                // `return x;` is replaced by a block, which doesn't need the `;`
+                if end < group_end && tokens[end].is_punct(src, ";") {
+                    end += 1;
+                }
+                res.push(i..end);
+            }
+            _ => (),
+        }
     }
+    res
+}
 
-    let args = s.split(",");
-    for arg in args {
-        let (argname, ty) = arg.split_once(":").expect("Expected `:`");
-        res.push((argname.trim().to_string(), ty.trim().to_string()));
+/// The first `?` in `range` that returns from the coroutine, if there is one.
+/// The ones in closures, `async` blocks and nested functions don't, and
+/// neither does the one in a `?Sized` bound.
+fn find_try(src: &str, tokens: &[Token], range: Range<usize>) -> Option<usize> {
+    // The groups we're in
+    let mut groups: Vec<usize> = vec![];
+    for i in range {
+        match tokens[i].kind {
+            TokenKind::Open { .. } => groups.push(i),
+            TokenKind::Close { .. } => {
+                groups.pop();
+            }
+            TokenKind::Punct if tokens[i].is_punct(src, "?") => {
+                let after_expr = i > 0
+                    && matches!(
+                        tokens[i - 1].kind,
+                        TokenKind::Ident | TokenKind::Literal | TokenKind::Close { .. }
+                    );
+                let in_fn = groups
+                    .iter()
+                    .any(|&g| brace_owner(src, tokens, g) == Owner::Fn);
+                if after_expr && !in_fn {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
     }
+    None
+}
 
-    res
+/// What a brace group is the body of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Owner {
+    /// A function, closure or `async` block, where `return` means something else
+    Fn,
+    /// A `loop`, `while` or `for`, where `break` and `continue` mean something else
+    Loop,
+    Other,
 }
 
-/// Gets:
-/// `&[(txt, String), (i: usize)]`
-/// Outputs
-/// `(String, usize)`
-/// If there are no args it returns: ""
-fn format_args_types_only(args: &[(String, String)]) -> String {
-    if args.is_empty() {
-        String::new()
-    } else {
-        let mut args_fmt: String = args.iter().map(|(_n, ty)| format!("{ty},")).collect();
-        // remove last `,`
-        args_fmt.pop();
-        format!("({args_fmt})")
+/// What the brace group opened at `open` is the body of
+fn brace_owner(src: &str, tokens: &[Token], open: usize) -> Owner {
+    if !tokens[open].is_open(Delim::Brace) || open == 0 {
+        return Owner::Other;
+    }
+    let prev = &tokens[open - 1];
+    if ["|", "||"].iter().any(|p| prev.is_punct(src, p))
+        || prev.is_ident(src, "move")
+        || prev.is_ident(src, "async")
+    {
+        return Owner::Fn;
     }
+
+    // Look for the keyword the block belongs to: `fn name(..) -> T {`,
+    // `|x| -> T {` or `while cond {`
+    let mut ret_ty = false;
+    let mut i = open;
+    while i > 0 {
+        i -= 1;
+        let tok = &tokens[i];
+        match tok.kind {
+            TokenKind::Close {
+                delim: Delim::Brace,
+                ..
+            }
+            | TokenKind::Open { .. } => return Owner::Other,
+            TokenKind::Close { pair, .. } => {
+                i = pair;
+                continue;
+            }
+            _ => (),
+        }
+        if tok.is_ident(src, "fn") {
+            return Owner::Fn;
+        }
+        if ["loop", "while", "for"]
+            .iter()
+            .any(|kw| tok.is_ident(src, kw))
+        {
+            return Owner::Loop;
+        }
+        if tok.is_punct(src, "->") {
+            ret_ty = true;
+        } else if ret_ty && ["|", "||"].iter().any(|p| tok.is_punct(src, p)) {
+            return Owner::Fn;
+        } else if ["if", "match", "else"]
+            .iter()
+            .any(|kw| tok.is_ident(src, kw))
+            || [";", ",", "=>"].iter().any(|p| tok.is_punct(src, p))
+        {
+            return Owner::Other;
+        }
+    }
+    Owner::Other
 }
 
-/// Gets:
-/// `&[(txt, String), (i, usize)]`
-/// Outputs
-/// `txt: String, i: usize`
-/// If there are no args it returns: ""
-fn format_args_name_and_types(args: &[(String, String)]) -> String {
-    if args.is_empty() {
-        String::new()
-    } else {
-        let mut args_fmt: String = args.iter().map(|(n, ty)| format!("{n}: {ty},")).collect();
-        // remove last `,`
-        args_fmt.pop();
-        format!("{args_fmt}")
+/// Drops a trailing `;`
+fn trim_semi(src: &str, tokens: &[Token], range: Range<usize>) -> Range<usize> {
+    match range.end > range.start && tokens[range.end - 1].is_punct(src, ";") {
+        true => range.start..range.end - 1,
+        false => range,
     }
 }
 
-/// Gets:
-/// `&[(txt, String), (i: usize)]`
-/// Outputs
-/// `(txt, i)`
-/// If there are no args it returns: ""
-fn format_args_names_only(args: &[(String, String)]) -> String {
-    if args.is_empty() {
-        String::new()
+/// What a coroutine resolves to if it doesn't say
+fn default_output(ret: Option<&str>) -> String {
+    match ret {
+        None => format!("{DEFAULT_OUTPUT}::new()"),
+        Some(_) => "()".to_string(),
+    }
+}
+
+fn is_unit(ty: &str) -> bool {
+    ty.split_whitespace().collect::<String>() == "()"
+}
+
+/// `.wait` (but not a method called `wait()`)
+fn is_wait(src: &str, tokens: &[Token], idx: usize) -> bool {
+    idx > 0
+        && tokens[idx].is_ident(src, W_KW)
+        && tokens[idx - 1].is_punct(src, ".")
+        && !tokens.get(idx + 1).is_some_and(|t| t.is_open(Delim::Paren))
+}
+
+/// Finds the first `punct` in `range` that's not nested in a delimiter
+fn find_top_level(src: &str, tokens: &[Token], range: Range<usize>, punct: &str) -> Option<usize> {
+    let mut i = range.start;
+    while i < range.end {
+        if tokens[i].is_punct(src, punct) {
+            return Some(i);
+        }
+        i = match tokens[i].kind {
+            TokenKind::Open { pair, .. } => pair + 1,
+            _ => i + 1,
+        };
+    }
+    None
+}
+
+/// Like `find_top_level` but for an identifier such as `else`
+fn find_top_level_ident(
+    src: &str,
+    tokens: &[Token],
+    range: Range<usize>,
+    ident: &str,
+) -> Option<usize> {
+    let mut i = range.start;
+    while i < range.end {
+        if tokens[i].is_ident(src, ident) {
+            return Some(i);
+        }
+        i = match tokens[i].kind {
+            TokenKind::Open { pair, .. } => pair + 1,
+            _ => i + 1,
+        };
+    }
+    None
+}
+
+/// Source text in `range` with surrounding blank lines removed and the
+/// common indentation stripped. `edits` replace parts of the source (byte
+/// ranges inside `range`, in order) with generated code.
+fn snippet(src: &str, range: Range<usize>, edits: Vec<(Range<usize>, Fragment)>) -> Vec<Fragment> {
+    // If only whitespace precedes the snippet on its first line, include it so
+    // the first line keeps its indentation relative to the rest
+    let line_start = src[..range.start].rfind('\n').map_or(0, |i| i + 1);
+    let start = if src[line_start..range.start].trim().is_empty() {
+        line_start
     } else {
-        let mut args_fmt: String = args.iter().map(|(n, _ty)| format!("{n},")).collect();
-        // remove last `,`
-        args_fmt.pop();
-        format!("({args_fmt})")
-    }
-}
-
-/// Since both text editors and github can change the line endings of files
-/// we need to account for \n line endings on Windows as well as \r\n.
-fn detect_line_ending(s: &str) {
-    let mut chars = s.chars().into_iter().peekable();
-
-    let len = loop {
-        let current = chars.next();
-        match chars.peek() {
-            Some(&'\n') => {
-                if let Some('\r') = current {
-                    break 2;
-                } else {
-                    break 1;
+        range.start
+    };
+    // The edits are marked with `\0{index}\0` until the text is dedented
+    let mut text = String::new();
+    let mut pos = start;
+    for (i, (edit, _)) in edits.iter().enumerate() {
+        text.push_str(&src[pos..edit.start]);
+        write!(&mut text, "\0{i}\0").unwrap();
+        pos = edit.end;
+    }
+    text.push_str(&src[pos..range.end]);
+
+    let blank = text.lines().take_while(|l| l.trim().is_empty()).count();
+    let lines: Vec<&str> = text.lines().skip(blank).collect();
+    let lines = &lines[..lines
+        .iter()
+        .rposition(|l| !l.trim().is_empty())
+        .map_or(0, |i| i + 1)];
+
+    // A first line that starts after other code (`x.wait; // comment`) says
+    // nothing about the indentation of the rest
+    let mid_line = blank == 0 && start != line_start;
+    let min_indent = lines
+        .iter()
+        .skip(mid_line as usize)
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    let text = lines
+        .iter()
+        .enumerate()
+        .map(|(i, l)| match mid_line && i == 0 {
+            true => l.trim(),
+            false => l.get(min_indent..).unwrap_or("").trim_end(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    // Each line of text follows the one before it in the source, so we
+    // only need to say where the text starts again after an edit
+    let mut res = vec![];
+    if !text.is_empty() {
+        res.push(Fragment::Line(line_of(src, start) + blank));
+    }
+    let mut edits: Vec<_> = edits.into_iter().map(Some).collect();
+    for (i, part) in text.split('\0').enumerate() {
+        match i % 2 {
+            0 if part.is_empty() => (),
+            0 => push_text(&mut res, part),
+            _ => match edits[part.parse::<usize>().unwrap()].take().unwrap() {
+                (_, Fragment::Text(text)) => push_text(&mut res, &text),
+                (edit, fragment) => {
+                    res.push(fragment);
+                    res.push(Fragment::Line(line_of(src, edit.end)));
                 }
+            },
+        }
+    }
+    res
+}
+
+/// The line of the byte at `pos`
+fn line_of(src: &str, pos: usize) -> usize {
+    src[..pos].matches('\n').count() + 1
+}
+
+/// Source text in `range` with `edits` (byte ranges, in order) applied. Edits
+/// that aren't inside `range` are ignored.
+fn splice(src: &str, range: Range<usize>, edits: &[(Range<usize>, Fragment)]) -> Vec<Fragment> {
+    let mut res = vec![];
+    let mut pos = range.start;
+    for (edit, fragment) in edits {
+        if edit.start >= pos && edit.end <= range.end {
+            push_text(&mut res, &src[pos..edit.start]);
+            res.push(fragment.clone());
+            pos = edit.end;
+        }
+    }
+    push_text(&mut res, &src[pos..range.end]);
+    res.retain(|f| !matches!(f, Fragment::Text(t) if t.is_empty()));
+    res
+}
+
+/// Turns the blocks of a coroutine into the steps of its state machine
+struct Machine<'a> {
+    src: &'a str,
+    tokens: &'a [Token],
+    cfg: &'a Cfg,
+    stack: &'a Stack,
+    ret: Option<&'a str>,
+    /// The blocks that start a state, in the order we write them
+    order: Vec<usize>,
+    /// The state each block starts (`Wait1`), if it starts one
+    names: Vec<Option<String>>,
+}
+
+impl<'a> Machine<'a> {
+    fn new(
+        src: &'a str,
+        tokens: &'a [Token],
+        cfg: &'a Cfg,
+        stack: &'a Stack,
+        states: &[bool],
+        ret: Option<&'a str>,
+    ) -> Self {
+        // States are numbered in the order they appear in the source. The
+        // ones that wait on a future are called `Wait{n}`, the ones we jump
+        // to (a loop, the code after an `if`) `Block{n}`.
+        let mut order: Vec<usize> = (1..cfg.blocks.len()).filter(|&b| states[b]).collect();
+        order.sort_by_key(|&b| (cfg.blocks[b].code.start, b));
+        order.insert(0, 0);
+
+        let mut names = vec![None; cfg.blocks.len()];
+        names[0] = Some("Start".to_string());
+        for (n, &b) in order.iter().enumerate().skip(1) {
+            let kind = match cfg.point(b) {
+                Some(_) => "Wait",
+                None => "Block",
+            };
+            names[b] = Some(format!("{kind}{n}"));
+        }
+
+        Self {
+            src,
+            tokens,
+            cfg,
+            stack,
+            ret,
+            order,
+            names,
+        }
+    }
+
+    fn steps(&self) -> Vec<Step> {
+        self.order.iter().map(|&b| self.step(b)).collect()
+    }
+
+    fn name(&self, block: usize) -> String {
+        self.names[block].clone().unwrap()
+    }
+
+    /// The state `block` starts
+    fn step(&self, block: usize) -> Step {
+        let wait = self.cfg.point(block).map(|point| Wait {
+            fut: point.fut.clone(),
+            pattern: point.pattern.clone(),
+            output: point.output.clone(),
+            propagate: point.propagate,
+            types: point
+                .fut
+                .exprs()
+                .into_iter()
+                .map(|fut| self.declared_type(fut))
+                .collect(),
+            line: point.line,
+        });
+        // Pinned arguments and values of a `wait` are moved to the stack first
+        let pin = self.cfg.blocks[block]
+            .decls
+            .iter()
+            .filter(|local| self.is_pinned(&local.name))
+            .map(|local| local.name.clone())
+            .collect();
+        Step {
+            name: self.name(block),
+            wait,
+            pin,
+            restore: self.stack.restore[block].clone(),
+            body: self.block(block, true),
+        }
+    }
+
+    /// The type you gave `expr` if it's a local or an argument. We don't
+    /// know which one it is if there are several with different types.
+    fn declared_type(&self, expr: &str) -> Option<String> {
+        let mut found = self
+            .cfg
+            .blocks
+            .iter()
+            .flat_map(|block| {
+                let decls = block.stmts.iter().flat_map(|stmt| &stmt.decls);
+                block.decls.iter().chain(decls)
+            })
+            .filter(|local| local.name == expr.trim())
+            .map(|local| local.ty.as_deref());
+        let ty = found.next()??;
+        found.all(|other| other == Some(ty)).then(|| ty.to_string())
+    }
+
+    /// The code of `block` and the blocks it continues to in the same state.
+    /// `root` is set for the block the state starts with.
+    fn block(&self, block: usize, root: bool) -> Vec<Node> {
+        // Blocks that just continue with the next one are written as one
+        let mut block = block;
+        let mut chunks = vec![self.code(block)];
+        while let Term::Goto(next) = self.cfg.blocks[block].term
+            && self.names[next].is_none()
+        {
+            block = next;
+            chunks.push(self.code(block));
+        }
+        chunks.retain(|c| !c.is_empty());
+        let mut code = vec![];
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            if i > 0 {
+                push_text(&mut code, "\n");
             }
-            Some(_) => (),
-            None => {
-                // defaults if we for some reason fall through
-                if cfg!(windows) {
-                    break 2;
-                } else {
-                    break 1;
+            for fragment in chunk {
+                match fragment {
+                    Fragment::Text(text) => push_text(&mut code, &text),
+                    fragment => code.push(fragment),
                 }
             }
         }
+
+        let mut res = vec![];
+        if root || !code.is_empty() {
+            res.push(Node::Code(code));
+        }
+
+        match &self.cfg.blocks[block].term {
+            Term::Goto(next) => res.extend(self.goto(block, *next)),
+            Term::If { cond, then, els } => res.push(Node::If {
+                cond: self.text(cond.clone()),
+                line: self.tokens[cond.start].span.line,
+                then: self.goto(block, *then),
+                els: self.goto(block, *els),
+            }),
+            Term::Match { scrutinee, arms } => res.push(Node::Match {
+                scrutinee: self.text(scrutinee.clone()),
+                line: self.tokens[scrutinee.start].span.line,
+                arms: arms
+                    .iter()
+                    .map(|(pattern, arm)| {
+                        (
+                            self.text(pattern.clone()),
+                            self.tokens[pattern.start].span.line,
+                            self.goto(block, *arm),
+                        )
+                    })
+                    .collect(),
+            }),
+            Term::Await { point, resume } => res.push(Node::Await {
+                fut: self.cfg.awaits[*point].fut.clone(),
+                line: self.cfg.awaits[*point].line,
+                save: self.stack.save(block, *resume),
+                to: self.name(*resume),
+            }),
+            Term::Return(value) => {
+                res.push(Node::Return(value.clone().unwrap_or_else(|| "()".into())));
+            }
+            Term::Diverge => (),
+        }
+        res
+    }
+
+    /// Continues from `from` with `to`, either by switching to the state it
+    /// starts or by running it right away
+    fn goto(&self, from: usize, to: usize) -> Vec<Node> {
+        match &self.names[to] {
+            Some(name) => vec![Node::Goto {
+                save: self.stack.save(from, to),
+                to: name.clone(),
+            }],
+            None => self.block(to, false),
+        }
+    }
+
+    /// The code you wrote in `block`, with `return`, `break` and `continue`
+    /// cut out so they can be replaced by code that switches states
+    fn code(&self, from: usize) -> Vec<Fragment> {
+        let (src, tokens) = (self.src, self.tokens);
+        let bytes = |r: &Range<usize>| tokens[r.start].span.start..tokens[r.end - 1].span.end;
+        let block = &self.cfg.blocks[from];
+
+        let mut edits = vec![];
+        for stmt in &block.stmts {
+            let mut inner: Vec<(Range<usize>, Fragment)> =
+                find_returns(src, tokens, stmt.range.clone())
+                    .into_iter()
+                    .map(|r| (bytes(&r), Fragment::Return(self.return_value(r))))
+                    .collect();
+            inner.extend(
+                block
+                    .jumps
+                    .iter()
+                    .filter(|(r, _)| stmt.range.start <= r.start && r.end <= stmt.range.end)
+                    .map(|(r, to)| {
+                        let jump = Fragment::Jump {
+                            save: self.stack.save(from, *to),
+                            to: self.name(*to),
+                        };
+                        (bytes(r), jump)
+                    }),
+            );
+            inner.sort_by_key(|(r, _)| r.start);
+
+            // Pinned locals are initialized directly in the stack
+            match (&stmt.decls[..], &stmt.init) {
+                ([local], Some(init)) if self.is_pinned(&local.name) => {
+                    let pin = Fragment::Pin {
+                        name: local.name.clone(),
+                        init: splice(src, bytes(init), &inner),
+                    };
+                    edits.push((bytes(&stmt.range), pin));
+                }
+                _ => edits.extend(inner),
+            }
+        }
+
+        // The expression of a `match` arm (`None => foo()`) is a statement now
+        if let Some(last) = block.stmts.last() {
+            let tok = &tokens[last.range.end - 1];
+            let is_expr = !tok.is_punct(src, ";")
+                && !matches!(
+                    tok.kind,
+                    TokenKind::Close {
+                        delim: Delim::Brace,
+                        ..
+                    }
+                );
+            if is_expr {
+                let end = tok.span.end;
+                edits.push((end..end, Fragment::Text(";".to_string())));
+            }
+        }
+
+        snippet(src, block.code.clone(), edits)
+    }
+
+    /// The value of `return value` somewhere in the code you wrote
+    fn return_value(&self, range: Range<usize>) -> String {
+        let (src, tokens) = (self.src, self.tokens);
+        let expr = trim_semi(src, tokens, range.start + 1..range.end);
+        match expr.is_empty() {
+            true => default_output(self.ret),
+            false => self.text(expr),
+        }
+    }
+
+    fn is_pinned(&self, name: &str) -> bool {
+        self.stack
+            .get(name)
+            .is_some_and(|v| v.storage == Storage::Pinned)
+    }
+
+    /// Source text of a token range
+    fn text(&self, range: Range<usize>) -> String {
+        let tokens = self.tokens;
+        self.src[tokens[range.start].span.start..tokens[range.end - 1].span.end].to_string()
+    }
+}
+
+/// Appends `text` to the code, merged with the text before it
+fn push_text(code: &mut Vec<Fragment>, text: &str) {
+    match code.last_mut() {
+        Some(Fragment::Text(last)) => last.push_str(text),
+        _ => code.push(Fragment::Text(text.to_string())),
+    }
+}
+
+// this expects the tokens between the parentheses, something like
+// `txt: String, i: usize` or nothing at all
+/// Splits a comma separated list such as the arguments of a function into
+/// the token range of each element. `HashMap<K, V>` has a `,` we shouldn't
+/// split on.
+fn split_list(src: &str, tokens: &[Token], range: Range<usize>) -> Vec<Range<usize>> {
+    let mut res = vec![];
+
+    let mut start = range.start;
+    let mut angle_depth = 0;
+    let mut i = range.start;
+    while i < range.end {
+        let tok = &tokens[i];
+        if let TokenKind::Open { pair, .. } = tok.kind {
+            i = pair + 1;
+            continue;
+        }
+        match tok.text(src) {
+            "<" if tok.kind == TokenKind::Punct => angle_depth += 1,
+            ">" if tok.kind == TokenKind::Punct => angle_depth -= 1,
+            "," if angle_depth == 0 => {
+                if start < i {
+                    res.push(start..i);
+                }
+                start = i + 1;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+
+    if start < range.end {
+        res.push(start..range.end);
+    }
+    res
+}
+
+fn get_arg(src: &str, tokens: &[Token], arg: Range<usize>) -> Result<(String, String), ParseError> {
+    let text = |r: Range<usize>| &src[tokens[r.start].span.start..tokens[r.end - 1].span.end];
+
+    let Some(colon) = find_top_level(src, tokens, arg.clone(), ":") else {
+        let span = tokens[arg.start].span.to(tokens[arg.end - 1].span);
+        if tokens[arg.clone()].iter().any(|t| t.is_ident(src, "self")) {
+            return Err(ParseError::new(
+                span,
+                "`self` can only be the first argument",
+            ));
+        }
+        return Err(ParseError::new(span, "Expected `:`"));
     };
-    L_TRM_LN.set(len).unwrap();
+
+    if colon == arg.start || colon + 1 == arg.end {
+        return Err(ParseError::new(
+            tokens[colon].span,
+            "Expected an argument as `name: Type`",
+        ));
+    }
+
+    Ok((
+        text(arg.start..colon).to_string(),
+        text(colon + 1..arg.end).to_string(),
+    ))
 }
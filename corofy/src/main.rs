@@ -1,49 +1,411 @@
 use std::{
-    env,
-    error::Error,
-    fs,
+    env, fs,
+    io::{self, Write},
     path::{Path, PathBuf},
+    process::{Command, ExitCode, Stdio},
 };
 
-use corofy::rewrite;
+use corofy::ir::Program;
+use corofy::{
+    CorofyError, Diagram, Futures, Target, explain, generate_with_map, has_coroutines, parse,
+};
+
+const USAGE: &str = "\
+Usage: corofy [options] <src> [dest]
+       corofy [options] <dir|src>...
+
+Without a destination, `main.rs` is rewritten to `main_corofied.rs` next to
+it. A crate's `original_main.rs` is rewritten to `src/main_corofied.rs`.
+
+With a directory, every file in it (and the directories below it) with a
+`coroutine fn` is rewritten to its `_corofied` file. With `--check` only the
+ones that have a `_corofied` file already are checked, and a `_corofied`
+file without a source with a `coroutine fn` fails the check.
+
+Options:
+  --target <no-waker|waker|std>  The `Future` trait to implement. Only `std`
+                                 pins the coroutine, so only `std` takes a
+                                 reference to a local that's kept across a
+                                 `wait`
+  --futures <boxed|concrete>     How the states store the futures they wait
+                                 on. `concrete` doesn't allocate, but a
+                                 future whose type you didn't write down
+                                 needs `#![feature(type_alias_impl_trait)]`
+  --check                        Write nothing and fail if a `_corofied` file
+                                 isn't what corofy writes now
+  --exclude <path>               Skip this file or directory when going
+                                 through a directory, can be given repeatedly
+  --rustfmt                      Format what corofy writes (or checks) with
+                                 `rustfmt --edition 2024`, for a file you keep
+                                 formatted
+  --map                          Also write a source map next to each file we
+                                 write (`dest.map`), which `corofy_map` uses
+                                 to point compiler errors and backtraces in
+                                 the generated code back at the code you wrote
+  --log-cancel                   A coroutine that's dropped before it resolves
+                                 prints the line of the `wait` it was
+                                 cancelled at
+  --in-place                     Rewrite each coroutine where it is instead of
+                                 at the end of the file. The state machine of
+                                 a method goes right after its `impl` block.
+  --explain <dot|mermaid>        Write nothing, print the states of each
+                                 coroutine as a Graphviz or Mermaid diagram
+                                 instead: the code each state runs and the
+                                 futures it waits on to get to the next";
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
+fn run() -> Result<ExitCode, CorofyError> {
+    // The options can go anywhere, the rest are paths
+    let mut args = vec![];
+    let mut target = None;
+    let mut futures = None;
+    let mut check = false;
+    let mut map = false;
+    let mut log_cancel = false;
+    let mut in_place = false;
+    let mut rustfmt = false;
+    let mut diagram = None;
+    let mut exclude = vec![];
+    let mut env_args = env::args().skip(1);
+    while let Some(arg) = env_args.next() {
+        if let Some(name) = arg.strip_prefix("--target=") {
+            target = Some(name.to_string());
+        } else if arg == "--target" {
+            target = Some(env_args.next().unwrap_or_default());
+        } else if let Some(name) = arg.strip_prefix("--futures=") {
+            futures = Some(name.to_string());
+        } else if arg == "--futures" {
+            futures = Some(env_args.next().unwrap_or_default());
+        } else if let Some(name) = arg.strip_prefix("--explain=") {
+            diagram = Some(name.to_string());
+        } else if arg == "--explain" {
+            diagram = Some(env_args.next().unwrap_or_default());
+        } else if let Some(path) = arg.strip_prefix("--exclude=") {
+            exclude.push(PathBuf::from(path));
+        } else if arg == "--exclude" {
+            exclude.push(PathBuf::from(env_args.next().unwrap_or_default()));
+        } else if arg == "--check" {
+            check = true;
+        } else if arg == "--map" {
+            map = true;
+        } else if arg == "--log-cancel" {
+            log_cancel = true;
+        } else if arg == "--in-place" {
+            in_place = true;
+        } else if arg == "--rustfmt" {
+            rustfmt = true;
+        } else {
+            args.push(PathBuf::from(arg));
+        }
+    }
 
-    let src = match args.get(1) {
-        Some(path) => Path::new(path),
-        None => {
-            println!("Missing source file. Please provide a path to a source file and try again.");
-            return Ok(());
+    let target: Target = match target.as_deref().map(str::parse).transpose() {
+        Ok(target) => target.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("{e}");
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+    let futures: Futures = match futures.as_deref().map(str::parse).transpose() {
+        Ok(futures) => futures.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("{e}");
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+    let diagram: Option<Diagram> = match diagram.as_deref().map(str::parse).transpose() {
+        Ok(diagram) => diagram,
+        Err(e) => {
+            eprintln!("{e}");
+            return Ok(ExitCode::FAILURE);
         }
     };
 
-    let dest = match args.get(2) {
-        Some(path) => PathBuf::from(path),
-        None => {
-            let src_n = src
-                .file_stem()
-                .map(|x| x.to_string_lossy())
-                .unwrap_or_default();
-            let src_ext = src
-                .extension()
-                .map(|x| x.to_string_lossy())
-                .unwrap_or_default();
-            let clone = format!("{src_n}_corofied.{src_ext}");
+    // The lines move when rustfmt formats the code
+    if map && rustfmt {
+        eprintln!("`--map` and `--rustfmt` can't be used together");
+        return Ok(ExitCode::FAILURE);
+    }
+
+    if args.is_empty() {
+        eprintln!("Missing source file. Please provide a path to a source file and try again.");
+        eprintln!("\n{USAGE}");
+        return Ok(ExitCode::FAILURE);
+    }
+
+    // `(src, dest)` of every file we rewrite
+    let mut files = vec![];
+    let mut ok = true;
+    if args.iter().any(|path| path.is_dir()) {
+        // Compared with what we find in the directories, however they're
+        // written
+        let exclude: Vec<PathBuf> = exclude
+            .iter()
+            .map(|path| path.canonicalize().unwrap_or_else(|_| path.clone()))
+            .collect();
+        for path in &args {
+            if !path.is_dir() {
+                files.push((path.clone(), corofied(path)));
+                continue;
+            }
+            let mut found = Found::default();
+            find_coroutines(path, &exclude, &mut found)?;
+            if !check {
+                files.extend(found.sources);
+                continue;
+            }
+            // A source we don't keep a `_corofied` file for is none of our
+            // business, a `_corofied` file without a source is stale
+            files.extend(found.sources.into_iter().filter(|(_, dest)| dest.exists()));
+            for orphan in found.orphans {
+                eprintln!(
+                    "{} has no source with a `coroutine fn`, delete it",
+                    orphan.display()
+                );
+                ok = false;
+            }
+        }
+    } else {
+        if args.len() > 2 {
+            eprintln!("Expected a source file and a destination, or directories.");
+            eprintln!("\n{USAGE}");
+            return Ok(ExitCode::FAILURE);
+        }
+        let dest = args.get(1).cloned().unwrap_or_else(|| corofied(&args[0]));
+        files.push((args[0].clone(), dest));
+    }
+
+    for (src, dest) in &files {
+        // Nothing is written unless the whole file could be rewritten
+        let code = fs::read_to_string(src).map_err(io_error(src))?;
+        let parsed = parse(&code).and_then(|program| {
+            // A diagram is the same for every target
+            if diagram.is_none() {
+                corofy::check(&program, target)?;
+            }
+            Ok(program)
+        });
+        let (rewritten, mut source_map) = match parsed {
+            Ok(program) if let Some(diagram) = diagram => {
+                print!("{}", explain(&program, diagram));
+                continue;
+            }
+            Ok(program) => generate_with_map(
+                &Program {
+                    log_cancel,
+                    futures,
+                    in_place,
+                    ..program
+                },
+                target,
+            ),
+            Err(e) => {
+                eprintln!("{}\n", CorofyError::parse(&code, e).in_file(src));
+                ok = false;
+                continue;
+            }
+        };
+        let rewritten = match rustfmt {
+            true => format_code(&rewritten).map_err(io_error(dest))?,
+            false => rewritten,
+        };
 
-            match src.parent() {
-                Some(path) => path.join(&clone).clone(),
-                None => PathBuf::from("./").join(&clone),
+        if check {
+            let problem = match fs::read_to_string(dest) {
+                Ok(current) if current == rewritten => continue,
+                Ok(_) => "is out of date with",
+                Err(_) => "is missing for",
+            };
+            eprintln!(
+                "{} {problem} {}, run corofy again",
+                dest.display(),
+                src.display()
+            );
+            ok = false;
+        } else {
+            fs::write(dest, rewritten).map_err(io_error(dest))?;
+            if map {
+                source_map.source = Some(map_source(src, dest));
+                let map_path = map_path(dest);
+                fs::write(&map_path, source_map.to_string()).map_err(io_error(&map_path))?;
             }
         }
+    }
+
+    Ok(match ok {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+/// Attaches `path` to an I/O error with it
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> CorofyError + '_ {
+    move |e| CorofyError::from(e).in_file(path)
+}
+
+/// `code` the way `rustfmt` formats it
+fn format_code(code: &str) -> io::Result<String> {
+    let mut child = Command::new("rustfmt")
+        .args(["--edition", "2024", "--emit", "stdout"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(code.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!(
+            "rustfmt failed: {}",
+            stderr.trim()
+        )));
+    }
+    String::from_utf8(output.stdout).map_err(io::Error::other)
+}
+
+/// `src/main.rs` -> `src/main_corofied.rs`, and a crate's
+/// `original_main.rs` -> `src/main_corofied.rs`
+fn corofied(src: &Path) -> PathBuf {
+    let src_n = src
+        .file_stem()
+        .map(|x| x.to_string_lossy())
+        .unwrap_or_default();
+    let src_ext = src
+        .extension()
+        .map(|x| x.to_string_lossy())
+        .unwrap_or_default();
+    let dir = match src.parent() {
+        Some(path) => path.to_path_buf(),
+        None => PathBuf::from("./"),
+    };
+
+    match src_n.strip_prefix("original_") {
+        Some(name) if dir.join("src").is_dir() => {
+            dir.join("src").join(format!("{name}_corofied.{src_ext}"))
+        }
+        Some(name) => dir.join(format!("{name}_corofied.{src_ext}")),
+        None => dir.join(format!("{src_n}_corofied.{src_ext}")),
+    }
+}
+
+/// The source `dest` was written from, by the names `corofied` gives: `main.rs`
+/// or `original_main.rs` next to it, or `original_main.rs` in the crate if
+/// it's in `src`. `None` if none of them has a `coroutine fn`.
+fn source_of(dest: &Path) -> Result<Option<PathBuf>, CorofyError> {
+    let name = dest
+        .file_name()
+        .map(|x| x.to_string_lossy())
+        .unwrap_or_default();
+    let Some(stem) = name.strip_suffix("_corofied.rs") else {
+        return Ok(None);
+    };
+    let dir = dest.parent().unwrap_or(Path::new(""));
+    let mut candidates = vec![
+        dir.join(format!("{stem}.rs")),
+        dir.join(format!("original_{stem}.rs")),
+    ];
+    if let Some(crate_dir) = dir.parent() {
+        candidates.push(crate_dir.join(format!("original_{stem}.rs")));
+    }
+    for src in candidates {
+        if src.is_file()
+            && corofied(&src) == dest
+            && has_coroutines(&fs::read_to_string(&src).map_err(io_error(&src))?)
+        {
+            return Ok(Some(src));
+        }
+    }
+    Ok(None)
+}
+
+/// `src/main_corofied.rs` -> `src/main_corofied.rs.map`
+fn map_path(dest: &Path) -> PathBuf {
+    let mut path = dest.as_os_str().to_owned();
+    path.push(".map");
+    PathBuf::from(path)
+}
+
+/// How the map next to `dest` refers to `src`: by its name if they're in the
+/// same directory, so the two can be moved together, by its full path if not
+fn map_source(src: &Path, dest: &Path) -> String {
+    let same_dir = match (src.canonicalize(), dest.canonicalize()) {
+        (Ok(src), Ok(dest)) => src.parent() == dest.parent(),
+        _ => false,
     };
+    match (same_dir, src.file_name()) {
+        (true, Some(name)) => name.to_string_lossy().into_owned(),
+        _ => src
+            .canonicalize()
+            .unwrap_or_else(|_| src.to_path_buf())
+            .display()
+            .to_string(),
+    }
+}
+
+/// What `find_coroutines` finds in a directory
+#[derive(Default)]
+struct Found {
+    /// `(src, dest)` of every Rust file with a `coroutine fn`
+    sources: Vec<(PathBuf, PathBuf)>,
+    /// `_corofied.rs` files whose source is gone or has no `coroutine fn`
+    orphans: Vec<PathBuf>,
+}
 
-    let src = fs::read_to_string(src)?;
-    // Will truncate if exists
-    let dest = fs::File::create(dest)?;
+impl Found {
+    /// Adds a source unless we found it from its `_corofied` file already
+    fn add(&mut self, files: (PathBuf, PathBuf)) {
+        if !self.sources.contains(&files) {
+            self.sources.push(files);
+        }
+    }
+}
+
+/// Collects the Rust files in `dir` (and the directories below it) with a
+/// `coroutine fn`, in a stable order. `target` and hidden directories are
+/// skipped, and so is anything in `exclude`. The files we wrote ourselves
+/// aren't sources, but their sources are found from them (even outside
+/// `dir`), and they're orphans if there's none.
+fn find_coroutines(dir: &Path, exclude: &[PathBuf], found: &mut Found) -> Result<(), CorofyError> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .and_then(|entries| entries.map(|entry| entry.map(|e| e.path())).collect())
+        .map_err(io_error(dir))?;
+    entries.sort();
 
-    if let Err(e) = rewrite(src, dest) {
-        println!("{e}");
+    for path in entries {
+        let name = path
+            .file_name()
+            .map(|x| x.to_string_lossy())
+            .unwrap_or_default();
+        if path
+            .canonicalize()
+            .is_ok_and(|path| exclude.contains(&path))
+        {
+            continue;
+        }
+        if path.is_dir() {
+            if name != "target" && !name.starts_with('.') {
+                find_coroutines(&path, exclude, found)?;
+            }
+        } else if name.ends_with("_corofied.rs") {
+            match source_of(&path)? {
+                Some(src) => found.add((src, path)),
+                None => found.orphans.push(path),
+            }
+        } else if name.ends_with(".rs")
+            && has_coroutines(&fs::read_to_string(&path).map_err(io_error(&path))?)
+        {
+            let dest = corofied(&path);
+            found.add((path, dest));
+        }
     }
     Ok(())
 }
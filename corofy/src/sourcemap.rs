@@ -0,0 +1,82 @@
+//! Maps the lines of a corofied file back to the file it was generated from.
+//!
+//! `corofy --map` writes it next to the generated file, `main_corofied.rs`
+//! gets a `main_corofied.rs.map`:
+//!
+//! ```text
+//! # corofy source map: <generated line> <original line>
+//! source main.rs
+//! 1 1
+//! 2 2
+//! 40 9
+//! ```
+//!
+//! The code you wrote maps to where you wrote it, the code we generate
+//! around it to the `wait`, the condition or the match arm it belongs to, or
+//! else to the `coroutine fn` line.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    /// The file the generated code comes from
+    pub source: Option<String>,
+    /// The original line of each generated line, `None` for the ones that
+    /// don't come from anywhere (blank lines, mostly)
+    pub lines: Vec<Option<usize>>,
+}
+
+impl SourceMap {
+    /// The original line of the (1-based) generated `line`
+    pub fn original_line(&self, line: usize) -> Option<usize> {
+        self.lines.get(line.checked_sub(1)?).copied().flatten()
+    }
+}
+
+impl fmt::Display for SourceMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# corofy source map: <generated line> <original line>")?;
+        if let Some(source) = &self.source {
+            writeln!(f, "source {source}")?;
+        }
+        for (i, line) in self.lines.iter().enumerate() {
+            if let Some(line) = line {
+                writeln!(f, "{} {line}", i + 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SourceMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = SourceMap::default();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(source) = line.strip_prefix("source ") {
+                map.source = Some(source.to_string());
+                continue;
+            }
+            let parsed = line
+                .split_once(' ')
+                .and_then(|(g, o)| Some((g.parse::<usize>().ok()?, o.parse().ok()?)));
+            let Some((generated, original)) = parsed.filter(|(g, _)| *g > 0) else {
+                return Err(format!(
+                    "Line {}: expected `<generated line> <original line>`",
+                    n + 1
+                ));
+            };
+            if map.lines.len() < generated {
+                map.lines.resize(generated, None);
+            }
+            map.lines[generated - 1] = Some(original);
+        }
+        Ok(map)
+    }
+}
@@ -0,0 +1,190 @@
+//! The `Future` trait the generated code implements. The examples in this
+//! repository use three different ones.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Target {
+    /// `fn poll(&mut self) -> PollState<Self::Output>` as in the `futures`
+    /// and `join` crates
+    #[default]
+    NoWaker,
+    /// `fn poll(&mut self, waker: &Waker) -> PollState<Self::Output>` as in
+    /// the `runtime_with_waker` and `pinning` crates
+    Waker,
+    /// `std::future::Future`, polled with `Pin<&mut Self>` and a `Context`.
+    /// A coroutine that borrows from its own stack is `!Unpin`.
+    Std,
+}
+
+impl Target {
+    pub const ALL: [Target; 3] = [Target::NoWaker, Target::Waker, Target::Std];
+
+    /// The trait we implement and the futures we wait on implement
+    pub fn future_trait(self) -> &'static str {
+        match self {
+            Target::NoWaker | Target::Waker => "Future",
+            Target::Std => "std::future::Future",
+        }
+    }
+
+    /// The signature of `poll`
+    pub fn poll_fn(self) -> &'static str {
+        match self {
+            Target::NoWaker => "fn poll(&mut self) -> PollState<Self::Output>",
+            Target::Waker => "fn poll(&mut self, waker: &Waker) -> PollState<Self::Output>",
+            Target::Std => {
+                "fn poll(\n        mut self: std::pin::Pin<&mut Self>,\n        \
+                 cx: &mut std::task::Context<'_>,\n    ) -> std::task::Poll<Self::Output>"
+            }
+        }
+    }
+
+    /// The signature of `resume`, which runs the states of a coroutine that
+    /// uses `?` and fails with `error`
+    pub fn resume_fn(self, output: &str, error: &str) -> String {
+        match self {
+            Target::NoWaker => {
+                format!("fn resume(&mut self) -> Result<PollState<{output}>, {error}>")
+            }
+            Target::Waker => format!(
+                "fn resume(&mut self, waker: &Waker) -> Result<PollState<{output}>, {error}>"
+            ),
+            Target::Std => format!(
+                "fn resume(\n        &mut self,\n        cx: &mut std::task::Context<'_>,\n    \
+                 ) -> Result<std::task::Poll<{output}>, {error}>"
+            ),
+        }
+    }
+
+    /// Calls `resume` from `poll`
+    pub fn resume_call(self) -> &'static str {
+        match self {
+            Target::NoWaker => "self.resume()",
+            Target::Waker => "self.resume(waker)",
+            Target::Std => "self.resume(cx)",
+        }
+    }
+
+    /// Polls the future in `fut` (a field in the `State` enum)
+    pub fn poll_call(self, fut: &str) -> String {
+        match self {
+            Target::NoWaker => format!("{fut}.poll()"),
+            Target::Waker => format!("{fut}.poll(waker)"),
+            Target::Std => format!("{fut}.as_mut().poll(cx)"),
+        }
+    }
+
+    /// Polls the future in `fut` where it is, when it's stored as its own
+    /// type. The `std` coroutine is pinned then, so the future is too.
+    pub fn poll_in_place(self, fut: &str) -> String {
+        match self {
+            Target::NoWaker | Target::Waker => self.poll_call(fut),
+            Target::Std => format!("unsafe {{ std::pin::Pin::new_unchecked({fut}) }}.poll(cx)"),
+        }
+    }
+
+    /// The variant a finished poll returns, without the value
+    pub fn ready(self) -> &'static str {
+        match self {
+            Target::NoWaker | Target::Waker => "PollState::Ready",
+            Target::Std => "std::task::Poll::Ready",
+        }
+    }
+
+    /// What a poll that isn't finished returns
+    pub fn not_ready(self) -> &'static str {
+        match self {
+            Target::NoWaker | Target::Waker => "PollState::NotReady",
+            Target::Std => "std::task::Poll::Pending",
+        }
+    }
+
+    /// The type we store the future we wait on as. It may borrow for
+    /// `lifetime`, if there is one.
+    pub fn boxed(self, output: &str, lifetime: Option<&str>) -> String {
+        let bound = lifetime.map(|l| format!(" + {l}")).unwrap_or_default();
+        match self {
+            Target::NoWaker | Target::Waker => {
+                format!("Box<dyn Future<Output = {output}>{bound}>")
+            }
+            Target::Std => {
+                format!("std::pin::Pin<Box<dyn std::future::Future<Output = {output}>{bound}>>")
+            }
+        }
+    }
+
+    /// Boxes the future `fut` so it can be stored as `boxed`
+    pub fn box_new(self, fut: &str) -> String {
+        match self {
+            Target::NoWaker | Target::Waker => format!("Box::new({fut})"),
+            Target::Std => format!("Box::pin({fut})"),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Target::NoWaker => "no-waker",
+            Target::Waker => "waker",
+            Target::Std => "std",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Target::ALL
+            .into_iter()
+            .find(|t| t.to_string() == s)
+            .ok_or_else(|| {
+                let names: Vec<String> = Target::ALL.iter().map(|t| t.to_string()).collect();
+                format!(
+                    "Unknown target `{s}`, expected one of: {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// How a state stores the future it waits on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Futures {
+    /// As a boxed trait object, so every `wait` allocates
+    #[default]
+    Boxed,
+    /// As its own type: the one of the local it's in, if you wrote it down
+    /// (`let fut: Ty = ..; fut.wait`), an `impl Future` type alias if you
+    /// didn't. Those need `#![feature(type_alias_impl_trait)]`.
+    Concrete,
+}
+
+impl Futures {
+    pub const ALL: [Futures; 2] = [Futures::Boxed, Futures::Concrete];
+}
+
+impl fmt::Display for Futures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Futures::Boxed => "boxed",
+            Futures::Concrete => "concrete",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Futures {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Futures::ALL
+            .into_iter()
+            .find(|futures| futures.to_string() == s)
+            .ok_or_else(|| format!("Unknown `--futures` `{s}`, expected `boxed` or `concrete`"))
+    }
+}
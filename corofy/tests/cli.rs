@@ -0,0 +1,260 @@
+//! `corofy` on directories: which files it rewrites, and what `--check` and
+//! `--exclude` make of a tree with sources, `_corofied` files and orphans.
+//! A reference into the coroutine needs `--target std`. The files corofy
+//! wrote that are checked in have to be up to date as well.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const COROUTINE: &str = "\
+use crate::http::Http;
+
+coroutine fn fetch() -> String {
+    Http::get(\"/a\").wait
+}
+";
+
+const PLAIN: &str = "fn main() {}\n";
+
+/// A fresh directory for `name` with `files` in it
+fn tree(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("cli")
+        .join(name);
+    let _ = fs::remove_dir_all(&dir);
+    for (path, code) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, code).unwrap();
+    }
+    dir
+}
+
+/// Runs corofy with `args` in `dir`, whether it succeeded and what it printed
+/// to stderr
+fn corofy(dir: &Path, args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_corofy"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    (output.status.success(), stderr)
+}
+
+#[test]
+fn directory() {
+    let dir = tree(
+        "directory",
+        &[
+            ("main.rs", PLAIN),
+            ("fetch.rs", COROUTINE),
+            ("nested/more.rs", COROUTINE),
+            ("target/built.rs", COROUTINE),
+            (".hidden/secret.rs", COROUTINE),
+        ],
+    );
+    let (ok, stderr) = corofy(&dir, &["."]);
+    assert!(ok, "{stderr}");
+    assert!(dir.join("fetch_corofied.rs").exists());
+    assert!(dir.join("nested/more_corofied.rs").exists());
+    assert!(!dir.join("main_corofied.rs").exists());
+    assert!(!dir.join("target/built_corofied.rs").exists());
+    assert!(!dir.join(".hidden/secret_corofied.rs").exists());
+
+    // What we wrote isn't a source the next time around
+    let (ok, stderr) = corofy(&dir, &["."]);
+    assert!(ok, "{stderr}");
+    assert!(!dir.join("fetch_corofied_corofied.rs").exists());
+}
+
+#[test]
+fn check() {
+    let dir = tree(
+        "check",
+        &[
+            ("main.rs", PLAIN),
+            ("fetch.rs", COROUTINE),
+            ("nested/more.rs", COROUTINE),
+        ],
+    );
+    corofy(&dir, &["fetch.rs"]);
+    corofy(&dir, &["nested/more.rs"]);
+
+    // Up to date
+    let (ok, stderr) = corofy(&dir, &["--check", "."]);
+    assert!(ok, "{stderr}");
+
+    // Stale
+    fs::write(dir.join("fetch.rs"), COROUTINE.replace("/a", "/b")).unwrap();
+    let (ok, stderr) = corofy(&dir, &["--check", "."]);
+    assert!(!ok);
+    assert!(
+        stderr.contains("fetch_corofied.rs is out of date with ./fetch.rs"),
+        "{stderr}"
+    );
+
+    // A source without a `_corofied` sibling isn't checked in a directory...
+    corofy(&dir, &["fetch.rs"]);
+    fs::remove_file(dir.join("nested/more_corofied.rs")).unwrap();
+    let (ok, stderr) = corofy(&dir, &["--check", "."]);
+    assert!(ok, "{stderr}");
+
+    // ...but it is when it's given by name
+    let (ok, stderr) = corofy(&dir, &["--check", ".", "nested/more.rs"]);
+    assert!(!ok);
+    assert!(
+        stderr.contains("more_corofied.rs is missing for nested/more.rs"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn orphans() {
+    let dir = tree(
+        "orphans",
+        &[
+            ("gone_corofied.rs", PLAIN),
+            ("main.rs", PLAIN),
+            ("main_corofied.rs", PLAIN),
+        ],
+    );
+    let (ok, stderr) = corofy(&dir, &["--check", "."]);
+    assert!(!ok);
+    assert!(
+        stderr.contains("gone_corofied.rs has no source"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("main_corofied.rs has no source"),
+        "{stderr}"
+    );
+
+    // Only `--check` looks for them
+    let (ok, stderr) = corofy(&dir, &["."]);
+    assert!(ok, "{stderr}");
+}
+
+#[test]
+fn exclude() {
+    let dir = tree(
+        "exclude",
+        &[
+            ("fetch.rs", COROUTINE),
+            ("vendor/fetch.rs", COROUTINE),
+            ("vendor/old_corofied.rs", PLAIN),
+            ("other.rs", COROUTINE),
+        ],
+    );
+    let (ok, stderr) = corofy(&dir, &[".", "--exclude", "vendor", "--exclude=./other.rs"]);
+    assert!(ok, "{stderr}");
+    assert!(dir.join("fetch_corofied.rs").exists());
+    assert!(!dir.join("vendor/fetch_corofied.rs").exists());
+    assert!(!dir.join("other_corofied.rs").exists());
+
+    // The orphan in `vendor` is skipped as well
+    let (ok, stderr) = corofy(&dir, &["--check", ".", "--exclude", "vendor"]);
+    assert!(ok, "{stderr}");
+    let (ok, _) = corofy(&dir, &["--check", "."]);
+    assert!(!ok);
+}
+
+#[test]
+fn original_in_crate() {
+    let dir = tree(
+        "original_in_crate",
+        &[("original_main.rs", COROUTINE), ("src/main.rs", PLAIN)],
+    );
+    let (ok, stderr) = corofy(&dir, &["original_main.rs"]);
+    assert!(ok, "{stderr}");
+    assert!(dir.join("src/main_corofied.rs").exists());
+
+    // `src/main.rs` has no `coroutine fn`, but the file has a source
+    let (ok, stderr) = corofy(&dir, &["--check", "."]);
+    assert!(ok, "{stderr}");
+    fs::write(dir.join("original_main.rs"), COROUTINE.replace("/a", "/b")).unwrap();
+    let (ok, stderr) = corofy(&dir, &["--check", "src"]);
+    assert!(!ok);
+    assert!(
+        stderr.contains("src/main_corofied.rs is out of date with original_main.rs"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn rustfmt() {
+    let dir = tree("rustfmt", &[("fetch.rs", COROUTINE)]);
+    let (ok, stderr) = corofy(&dir, &["--rustfmt", "fetch.rs"]);
+    assert!(ok, "{stderr}");
+    let code = fs::read_to_string(dir.join("fetch_corofied.rs")).unwrap();
+    assert!(code.contains("impl Future<Output = String>"), "{code}");
+
+    let (ok, stderr) = corofy(&dir, &["--check", "--rustfmt", "fetch.rs"]);
+    assert!(ok, "{stderr}");
+    let (ok, _) = corofy(&dir, &["--check", "fetch.rs"]);
+    assert!(!ok);
+
+    let (ok, stderr) = corofy(&dir, &["--rustfmt", "--map", "fetch.rs"]);
+    assert!(!ok);
+    assert!(stderr.contains("can't be used together"), "{stderr}");
+}
+
+/// The files corofy wrote that are checked in, with the options they were
+/// written with
+/// Only `std` pins the coroutine, the others can't keep a reference into it
+#[test]
+fn self_borrow_needs_std() {
+    let code = "\
+use crate::http::Http;
+
+coroutine fn first() -> usize {
+    let xs: [usize; 2] = [1, 2];
+    let x: &usize = &xs[1];
+    let txt: String = Http::get(\"/a\").wait;
+    *x + txt.len()
+}
+";
+    let dir = tree("self_borrow_needs_std", &[("first.rs", code)]);
+    let (ok, stderr) = corofy(&dir, &["first.rs"]);
+    assert!(!ok);
+    assert!(stderr.contains("`x` borrows a local"), "{stderr}");
+    assert!(stderr.contains("`--target std`"), "{stderr}");
+    assert!(!dir.join("first_corofied.rs").exists());
+
+    let (ok, stderr) = corofy(&dir, &["--target", "std", "first.rs"]);
+    assert!(ok, "{stderr}");
+    assert!(dir.join("first_corofied.rs").exists());
+}
+
+#[test]
+fn checked_in() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    let benches = "corofy/benches/futures";
+    let runs: [&[&str]; 4] = [
+        // The benches write two files from one source
+        &["--check", ".", "--exclude", benches],
+        &[
+            "--check",
+            "--rustfmt",
+            "join/original_main.rs",
+            "join/src/main.rs",
+        ],
+        &[
+            "--check",
+            &format!("{benches}/coroutines.rs"),
+            &format!("{benches}/boxed_corofied.rs"),
+        ],
+        &[
+            "--check",
+            "--futures",
+            "concrete",
+            &format!("{benches}/coroutines.rs"),
+            &format!("{benches}/concrete_corofied.rs"),
+        ],
+    ];
+    for args in runs {
+        let (ok, stderr) = corofy(root, args);
+        assert!(ok, "corofy {}:\n{stderr}", args.join(" "));
+    }
+}
@@ -0,0 +1,105 @@
+//! Builds the code corofy writes with `rustc`, against the stub futures in
+//! `tests/stubs`, and runs it
+
+// Each test uses some of these
+#![allow(dead_code)]
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{env, fs};
+
+use corofy::Target;
+
+/// Builds `code`, which corofy wrote for `target`, as a library with the
+/// nightly `features` turned on. `Err` has the diagnostics if it doesn't
+/// build. `name` tells the files it writes to the target directory apart.
+pub fn build(name: &str, code: &str, target: Target, features: &[&str]) -> Result<(), String> {
+    let mut metadata = OsString::from("metadata=");
+    metadata.push(out_dir().join(format!("lib{}.rmeta", crate_name(name, target))));
+    rustc(
+        name,
+        code,
+        target,
+        features,
+        &[
+            "--crate-type".into(),
+            "lib".into(),
+            "--emit".into(),
+            metadata,
+        ],
+    )
+}
+
+/// Builds `code` like `build` does, but as a program with the `main` in it,
+/// and runs it. `Err` has the diagnostics if it doesn't build, or what it
+/// printed to stderr if it fails.
+pub fn run(name: &str, code: &str, target: Target, features: &[&str]) -> Result<(), String> {
+    let exe = out_dir().join(crate_name(name, target));
+    rustc(
+        name,
+        code,
+        target,
+        features,
+        &[
+            "--crate-type".into(),
+            "bin".into(),
+            "-o".into(),
+            exe.clone().into(),
+        ],
+    )?;
+    let output = Command::new(&exe).output().unwrap();
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(String::from_utf8_lossy(&output.stderr).into_owned()),
+    }
+}
+
+fn out_dir() -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("build");
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn crate_name(name: &str, target: Target) -> String {
+    format!("{name}_{target}")
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Compiles the stubs of `target` and `code` with `args`
+fn rustc(
+    name: &str,
+    code: &str,
+    target: Target,
+    features: &[&str],
+    args: &[OsString],
+) -> Result<(), String> {
+    let stubs = Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("tests/stubs/{target}.rs"));
+    let src = out_dir().join(format!("{name}.{target}.rs"));
+    let features: String = features
+        .iter()
+        .map(|feature| format!("#![feature({feature})]\n"))
+        .collect();
+    let stubs = fs::read_to_string(stubs).unwrap();
+    fs::write(&src, format!("{features}{stubs}\n{code}")).unwrap();
+
+    // The generated code has unused imports and variables, that's fine
+    let mut rustc = Command::new(env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()));
+    if !features.is_empty() {
+        // So a stable `rustc` takes them too
+        rustc.env("RUSTC_BOOTSTRAP", "1");
+    }
+    let output = rustc
+        .args(["--edition", "2024", "--cap-lints", "allow"])
+        .args(["--crate-name", &crate_name(name, target)])
+        .args(args)
+        .arg(&src)
+        .output()
+        .unwrap();
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(String::from_utf8_lossy(&output.stderr).into_owned()),
+    }
+}
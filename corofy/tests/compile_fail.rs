@@ -0,0 +1,46 @@
+//! Code that mustn't build once corofy rewrote it, because it doesn't build
+//! as an `async fn` either. Every file in `tests/compile_fail` is rewritten
+//! for each target, and `rustc` has to reject it with the error on its first
+//! line.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use corofy::{Target, generate, parse};
+
+#[test]
+fn compile_fail() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/compile_fail");
+    let mut inputs: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .collect();
+    inputs.sort();
+
+    let mut failures = vec![];
+    for input in inputs {
+        let src = fs::read_to_string(&input).unwrap();
+        let expected = src
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix("// "))
+            .unwrap_or_else(|| panic!("{} doesn't start with the error", input.display()));
+        let program = parse(&src).unwrap();
+        let name = input.file_stem().unwrap().to_string_lossy();
+        for target in Target::ALL {
+            match common::build(&name, &generate(&program, target), target, &[]) {
+                Ok(()) => failures.push(format!("{}: builds for `{target}`", input.display())),
+                Err(e) if !e.contains(expected) => failures.push(format!(
+                    "{}: expected `{expected}` for `{target}`, got:\n{e}",
+                    input.display()
+                )),
+                Err(_) => (),
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
@@ -0,0 +1,7 @@
+// error: lifetime may not live long enough
+use crate::http::Http;
+
+coroutine fn leak<'a>(s: &'a str) -> &'static str {
+    Http::get("/a").wait;
+    s
+}
@@ -0,0 +1,219 @@
+//! Rewrites every `*.rs` file in `tests/golden` and compares the result with
+//! the files next to it: `name.{target}.out` and its source map
+//! `name.{target}.map` for each target, or `name.err` with the diagnostic if
+//! the file doesn't parse (`name.{target}.err` if a target can't run it).
+//! What `parse` makes of a file that parses is printed to `name.ir`. Files whose names start with `concrete_` are
+//! rewritten with `--futures concrete`, the ones that start with `in_place_`
+//! with `--in-place` and the ones that start with `log_cancel_` with
+//! `--log-cancel`. The ones that start with `explain_` aren't rewritten but
+//! drawn, to `name.{diagram}.out` for each `--explain` diagram.
+//!
+//! After a change to the generated code, run with `BLESS=1` to write the
+//! current output as the expected one and review the diff. Whatever it
+//! writes has to build against the stub futures in `tests/stubs` as well.
+
+mod common;
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use corofy::ir::Program;
+use corofy::{
+    CorofyError, Diagram, Futures, Target, check, explain, generate, generate_nested,
+    generate_with_map, parse,
+};
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+/// The files in `tests/golden` with `extension`, sorted so failures are
+/// reported in the same order every time
+fn files(extension: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(golden_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+        .collect();
+    files.sort();
+    files
+}
+
+/// The expected files for `input` and what they should contain
+fn outputs(input: &Path) -> Vec<(PathBuf, String)> {
+    let src = fs::read_to_string(input).unwrap();
+    let name = input.file_name().unwrap().to_string_lossy();
+    let error = |e| {
+        let error = CorofyError::parse(&src, e).in_file(input.file_name().unwrap());
+        format!("{error}\n")
+    };
+    let program = match parse(&src) {
+        Ok(program) => program,
+        Err(e) => return vec![(input.with_extension("err"), error(e))],
+    };
+    let mut outputs = vec![(input.with_extension("ir"), program.to_string())];
+    if name.starts_with("explain_") {
+        for diagram in Diagram::ALL {
+            let path = input.with_extension(format!("{diagram}.out"));
+            outputs.push((path, explain(&program, diagram)));
+        }
+        return outputs;
+    }
+
+    let program = with_options(&name, program);
+    for target in Target::ALL {
+        if let Err(e) = check(&program, target) {
+            outputs.push((input.with_extension(format!("{target}.err")), error(e)));
+            continue;
+        }
+        let (code, map) = generate_with_map(&program, target);
+        outputs.push((input.with_extension(format!("{target}.out")), code));
+        outputs.push((
+            input.with_extension(format!("{target}.map")),
+            map.to_string(),
+        ));
+    }
+    outputs
+}
+
+/// `program` with the options the name of its input asks for
+fn with_options(name: &str, program: Program) -> Program {
+    Program {
+        futures: match name.starts_with("concrete_") {
+            true => Futures::Concrete,
+            false => Futures::Boxed,
+        },
+        in_place: name.starts_with("in_place_"),
+        log_cancel: name.starts_with("log_cancel_"),
+        ..program
+    }
+}
+
+/// The first line where `actual` isn't what we `expected`
+fn first_difference(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.split('\n').collect();
+    let actual: Vec<&str> = actual.split('\n').collect();
+    let line = (0..expected.len().max(actual.len()))
+        .find(|&i| expected.get(i) != actual.get(i))
+        .unwrap_or(0);
+    format!(
+        "line {}: expected {:?}, got {:?}",
+        line + 1,
+        expected.get(line).copied().unwrap_or("<end of file>"),
+        actual.get(line).copied().unwrap_or("<end of file>"),
+    )
+}
+
+#[test]
+fn golden() {
+    let bless = std::env::var_os("BLESS").is_some();
+    let mut expected_files = BTreeSet::new();
+    let mut failures = vec![];
+
+    for input in files("rs") {
+        for (path, actual) in outputs(&input) {
+            if bless {
+                fs::write(&path, &actual).unwrap();
+            } else {
+                match fs::read_to_string(&path) {
+                    Ok(expected) if expected == actual => (),
+                    Ok(expected) => failures.push(format!(
+                        "{}: {}",
+                        path.display(),
+                        first_difference(&expected, &actual)
+                    )),
+                    Err(_) => failures.push(format!("{}: missing", path.display())),
+                }
+            }
+            expected_files.insert(path);
+        }
+    }
+
+    // An input that used to parse and doesn't anymore (or the other way
+    // round) leaves the old files behind
+    for stale in [files("out"), files("map"), files("err"), files("ir")].concat() {
+        if expected_files.contains(&stale) {
+            continue;
+        }
+        match bless {
+            true => fs::remove_file(&stale).unwrap(),
+            false => failures.push(format!("{}: no input produces it", stale.display())),
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "The output differs from the golden files, run with `BLESS=1` to update them:\n{}",
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn golden_builds() {
+    let mut failures = vec![];
+    for input in files("rs") {
+        let src = fs::read_to_string(&input).unwrap();
+        let name = input.file_stem().unwrap().to_string_lossy();
+        let Ok(program) = parse(&src) else { continue };
+        if name.starts_with("explain_") {
+            continue;
+        }
+        let program = with_options(&name, program);
+        // The type aliases `--futures concrete` writes are a nightly feature
+        let features: &[&str] = match program.futures {
+            Futures::Concrete => &["type_alias_impl_trait"],
+            Futures::Boxed => &[],
+        };
+        for target in Target::ALL
+            .into_iter()
+            .filter(|&t| check(&program, t).is_ok())
+        {
+            if let Err(e) = common::build(&name, &generate(&program, target), target, features) {
+                failures.push(format!("{name}.{target}.out doesn't build:\n{e}"));
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// `#[corofy_macro::coroutine]` puts the state machine inside the function,
+/// but it's the same one the command line writes
+#[test]
+fn nested_is_the_same_machine() {
+    let mut failures = vec![];
+    for input in files("rs") {
+        let src = fs::read_to_string(&input).unwrap();
+        let name = input.file_stem().unwrap().to_string_lossy();
+        let Ok(program) = parse(&src) else { continue };
+        // The macro has none of these options
+        if ["explain_", "concrete_", "in_place_", "log_cancel_"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+        {
+            continue;
+        }
+        for target in Target::ALL
+            .into_iter()
+            .filter(|&t| check(&program, t).is_ok())
+        {
+            // The line endings of the file don't matter to a macro
+            let cli = generate(&program, target).replace("\r\n", "\n");
+            for coroutine in program.coroutines.iter().filter(|c| c.method.is_none()) {
+                // Between the signature and the call that makes the coroutine
+                let nested = generate_nested(coroutine, target);
+                let machine = nested
+                    .split_once('\n')
+                    .and_then(|(_, rest)| rest.rsplit_once("\n\n    Coroutine"))
+                    .map(|(machine, _)| machine)
+                    .unwrap();
+                if !cli.contains(machine) {
+                    failures.push(format!("{name}.{target}.out: {}", coroutine.name));
+                }
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
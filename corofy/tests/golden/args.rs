@@ -0,0 +1,28 @@
+use crate::http::Http;
+
+coroutine fn request(i: usize, delay: u64) {
+    let path = format!("/{}/HelloWorld{i}", delay * 100);
+    let txt = Http::get(&path).wait;
+    println!("{i}: {txt}");
+}
+
+coroutine fn collect(mut paths: Vec<String>, sep: char) -> String {
+    let mut out: String = String::new();
+    while let Some(path) = paths.pop() {
+        let txt = Http::get(&path).wait;
+        out.push_str(&txt);
+        out.push(sep);
+    }
+    out
+}
+
+coroutine fn first_line(prefix: &'static str, fallback: String) -> String {
+    let txt = Http::get(prefix).wait;
+    txt.lines().next().map_or(fallback, str::to_string)
+}
+
+coroutine fn patterns(_: u8, (path, n): (&'static str, usize), mut sum: usize) -> usize {
+    sum += n;
+    let txt = Http::get(path).wait;
+    sum + txt.len()
+}
@@ -0,0 +1,24 @@
+use std::collections::HashMap;
+
+use crate::http::Http;
+
+// coroutine fn not_me() {}
+fn my_coroutine_fn() { let s = "coroutine fn x() {"; }
+/* block { comment
+   coroutine fn nope() { */
+
+/// Doc comments move with the function
+coroutine fn request(
+    i: usize,
+    m: HashMap<u8, String>, // }
+) {
+    // A comment before the first statement
+    let s: &'static str = "}}}{";
+    let r: &'static str = r#"raw " } "#;
+    let c: char = '}';
+    /* the path */
+    let txt: String = Http::get(&format!("/{i}")) // trailing
+        .wait; // after the wait
+    println!("{txt} {s} {r} {c}"); /* { */
+    // the last line
+}
@@ -0,0 +1,20 @@
+use crate::http::Http;
+use crate::future::{Delay, delay};
+
+coroutine fn fetch(path: &'static str) -> usize {
+    let txt = Http::get(path).wait;
+    txt.len()
+}
+
+coroutine fn async_main(pending: Delay<String>) -> String {
+    let mut log: String = String::new();
+    let waited: String = pending.wait;
+    log.push_str(&waited);
+    let n: usize = fetch("/one").wait;
+    let (a, b): (String, usize) = join!(Http::get("/two"), fetch("/three")).wait;
+    let first = select!(Http::get("/four"), Http::get("/five")).wait;
+    let delays: Vec<Delay<String>> = vec![delay(String::from("a"), 1), delay(String::from("b"), 2)];
+    let all: Vec<String> = join_all(delays).wait;
+    let more: Vec<usize> = join_all(vec![fetch("/six")]).wait;
+    format!("{log} {n} {a} {b} {first} {all:?} {more:?}")
+}
@@ -0,0 +1,59 @@
+use std::fmt::Write;
+
+use crate::http::Http;
+
+coroutine fn fetch(n: usize) -> usize {
+    let mut attempts: usize = 0;
+    // retry until the response is long enough
+    loop {
+        let txt = Http::get("/abc").wait;
+        attempts += 1;
+        if attempts < n {
+            continue;
+        }
+        if txt.len() > 2 {
+            break;
+        }
+    }
+    attempts
+}
+
+coroutine fn async_main() -> String {
+    let mut log: String = String::new();
+    let n: usize = fetch(3).wait;
+    writeln!(log, "attempts {n}").unwrap();
+    if n > 2 {
+        let a = Http::get("/one").wait;
+        let b = Http::get("/two").wait;
+        writeln!(log, "{a} {b}").unwrap();
+    } else if n == 1 {
+        Http::get("/x").wait;
+    } else {
+        writeln!(log, "no wait here").unwrap();
+    }
+    match n {
+        0 => {
+            Http::get("/zero").wait;
+        }
+        m if m > 1 => {
+            let k: usize = m;
+            let t = Http::get("/many").wait;
+            writeln!(log, "many {k} {t}").unwrap();
+        }
+        _ => (),
+    }
+    let mut left: Vec<&'static str> = vec!["/a", "/b", "/c"];
+    let mut total: usize = 0;
+    'outer: while let Some(p) = left.pop() {
+        let r = Http::get(p).wait;
+        total += r.len();
+        if r == "/b" {
+            continue 'outer;
+        }
+        if total > 100 {
+            return log;
+        }
+    }
+    writeln!(log, "total {total}").unwrap();
+    log
+}
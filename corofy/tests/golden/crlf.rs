@@ -0,0 +1,12 @@
+use crate::http::Http;
+
+/// Line endings of the input are kept
+coroutine fn crlf(n: usize) -> usize {
+    let mut total: usize = 0;
+    let txt = Http::get("/crlf").wait;
+    if txt.len() > n {
+        let more = Http::get("/more").wait;
+        total += more.len();
+    }
+    total + txt.len()
+}
@@ -0,0 +1,7 @@
+use crate::http::Http;
+
+coroutine fn elided<'a>(s: &'a str) -> usize {
+    let t: &str = s;
+    let txt: String = Http::get("/a").wait;
+    t.len() + txt.len()
+}
@@ -0,0 +1,3 @@
+coroutine fn nothing_to_wait_on() {
+    .wait;
+}
@@ -0,0 +1,6 @@
+use crate::http::Http;
+
+coroutine fn pattern((path, n): (&'static str, usize)) -> usize {
+    let txt = Http::get(path).wait;
+    txt.len() + n
+}
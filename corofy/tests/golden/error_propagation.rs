@@ -0,0 +1,23 @@
+use std::fmt::Write;
+use std::num::ParseIntError;
+
+use crate::http::Http;
+
+coroutine fn length(path: &'static str) -> Result<usize, ParseIntError> {
+    let txt: String = Http::get(path).wait;
+    let n: usize = txt.trim().parse()?;
+    Ok(n)
+}
+
+coroutine fn async_main() -> Result<String, ParseIntError> {
+    let mut log: String = String::new();
+    let a: usize = length("/1").wait?;
+    writeln!(log, "a {a}").unwrap();
+    if a == 0 {
+        return Ok(log);
+    }
+    let b: usize = length("/2").wait?;
+    let c: usize = "3".parse()?;
+    writeln!(log, "{b} {c}").unwrap();
+    Ok(log)
+}
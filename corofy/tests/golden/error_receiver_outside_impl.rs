@@ -0,0 +1,5 @@
+struct Client;
+
+coroutine fn outside(&self) {
+    println!("not in an impl");
+}
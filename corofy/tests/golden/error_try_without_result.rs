@@ -0,0 +1,7 @@
+use crate::http::Http;
+
+coroutine fn length() -> usize {
+    let txt: String = Http::get("/1").wait;
+    let n: usize = txt.parse()?;
+    n
+}
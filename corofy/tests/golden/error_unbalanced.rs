@@ -0,0 +1,3 @@
+coroutine fn unbalanced() {
+    let s = (1;
+}
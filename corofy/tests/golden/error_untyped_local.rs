@@ -0,0 +1,7 @@
+use crate::http::Http;
+
+coroutine fn untyped() {
+    let count = 1;
+    let txt = Http::get("/").wait;
+    println!("{txt} {count}");
+}
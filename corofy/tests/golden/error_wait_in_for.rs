@@ -0,0 +1,8 @@
+use crate::http::Http;
+
+coroutine fn in_for() {
+    for i in 0..3 {
+        let txt = Http::get(&format!("/{i}")).wait;
+        println!("{txt}");
+    }
+}
@@ -0,0 +1,26 @@
+use std::num::ParseIntError;
+
+use crate::future::delay;
+use crate::http::Http;
+
+coroutine fn count(path: &'static str) -> Result<usize, ParseIntError> {
+    let (a, b): (String, String) = join!(Http::get(path), delay("0".to_string(), 1)).wait;
+    let n: usize = a.trim().parse()?;
+    if n == 0 {
+        return Ok(b.len());
+    }
+    Ok(n)
+}
+
+coroutine fn async_main() -> Result<String, ParseIntError> {
+    let mut total: usize = 0;
+    while total < 10 {
+        let n: usize = count("/1").wait?;
+        if n > 5 {
+            break;
+        }
+        total += n;
+    }
+    let fastest: &'static str = select!(delay("slow", 5), delay("fast", 1)).wait;
+    Ok(format!("{fastest} \"{total}\" <#>"))
+}
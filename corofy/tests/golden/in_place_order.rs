@@ -0,0 +1,33 @@
+use crate::http::Http;
+
+/// Comes first and stays first
+#[allow(dead_code)]
+pub coroutine fn first() -> String {
+    Http::get("/first").wait
+}
+
+fn between() {}
+
+mod nested {
+    // The state machine goes in here, it needs the `Future` trait as well
+    use super::*;
+
+    /// Indented like the module
+    pub(super) coroutine fn inner(path: &'static str) -> usize {
+        let txt: String = Http::get(path).wait;
+        txt.len()
+    }
+}
+
+struct Client {
+    base: String,
+}
+
+impl Client {
+    coroutine fn fetch(&self, path: &'static str) -> String {
+        let txt: String = Http::get(path).wait;
+        format!("{}{txt}", self.base)
+    }
+}
+
+fn main() {}
@@ -0,0 +1,24 @@
+use std::fmt::Write;
+
+use crate::future::delay;
+
+coroutine fn pair(x: usize) -> (usize, String) {
+    join!(delay(x, 2), delay(format!("x{x}"), 1)).wait
+}
+
+coroutine fn async_main() -> String {
+    let mut log: String = String::new();
+    let (a, b): (usize, String) = join!(delay(1usize, 3), delay("b".to_string(), 1),).wait;
+    writeln!(log, "join {a} {b}").unwrap();
+    let first: &'static str = select!(delay("slow", 5), delay("fast", 1), delay("never", 9)).wait;
+    writeln!(log, "select {first}").unwrap();
+    let mut futs = vec![];
+    for i in 0..4 {
+        futs.push(delay(i * 10, 4 - i));
+    }
+    let all: Vec<usize> = join_all(futs).wait;
+    writeln!(log, "join_all {all:?} {a}").unwrap();
+    let p: (usize, String) = pair(7).wait;
+    writeln!(log, "pair {p:?}").unwrap();
+    log
+}
@@ -0,0 +1,12 @@
+use crate::http::Http;
+
+/// Dropped while it waits, it says on which line
+coroutine fn poll_until(path: &'static str, n: usize) -> usize {
+    let first: String = Http::get(path).wait;
+    let mut seen: usize = first.len();
+    while seen < n {
+        let more: String = Http::get("/more").wait;
+        seen += more.len();
+    }
+    seen
+}
@@ -0,0 +1,18 @@
+use std::fmt::Write;
+
+use crate::http::Http;
+
+coroutine fn async_main() {
+    println!("Program starting");
+    let mut buffer: String = String::from("\nBUFFER:\n----\n");
+    let writer: &mut String = &mut buffer;
+    let txt = Http::get("/600/HelloAsyncAwait").wait;
+    writeln!(writer, "{txt}").unwrap();
+    let txt = Http::get("/400/HelloAsyncAwait").wait;
+    writeln!(writer, "{txt}").unwrap();
+    let txt = Http::get("/200/HelloAsyncAwait").wait;
+    writeln!(writer, "{txt}").unwrap();
+    let txt = Http::get("/100/HelloAsyncAwait").wait;
+    writeln!(writer, "{txt}").unwrap();
+    println!("{buffer}");
+}
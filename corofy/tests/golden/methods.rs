@@ -0,0 +1,65 @@
+use std::fmt::Display;
+
+use crate::future::{Peek, delay};
+
+struct Client {
+    base: String,
+    calls: usize,
+}
+
+impl Client {
+    const RETRIES: usize = 2;
+
+    coroutine fn connect(base: String) -> Self {
+        let ok: bool = delay(true, 1).wait;
+        Self { base: if ok { base } else { String::new() }, calls: 0 }
+    }
+
+    /// Fetches `id`
+    pub coroutine fn fetch(&self, id: u32) -> String {
+        let body: String = delay(format!("{}/{id}", self.base), 2).wait;
+        let again: usize = delay(Self::RETRIES, 1).wait;
+        let len: usize = Peek(&self.base).wait;
+        assert_eq!(len, self.base.len());
+        format!("{body} x{again} from {}", self.base)
+    }
+
+    coroutine fn count(&mut self, n: usize) -> usize {
+        let mut i: usize = 0;
+        while i < n {
+            let one: usize = delay(1, 1).wait;
+            self.calls += one;
+            i += 1;
+        }
+        self.calls
+    }
+
+    coroutine fn into_base(mut self, suffix: String) -> String {
+        let s: String = delay(suffix, 1).wait;
+        self.base.push_str(&s);
+        self.base
+    }
+}
+
+struct Labeled<T> {
+    label: T,
+}
+
+impl<T: Display + Clone> Labeled<T>
+where
+    T: 'static,
+{
+    coroutine fn show<U>(&self, extra: U) -> String
+    where
+        U: Display,
+    {
+        let n: usize = delay(3usize, 1).wait;
+        format!("{}: {extra} {n}", self.label)
+    }
+}
+
+coroutine fn generic_free<'a, T: Clone + Display>(x: &'a T) -> String {
+    let copy: T = x.clone();
+    let n: usize = delay(1usize, 1).wait;
+    format!("{copy} {n}")
+}
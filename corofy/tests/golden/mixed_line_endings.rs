@@ -0,0 +1,13 @@
+use crate::http::Http;
+
+/// Most lines end with `\n`, a few with `\r\n`. The output gets the one
+/// most of them use.
+coroutine fn mixed(n: usize) -> usize {
+    let first: String = Http::get("/first").wait;
+    let mut total: usize = first.len();
+    if n > 1 {
+        let second: String = Http::get("/second").wait;
+        total += second.len();
+    }
+    total
+}
@@ -0,0 +1,21 @@
+use crate::http::Http;
+
+coroutine   fn   squashed(  ) -> usize { let a = Http::get("/a").wait; let b = Http::get("/b").wait; a.len() + b.len() }
+
+  coroutine fn
+split(
+	n : usize ,
+)
+-> String
+{
+	let txt
+		= Http::get(
+			"/split",
+		)
+		.wait
+		;
+            if n>0{ Http::get("/n").wait; }
+
+
+	txt
+}
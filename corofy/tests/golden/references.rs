@@ -0,0 +1,8 @@
+use crate::http::Http;
+
+/// `name` points outside, so it keeps its lifetime
+coroutine fn greet<'a>(names: &'a [String]) -> String {
+    let name: &'a str = &names[0];
+    let txt: String = Http::get("/b").wait;
+    format!("{txt} {name}")
+}
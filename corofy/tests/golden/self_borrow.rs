@@ -0,0 +1,18 @@
+use crate::http::Http;
+
+/// `first` points into `buffer`, which stays in the stack for it. Only the
+/// `std` target pins the coroutine, the others reject it.
+coroutine fn first_char(prefix: String) -> usize {
+    let buffer: String = prefix.clone();
+    let view: &str = buffer.as_str();
+    let first: &str = &view[..1];
+    let txt: String = Http::get("/a").wait;
+    first.len() + txt.len()
+}
+
+/// The code after the `let` that moves `x` into the stack goes on its own line
+coroutine fn third(offset: u64) -> u64 {
+    let x: [u64; 4] = [1, 2, 3, offset]; let r: &u64 = &x[2];
+    let s: String = Http::get("/q").wait;
+    *r + s.len() as u64
+}
@@ -0,0 +1,26 @@
+use crate::future::delay;
+
+/// An `if` at the end that waits resolves the coroutine in each branch
+coroutine fn pick(n: usize) -> usize {
+    if n > 1 {
+        let v: usize = delay(n, 1).wait;
+        v
+    } else if n == 1 {
+        delay(1, 1).wait
+    } else {
+        0
+    }
+}
+
+/// And so does a `match`
+coroutine fn name(n: usize) -> String {
+    let prefix: String = delay(String::from("n"), 1).wait;
+    match n {
+        0 => delay(String::from("zero"), 1).wait,
+        1 => {
+            let one: String = delay(String::from("one"), 1).wait;
+            format!("{prefix}{one}")
+        }
+        _ => return prefix,
+    }
+}
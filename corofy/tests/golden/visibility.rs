@@ -0,0 +1,22 @@
+use crate::http::Http;
+
+/// Fetches the front page
+///
+/// It's slow.
+#[allow(dead_code)]
+pub(crate) coroutine fn front_page() -> String {
+    Http::get("/").wait
+}
+
+    /// Not at the start of its line
+    pub coroutine fn indented() {
+        Http::get("/indented").wait;
+    }
+
+// A plain comment stays where it is
+
+#[inline] pub coroutine fn inline() {
+    Http::get("/inline").wait;
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use crate::http::Http;
+
+coroutine fn hello() {
+    println!("Hello from a coroutine that never waits");
+}
+
+coroutine fn answer() -> usize {
+    40 + 2
+}
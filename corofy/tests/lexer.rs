@@ -0,0 +1,95 @@
+//! The tokenizer, through `parse`: a brace, a `wait` or a `coroutine fn` in
+//! a literal or a comment isn't one, and a literal that doesn't end says
+//! where it starts.
+
+use corofy::{Target, generate, has_coroutines, parse};
+
+/// The states of the only coroutine in `src`
+fn states(src: &str) -> Vec<String> {
+    let program = parse(src).unwrap_or_else(|e| panic!("{e}"));
+    assert_eq!(program.coroutines.len(), 1);
+    program.coroutines[0]
+        .steps
+        .iter()
+        .map(|step| step.name.clone())
+        .collect()
+}
+
+#[test]
+fn raw_strings() {
+    let src = r####"
+coroutine fn f() -> usize {
+    let s: &str = r##"} "# Http::get("/x").wait { "##;
+    let n: usize = delay(s.len(), 1).wait;
+    n
+}
+"####;
+    assert_eq!(states(src), ["Start", "Wait1"]);
+    // Copied as it is
+    let code = generate(&parse(src).unwrap(), Target::NoWaker);
+    assert!(code.contains(r###"r##"} "# Http::get("/x").wait { "##;"###));
+}
+
+#[test]
+fn nested_block_comments() {
+    let src = "
+coroutine fn f() -> usize {
+    /* outer /* inner } */ still a comment: x.wait } */
+    let n: usize = delay(1usize, 1).wait;
+    n
+}
+
+/* /* */ coroutine fn commented() {} */
+";
+    assert_eq!(states(src), ["Start", "Wait1"]);
+    assert!(!has_coroutines("/* /* */ coroutine fn f() {} */"));
+}
+
+#[test]
+fn byte_chars() {
+    let src = "
+coroutine fn f() -> u8 {
+    let close: u8 = b'}';
+    let n: u8 = delay(b'{', 1).wait;
+    close - n
+}
+";
+    assert_eq!(states(src), ["Start", "Wait1"]);
+}
+
+#[test]
+fn lifetimes_and_chars() {
+    let src = "
+coroutine fn f<'a>(s: &'a str) -> char {
+    let c: char = '}';
+    let first: &'a str = &s[..1];
+    let n: usize = delay(1usize, 1).wait;
+    if first == \"a\" { 'a' } else { c }
+}
+";
+    assert_eq!(states(src), ["Start", "Wait1"]);
+    let program = parse(src).unwrap();
+    assert_eq!(program.coroutines[0].generics.params, ["'a"]);
+    assert_eq!(
+        program.coroutines[0].args[0],
+        ("s".into(), "&'a str".into())
+    );
+}
+
+#[test]
+fn unterminated_literals() {
+    for (literal, msg) in [
+        ("\"abc;", "Unterminated string literal"),
+        ("r#\"abc\";", "Unterminated raw string literal"),
+        ("'\\n;", "Unterminated character literal"),
+        ("b'}", "Unterminated character literal"),
+        ("/* /* */ 1;", "Unterminated block comment"),
+    ] {
+        let src = format!("coroutine fn f() {{\n    let s = {literal}\n}}\n");
+        let e = parse(&src).unwrap_err();
+        assert_eq!(e.msg, msg, "{literal}");
+        let span = e.span.unwrap();
+        // Where the literal starts, after `    let s = `
+        assert_eq!((span.line, span.col), (2, 13), "{literal}");
+    }
+}
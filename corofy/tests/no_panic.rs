@@ -0,0 +1,58 @@
+//! `rewrite` has to report anything it can't handle as a `ParseError`, it
+//! must never panic. This feeds it the golden inputs cut up the way a file
+//! looks while you're still typing it. The fuzz target in `fuzz/` does the
+//! same with arbitrary input.
+
+use std::fs;
+use std::io;
+use std::panic;
+use std::path::Path;
+
+use corofy::{Target, rewrite};
+
+/// Characters where a half written file is most likely to end
+const DELIMS: &str = "(){}[]<>;:,.&'\"#!=|-";
+
+/// Truncates `src` after every line and delimiter, and drops every line and
+/// every delimiter in turn
+fn mutations(src: &str) -> Vec<String> {
+    let mut res = vec![];
+    for (i, c) in src.char_indices() {
+        if c == '\n' || DELIMS.contains(c) {
+            res.push(src[..i].to_string());
+            res.push(format!("{}{}", &src[..i], &src[i + c.len_utf8()..]));
+        }
+    }
+    let lines: Vec<&str> = src.split_inclusive('\n').collect();
+    for i in 0..lines.len() {
+        let mut lines = lines.clone();
+        lines.remove(i);
+        res.push(lines.concat());
+    }
+    res
+}
+
+#[test]
+fn rewrite_never_panics() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut inputs: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .collect();
+    inputs.sort();
+
+    for input in inputs {
+        let src = fs::read_to_string(&input).unwrap();
+        for mutated in mutations(&src) {
+            for target in Target::ALL {
+                let res = panic::catch_unwind(|| rewrite(mutated.clone(), io::sink(), target));
+                assert!(
+                    res.is_ok(),
+                    "`rewrite` panicked on a mutation of {} for `{target}`:\n{mutated}",
+                    input.display()
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Runs the state machines corofy writes. Every file in `tests/run` is
+//! rewritten for each target that takes it, with boxed and with concrete
+//! futures, built against the stub futures in `tests/stubs` and run. Its
+//! `main` checks what the coroutines resolve to.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use corofy::ir::Program;
+use corofy::{Futures, Target, check, generate, parse};
+
+#[test]
+fn run() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/run");
+    let mut inputs: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .collect();
+    inputs.sort();
+
+    let mut failures = vec![];
+    for input in inputs {
+        let src = fs::read_to_string(&input).unwrap();
+        let program = parse(&src).unwrap();
+        let name = input.file_stem().unwrap().to_string_lossy();
+        for futures in [Futures::Boxed, Futures::Concrete] {
+            let program = Program {
+                futures,
+                ..program.clone()
+            };
+            // The type aliases `--futures concrete` writes are a nightly feature
+            let (name, features): (String, &[&str]) = match futures {
+                Futures::Boxed => (name.to_string(), &[]),
+                Futures::Concrete => (format!("{name}_concrete"), &["type_alias_impl_trait"]),
+            };
+            for target in Target::ALL
+                .into_iter()
+                .filter(|&t| check(&program, t).is_ok())
+            {
+                let code = generate(&program, target);
+                if let Err(e) = common::run(&name, &code, target, features) {
+                    failures.push(format!("{name} for `{target}`:\n{e}"));
+                }
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
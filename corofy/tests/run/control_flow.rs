@@ -0,0 +1,59 @@
+use crate::future::delay;
+use crate::http::Http;
+
+/// `break 'outer` out of the inner loop, and a plain `break` out of it
+coroutine fn labeled_break(limit: usize) -> usize {
+    let mut i: usize = 0;
+    let mut steps: usize = 0;
+    'outer: loop {
+        loop {
+            let n: usize = delay(i, 1).wait;
+            steps += 1;
+            if n >= limit {
+                break 'outer;
+            }
+            i += 1;
+            if i % 2 == 0 {
+                break;
+            }
+        }
+        steps += 100;
+    }
+    steps
+}
+
+/// `continue` and the back edge of a `while` both go back to the condition
+coroutine fn sum_even(n: usize) -> usize {
+    let mut i: usize = 0;
+    let mut sum: usize = 0;
+    while i < n {
+        let v: usize = delay(i, 2).wait;
+        i += 1;
+        if v % 2 == 1 {
+            continue;
+        }
+        sum += v;
+    }
+    sum
+}
+
+/// `return` in the middle of a `while let`
+coroutine fn first_long(paths: Vec<&'static str>) -> String {
+    let mut left: Vec<&'static str> = paths;
+    while let Some(p) = left.pop() {
+        let txt = Http::get(p).wait;
+        if txt.len() > 3 {
+            return txt;
+        }
+    }
+    String::from("none")
+}
+
+fn main() {
+    assert_eq!(block_on(labeled_break(3)), 104);
+    assert_eq!(block_on(labeled_break(0)), 1);
+    assert_eq!(block_on(sum_even(7)), 12);
+    assert_eq!(block_on(sum_even(0)), 0);
+    assert_eq!(block_on(first_long(vec!["/a", "/long", "/b"])), "/long");
+    assert_eq!(block_on(first_long(vec!["/a", "/b"])), "none");
+}
@@ -0,0 +1,13 @@
+use crate::future::delay;
+
+/// `select!` resolves to the future that's ready first, `join!` to all of them
+coroutine fn race(slow: usize) -> String {
+    let first: &'static str = select!(delay("late", slow), delay("early", 2), delay("never", 9)).wait;
+    let (a, b): (usize, String) = join!(delay(1usize, 3), delay("b".to_string(), 1)).wait;
+    format!("{first} {a} {b}")
+}
+
+fn main() {
+    assert_eq!(block_on(race(5)), "early 1 b");
+    assert_eq!(block_on(race(0)), "late 1 b");
+}
@@ -0,0 +1,23 @@
+use std::fmt::Write;
+
+use crate::http::Http;
+
+/// `writer` points into `buffer` across every `wait`, which only the `std`
+/// target takes
+coroutine fn collect(paths: Vec<&'static str>) -> String {
+    let mut buffer: String = String::from("got");
+    let writer: &mut String = &mut buffer;
+    let mut left: Vec<&'static str> = paths;
+    while let Some(p) = left.pop() {
+        let txt = Http::get(p).wait;
+        write!(writer, " {txt}").unwrap();
+    }
+    let view: &str = buffer.as_str();
+    let first: &str = &view[..3];
+    let last = Http::get("/end").wait;
+    format!("{first}: {buffer}{last}")
+}
+
+fn main() {
+    assert_eq!(block_on(collect(vec!["/a", "/b"])), "got: got /b /a/end");
+}
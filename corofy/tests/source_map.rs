@@ -0,0 +1,138 @@
+//! The source maps `corofy --map` writes, and `corofy_map` pointing a panic
+//! in the code we generated back at the line you wrote
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use corofy::SourceMap;
+
+const FETCH: &str = "\
+use crate::http::Http;
+
+coroutine fn fetch(n: usize) -> usize {
+    let txt = Http::get(\"/a\").wait;
+    match n {
+        0 => {
+            Http::get(\"/zero\").wait;
+        }
+        _ => assert!(txt.is_empty(), \"not empty\"),
+    }
+    n
+}
+";
+
+/// A fresh directory for `name`
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("source_map")
+        .join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The (1-based) line of the first line of generated code in `code` that
+/// contains `text`, not the copy of the original in a comment
+fn line_with(code: &str, text: &str) -> usize {
+    code.lines()
+        .position(|line| line.contains(text) && !line.trim_start().starts_with("//"))
+        .unwrap()
+        + 1
+}
+
+/// What `corofy_map` makes of `input` on stdin, run in `dir`
+fn corofy_map(dir: &Path, input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_corofy_map"))
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    String::from_utf8(child.wait_with_output().unwrap().stdout).unwrap()
+}
+
+#[test]
+fn round_trip() {
+    let text =
+        "# corofy source map: <generated line> <original line>\nsource main.rs\n1 1\n3 3\n40 9\n";
+    let map: SourceMap = text.parse().unwrap();
+    assert_eq!(map.source.as_deref(), Some("main.rs"));
+    assert_eq!(map.original_line(3), Some(3));
+    assert_eq!(map.original_line(40), Some(9));
+    // Lines we generated out of nothing, and lines past the end
+    assert_eq!(map.original_line(0), None);
+    assert_eq!(map.original_line(2), None);
+    assert_eq!(map.original_line(41), None);
+    assert_eq!(map.to_string(), text);
+
+    assert_eq!(
+        "1 1\n2 two\n".parse::<SourceMap>(),
+        Err(String::from(
+            "Line 2: expected `<generated line> <original line>`"
+        ))
+    );
+}
+
+#[test]
+fn panic_in_explicit_dest() {
+    let dir = temp_dir("panic_in_explicit_dest");
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::create_dir_all(dir.join("gen")).unwrap();
+    fs::write(dir.join("src/fetch.rs"), FETCH).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_corofy"))
+        .args(["--map", "src/fetch.rs", "gen/fetch.rs"])
+        .current_dir(&dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(dir.join("gen/fetch.rs.map").exists());
+
+    let code = fs::read_to_string(dir.join("gen/fetch.rs")).unwrap();
+    let assert = line_with(&code, "assert!(txt");
+    let arm = line_with(&code, "0 => {");
+    let panic = format!(
+        "thread 'main' panicked at gen/fetch.rs:{assert}:17:\nnot empty\n\
+         note: the match at gen/fetch.rs:{arm}\n\
+         unrelated src/lib.rs:3 and gen/fetch.rs:1000000\n"
+    );
+    // The source isn't next to the map, so it's named by its full path
+    let src = dir.join("src/fetch.rs").canonicalize().unwrap();
+    let src = src.display();
+    assert_eq!(
+        corofy_map(&dir, &panic),
+        format!(
+            "thread 'main' panicked at {src}:9:\nnot empty\n\
+             note: the match at {src}:6\n\
+             unrelated src/lib.rs:3 and gen/fetch.rs:1000000\n"
+        )
+    );
+}
+
+#[test]
+fn sibling() {
+    let dir = temp_dir("sibling");
+    fs::write(dir.join("fetch.rs"), FETCH).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_corofy"))
+        .args(["--map", "fetch.rs"])
+        .current_dir(&dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let code = fs::read_to_string(dir.join("fetch_corofied.rs")).unwrap();
+    let wait = line_with(&code, "Box::new(Http::get(\"/zero\"))");
+    let output = Command::new(env!("CARGO_BIN_EXE_corofy_map"))
+        .arg(format!("./fetch_corofied.rs:{wait}:13"))
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "./fetch.rs:7\n");
+}
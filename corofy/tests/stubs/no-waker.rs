@@ -0,0 +1,76 @@
+// What the code corofy writes for `--target no-waker` needs to build: the
+// trait it implements and the futures the tests wait on, and `block_on` for
+// the tests that run it.
+
+pub enum PollState<T> {
+    Ready(T),
+    NotReady,
+}
+
+pub trait Future {
+    type Output;
+    fn poll(&mut self) -> PollState<Self::Output>;
+}
+
+/// Polls `future` until it's ready
+pub fn block_on<F: Future>(mut future: F) -> F::Output {
+    loop {
+        if let PollState::Ready(value) = future.poll() {
+            return value;
+        }
+    }
+}
+
+pub mod future {
+    use super::{Future, PollState};
+
+    /// Resolves to `value` after `polls` polls
+    pub struct Delay<T> {
+        value: Option<T>,
+        polls: usize,
+    }
+
+    pub fn delay<T>(value: T, polls: usize) -> Delay<T> {
+        Delay {
+            value: Some(value),
+            polls,
+        }
+    }
+
+    impl<T> Future for Delay<T> {
+        type Output = T;
+
+        fn poll(&mut self) -> PollState<T> {
+            match self.polls {
+                0 => PollState::Ready(self.value.take().unwrap()),
+                _ => {
+                    self.polls -= 1;
+                    PollState::NotReady
+                }
+            }
+        }
+    }
+
+    /// Resolves to the length of what it borrows
+    pub struct Peek<'a>(pub &'a str);
+
+    impl Future for Peek<'_> {
+        type Output = usize;
+
+        fn poll(&mut self) -> PollState<usize> {
+            PollState::Ready(self.0.len())
+        }
+    }
+}
+
+pub mod http {
+    use super::future::{Delay, delay};
+
+    pub struct Http;
+
+    impl Http {
+        pub fn get(path: &str) -> Delay<String> {
+            delay(path.to_string(), 1)
+        }
+    }
+}
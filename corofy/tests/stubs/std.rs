@@ -0,0 +1,72 @@
+// What the code corofy writes for `--target std` needs to build: the
+// futures the tests wait on, and `block_on` for the tests that run it.
+
+/// Polls `future` until it's ready
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+    loop {
+        if let std::task::Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+            return value;
+        }
+    }
+}
+
+pub mod future {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Resolves to `value` after `polls` polls
+    pub struct Delay<T> {
+        value: Option<T>,
+        polls: usize,
+    }
+
+    pub fn delay<T>(value: T, polls: usize) -> Delay<T> {
+        Delay {
+            value: Some(value),
+            polls,
+        }
+    }
+
+    impl<T> Unpin for Delay<T> {}
+
+    impl<T> Future for Delay<T> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+            match self.polls {
+                0 => Poll::Ready(self.value.take().unwrap()),
+                _ => {
+                    self.polls -= 1;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    /// Resolves to the length of what it borrows
+    pub struct Peek<'a>(pub &'a str);
+
+    impl Future for Peek<'_> {
+        type Output = usize;
+
+        fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<usize> {
+            Poll::Ready(self.0.len())
+        }
+    }
+}
+
+pub mod http {
+    use super::future::{Delay, delay};
+
+    pub struct Http;
+
+    impl Http {
+        pub fn get(path: &str) -> Delay<String> {
+            delay(path.to_string(), 1)
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Two coroutines in one module, polled to completion without a runtime.
+//! `Http::get` doesn't talk to the delayserver here, the `Delay` it returns
+//! is ready the second time it's polled.
+
+use std::fmt::Write;
+
+pub trait Future {
+    type Output;
+    fn poll(&mut self) -> PollState<Self::Output>;
+}
+
+pub enum PollState<T> {
+    Ready(T),
+    NotReady,
+}
+
+struct Delay {
+    msg: String,
+    polled: bool,
+}
+
+struct Http;
+
+impl Http {
+    fn get(path: &str) -> impl Future<Output = String> + use<> {
+        Delay {
+            msg: path.to_string(),
+            polled: false,
+        }
+    }
+}
+
+impl Future for Delay {
+    type Output = String;
+
+    fn poll(&mut self) -> PollState<Self::Output> {
+        if self.polled {
+            PollState::Ready(self.msg.clone())
+        } else {
+            self.polled = true;
+            PollState::NotReady
+        }
+    }
+}
+
+#[corofy_macro::coroutine]
+fn request(i: usize) -> usize {
+    let path = format!("/{}/HelloWorld{i}", i * 1000);
+    let txt = Http::get(&path).wait;
+    println!("{txt}");
+    txt.len()
+}
+
+#[corofy_macro::coroutine]
+fn async_main() {
+    println!("Program starting");
+    let mut log: String = String::new();
+    let mut i: usize = 0;
+    while i < 3 {
+        let len: usize = request(i).wait;
+        writeln!(log, "request {i}: {len} bytes").unwrap();
+        i += 1;
+    }
+    log
+}
+
+fn main() {
+    let mut future = async_main();
+    let mut polls = 1;
+    let log = loop {
+        match future.poll() {
+            PollState::Ready(log) => break log,
+            PollState::NotReady => polls += 1,
+        }
+    };
+    print!("{log}");
+    println!("Done after {polls} polls");
+}
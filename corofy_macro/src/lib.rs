@@ -0,0 +1,156 @@
+//! `#[coroutine]` does what the `corofy` binary does, but in place:
+//!
+//! ```ignore
+//! #[corofy_macro::coroutine]
+//! fn async_main() {
+//!     let txt = Http::get("/600/HelloAsyncAwait").wait;
+//!     println!("{txt}");
+//! }
+//! ```
+//!
+//! expands to the `State0`/`Coroutine0` state machine corofy writes for
+//! `coroutine fn async_main() { .. }`, using the same parser and code
+//! generator. The state machine is the same to the character, what's around
+//! it isn't:
+//!
+//! - It goes inside `async_main` instead of after it, so the coroutines in a
+//!   module don't clash. Each of them is `State0`/`Coroutine0`.
+//! - There's no copy of the original in a comment, and no source map.
+//! - The futures it waits on are always boxed and it doesn't log
+//!   cancellation, there's no `--futures concrete` or `--log-cancel`.
+//!
+//! `coroutine` is also the name of an (unstable) attribute built into the
+//! compiler, so it has to be used with its path. A plain `#[coroutine]`
+//! after `use corofy_macro::coroutine` is ambiguous.
+//!
+//! The `Future` trait defaults to the one without a waker. Pass the target
+//! like on the command line to change it: `#[corofy_macro::coroutine(waker)]`
+//! or `#[corofy_macro::coroutine(std)]`.
+//!
+//! We go through the text of the function, so the generated code only
+//! knows the position of the attribute. A compile error in it points there.
+
+use corofy::{Target, check, generate_nested, parse};
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+#[proc_macro_attribute]
+pub fn coroutine(attr: TokenStream, item: TokenStream) -> TokenStream {
+    // `no-waker` comes in as `no - waker`
+    let attr_span = attr.clone().into_iter().next().map(|t| t.span());
+    let target: String = attr.to_string().split_whitespace().collect();
+    let target = match target.as_str() {
+        "" => Target::default(),
+        name => match name.parse::<Target>() {
+            Ok(target) => target,
+            Err(e) => return compile_error(&e, attr_span.unwrap_or_else(Span::call_site)),
+        },
+    };
+
+    // Attributes and the visibility stay in front of the function, corofy
+    // gets `coroutine fn ..`
+    let mut res = TokenStream::new();
+    let mut src = Source::default();
+    let mut tokens = item.into_iter();
+    for tok in tokens.by_ref() {
+        if matches!(&tok, TokenTree::Ident(ident) if ident.to_string() == "fn") {
+            src.text.push_str("coroutine ");
+            src.write(tok);
+            break;
+        }
+        res.extend([tok]);
+    }
+    tokens.for_each(|tok| src.write(tok));
+
+    let program = match parse(&src.text).and_then(|program| {
+        check(&program, target)?;
+        Ok(program)
+    }) {
+        Ok(program) => program,
+        Err(e) => {
+            let span = e
+                .span
+                .map_or_else(Span::call_site, |span| src.span_at(span.start));
+            return compile_error(&e.msg, span);
+        }
+    };
+    let code = generate_nested(&program.coroutines[0], target);
+    match code.parse::<TokenStream>() {
+        Ok(expanded) => res.extend(expanded),
+        Err(e) => {
+            return compile_error(
+                &format!("corofy generated code that doesn't parse: {e}"),
+                Span::call_site(),
+            );
+        }
+    }
+    res
+}
+
+/// The function as text, and where each token in it came from
+#[derive(Default)]
+struct Source {
+    text: String,
+    /// `(offset in text, span)` of each token, in order
+    spans: Vec<(usize, Span)>,
+}
+
+impl Source {
+    fn write(&mut self, tok: TokenTree) {
+        match tok {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::None => ("", ""),
+                };
+                self.push(open, group.span_open());
+                group.stream().into_iter().for_each(|tok| self.write(tok));
+                self.push(close, group.span_close());
+            }
+            TokenTree::Punct(punct) => {
+                self.push(&punct.as_char().to_string(), punct.span());
+                // `::`, `->` and `'a` stay together
+                if punct.spacing() == Spacing::Joint {
+                    self.text.pop();
+                }
+            }
+            tok => self.push(&tok.to_string(), tok.span()),
+        }
+    }
+
+    fn push(&mut self, text: &str, span: Span) {
+        self.spans.push((self.text.len(), span));
+        self.text.push_str(text);
+        self.text.push(' ');
+    }
+
+    /// The span of the token at `offset` in the text
+    fn span_at(&self, offset: usize) -> Span {
+        self.spans
+            .iter()
+            .take_while(|(start, _)| *start <= offset)
+            .last()
+            .map_or_else(Span::call_site, |(_, span)| *span)
+    }
+}
+
+/// `compile_error!("msg");` pointing at `span`
+fn compile_error(msg: &str, span: Span) -> TokenStream {
+    let mut lit = Literal::string(msg);
+    lit.set_span(span);
+    let mut args = Group::new(Delimiter::Parenthesis, TokenTree::Literal(lit).into());
+    args.set_span(span);
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    let mut semi = Punct::new(';', Spacing::Alone);
+    semi.set_span(span);
+    [
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(bang),
+        TokenTree::Group(args),
+        TokenTree::Punct(semi),
+    ]
+    .into_iter()
+    .collect()
+}
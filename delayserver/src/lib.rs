@@ -0,0 +1,266 @@
+//! The delay server as a library, so a test can start one of its own instead
+//! of relying on a `delayserver` someone launched on port 8080:
+//!
+//! ```no_run
+//! use delayserver::{Config, DelayServer};
+//!
+//! let server = DelayServer::start(Config::ephemeral()).unwrap();
+//! // `GET {url}/200/hello` answers `hello` after 200ms
+//! let url = server.url();
+//! // Dropping the handle stops the server
+//! drop(server);
+//! ```
+//!
+//! `GET {url}/metrics` says how many requests are in flight, how many there
+//! were at most and how long they took.
+
+use actix_web::{
+    body::BoxBody,
+    dev::{Server, ServerHandle},
+    get,
+    http::KeepAlive,
+    rt::{time::sleep, System},
+    web, App, HttpRequest, HttpResponse, HttpServer,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+mod log;
+mod metrics;
+mod options;
+mod reply;
+
+pub use log::Log;
+use metrics::{Logged, Metrics};
+pub use options::Options;
+use reply::Reply;
+
+/// What actix keeps connections alive for
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(5);
+
+/// How long `Handle::stop` waits for the requests in flight, what actix waits
+/// for its connections when it's stopped gracefully
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the server listens, how many threads it uses, how long it keeps a
+/// connection open and how it logs requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub host: String,
+    /// With 0 the system picks a free port
+    pub port: u16,
+    /// `None` leaves it to actix, which starts one per CPU core
+    pub workers: Option<usize>,
+    /// How long a connection waits for the next request once a response is
+    /// sent, unless the client asks to close it. Zero closes every
+    /// connection after one response.
+    pub keep_alive: Duration,
+    pub log: Log,
+}
+
+impl Default for Config {
+    /// `localhost:8080`, where the examples expect it
+    fn default() -> Self {
+        Config {
+            host: String::from("localhost"),
+            port: 8080,
+            workers: None,
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            log: Log::Text,
+        }
+    }
+}
+
+impl Config {
+    /// `127.0.0.1` on a port the system picks, with a single worker: what a
+    /// test that starts its own server wants
+    pub fn ephemeral() -> Self {
+        Config {
+            host: String::from("127.0.0.1"),
+            port: 0,
+            workers: Some(1),
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            log: Log::Text,
+        }
+    }
+}
+
+#[get("/{delay}/{message}")]
+async fn delay(
+    req: HttpRequest,
+    path: web::Path<(u64, String)>,
+    query: web::Query<HashMap<String, String>>,
+    metrics: web::Data<Metrics>,
+    log: web::Data<Log>,
+) -> HttpResponse<Logged<BoxBody>> {
+    let (delay_ms, message) = path.into_inner();
+    let mut exchange = metrics.receive(&req, delay_ms, &message, **log);
+    let text = **log == Log::Text;
+    let response = match Reply::parse(&query) {
+        Ok(reply) => {
+            if text {
+                println!("#{} - {delay_ms}ms: {message}{reply}", exchange.id);
+            }
+            sleep(Duration::from_millis(delay_ms)).await;
+            reply.respond(&req, message).await
+        }
+        Err(e) => {
+            if text {
+                println!("#{} - {e}", exchange.id);
+            }
+            HttpResponse::BadRequest().body(e)
+        }
+    };
+    exchange.status = Some(response.status().as_u16());
+    response.map_body(|_, body| Logged::new(body, exchange))
+}
+
+#[get("/metrics")]
+async fn serve_metrics(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.to_string())
+}
+
+/// The server for `config`, bound but not running until it's awaited, and
+/// the addresses it listens on. It has to be awaited on an actix runtime.
+pub fn bind(config: &Config) -> io::Result<(Server, Vec<SocketAddr>)> {
+    bind_with(config, true, web::Data::new(Metrics::default()))
+}
+
+/// `bind`, where `signals` says whether Ctrl-C stops the server. It
+/// shouldn't stop a process that only started one in the background.
+fn bind_with(
+    config: &Config,
+    signals: bool,
+    metrics: web::Data<Metrics>,
+) -> io::Result<(Server, Vec<SocketAddr>)> {
+    let log = web::Data::new(config.log);
+    let mut server = HttpServer::new(move || {
+        App::new()
+            .app_data(metrics.clone())
+            .app_data(log.clone())
+            .service(serve_metrics)
+            .service(delay)
+    })
+    .on_connect(reply::on_connect)
+    .bind((config.host.as_str(), config.port))?;
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+    server = match config.keep_alive.is_zero() {
+        true => server.keep_alive(KeepAlive::Disabled),
+        false => server.keep_alive(config.keep_alive),
+    };
+    if !signals {
+        server = server.disable_signals();
+    }
+    let addrs = server.addrs();
+    Ok((server.run(), addrs))
+}
+
+/// A delay server running in the background of the process that started it
+pub struct DelayServer;
+
+impl DelayServer {
+    /// Starts a server for `config` on a thread of its own. It runs until
+    /// the `Handle` is dropped.
+    pub fn start(config: Config) -> io::Result<Handle> {
+        let metrics = web::Data::new(Metrics::default());
+        let data = metrics.clone();
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name(String::from("delayserver"))
+            .spawn(move || {
+                System::new().block_on(async move {
+                    let (server, addrs) = match bind_with(&config, false, data) {
+                        Ok(bound) => bound,
+                        Err(e) => {
+                            let _ = tx.send(Err(e));
+                            return Ok(());
+                        }
+                    };
+                    let _ = tx.send(Ok((server.handle(), addrs)));
+                    server.await
+                })
+            })?;
+
+        // The thread only stops before it sends something if it panics
+        let (server, addrs) = match rx.recv() {
+            Ok(bound) => bound?,
+            Err(_) => return Err(io::Error::other("the delayserver thread panicked")),
+        };
+        Ok(Handle {
+            addrs,
+            server,
+            metrics,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// A running `DelayServer`. Dropping it stops the server without waiting
+/// for the requests it's still delaying, `stop` waits for them.
+pub struct Handle {
+    addrs: Vec<SocketAddr>,
+    server: ServerHandle,
+    metrics: web::Data<Metrics>,
+    thread: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl Handle {
+    /// The address the server listens on, the first one if the host
+    /// resolved to several
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// `http://127.0.0.1:41605`, without a trailing `/`
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr())
+    }
+
+    /// Stops the server once it answered the requests it's handling, or
+    /// gave them 30 seconds
+    pub fn stop(mut self) -> io::Result<()> {
+        self.shut_down(true)
+    }
+
+    fn shut_down(&mut self, graceful: bool) -> io::Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        if graceful {
+            // Actix can stop a worker along with its connections as soon as
+            // it stops accepting new ones, so the wait for the requests in
+            // flight is up to us. The commands are sent right away, the
+            // futures only wait for the server to get them.
+            drop(self.server.pause());
+            let start = Instant::now();
+            while self.metrics.in_flight() > 0 && start.elapsed() < SHUTDOWN_TIMEOUT {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        drop(self.server.stop(graceful));
+        match thread.join() {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::other("the delayserver thread panicked")),
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let _ = self.shut_down(false);
+    }
+}
@@ -0,0 +1,112 @@
+//! What a server prints about the requests it gets. By default that's a line
+//! for a person as each one comes in:
+//!
+//! ```text
+//! #1 - 200ms: hello (3 chunks, every 100ms)
+//! ```
+//!
+//! With `Log::Json` it's a JSON object on a line of its own once a request is
+//! over, for a test or a script to read:
+//!
+//! ```text
+//! {"id":1,"client":"127.0.0.1:50514","delay_ms":200,"message":"hello","status":200,"bytes":5,"arrived_ms":1760781600000,"completed_ms":1760781600501}
+//! ```
+//!
+//! The times are milliseconds since the Unix epoch. `status` is `null` if
+//! there never was a response, `bytes` counts the body without the headers.
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Log {
+    /// `#1 - 200ms: hello` when a request comes in
+    #[default]
+    Text,
+    /// A JSON line when a request is over
+    Json,
+}
+
+impl fmt::Display for Log {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Log::Text => f.write_str("text"),
+            Log::Json => f.write_str("json"),
+        }
+    }
+}
+
+impl FromStr for Log {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Log::Text),
+            "json" => Ok(Log::Json),
+            _ => Err(String::from("expected `text` or `json`")),
+        }
+    }
+}
+
+/// A request that's over, as `Log::Json` prints it
+pub(crate) struct Record<'a> {
+    pub(crate) id: usize,
+    pub(crate) client: Option<SocketAddr>,
+    pub(crate) delay_ms: u64,
+    pub(crate) message: &'a str,
+    pub(crate) status: Option<u16>,
+    pub(crate) bytes: u64,
+    pub(crate) arrived: SystemTime,
+    pub(crate) completed: SystemTime,
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{\"id\":{},\"client\":", self.id)?;
+        match self.client {
+            Some(client) => write!(f, "\"{client}\"")?,
+            None => f.write_str("null")?,
+        }
+        write!(f, ",\"delay_ms\":{},\"message\":", self.delay_ms)?;
+        write_string(f, self.message)?;
+        f.write_str(",\"status\":")?;
+        match self.status {
+            Some(status) => write!(f, "{status}")?,
+            None => f.write_str("null")?,
+        }
+        write!(
+            f,
+            ",\"bytes\":{},\"arrived_ms\":{},\"completed_ms\":{}}}",
+            self.bytes,
+            millis(self.arrived),
+            millis(self.completed)
+        )
+    }
+}
+
+/// `s` as a JSON string, quotes and all
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+fn millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
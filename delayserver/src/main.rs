@@ -1,48 +1,74 @@
-use actix_web::{get, rt::time::sleep, web, App, HttpServer, Responder};
-use std::{
-    env,
-    sync::atomic::{AtomicUsize, Ordering},
-    time::Duration,
-};
+use delayserver::{bind, Options};
+use std::{env, io, process::ExitCode};
 
 const EXPLANATION: &str =
 "USAGE:
 Delay server works by issuing an HTTP GET request in the format:
-http://localhost:8080/[delay in ms]/[URL-encoded message]
+http://[host]:[port]/[delay in ms]/[URL-encoded message]
 
-If an argument is passed in when delayserver is started, that
-argument will be used as the URL instead of 'localhost'.
+The host is 'localhost' and the port 8080 unless you pick others, see
+`delayserver --help`.
 
 Upon receiving a request, it immediately reports the following to the console:
 
 {Message #} - {delay in ms}: {message}
 
 The server then delays the response for the requested time and echoes the message back to the caller.
+With `--log json` it prints a JSON line for each request once it's answered instead.
+
+GET http://[host]:[port]/metrics for the number of requests in flight, the most there were at
+once, the number answered and how long they took.
 
 REQUESTS:
 --------
 ";
 
-static COUNTER: AtomicUsize = AtomicUsize::new(1);
+const USAGE: &str = "\
+Usage: delayserver [options] [host]
 
-#[get("/{delay}/{message}")]
-async fn delay(path: web::Path<(u64, String)>) -> impl Responder {
-    let (delay_ms, message) = path.into_inner();
-    let count = COUNTER.fetch_add(1, Ordering::SeqCst);
-    println!("#{count} - {delay_ms}ms: {message}");
-    sleep(Duration::from_millis(delay_ms)).await;
-    message
-}
+Options:
+  --host <host>    The address to listen on, `localhost` if there's none
+  --port <port>    The port to listen on, 8080 if there's none. With 0 the
+                   system picks a free one.
+  --workers <n>    How many worker threads handle requests, one per CPU core
+                   if there's no number
+  --keep-alive <s> How many seconds a connection waits for another request,
+                   5 if there's no number. With 0 every connection is closed
+                   after one response.
+  --log <format>   `text` prints a line as each request comes in, `json` a
+                   JSON line once it's answered. `text` if there's none.
+  --quiet          Don't print the explanation at startup
+  --help           Print this and exit
+
+Every option can be set in an environment variable as well, the option wins
+if there are both: DELAYSERVER_HOST, DELAYSERVER_PORT, DELAYSERVER_WORKERS,
+DELAYSERVER_KEEP_ALIVE, DELAYSERVER_LOG and DELAYSERVER_QUIET (`1` or `true`).
+
+The address the server listens on is printed once it does, as
+`Listening on http://127.0.0.1:8080`.";
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let url = env::args()
-        .nth(1)
-        .unwrap_or_else(|| String::from("localhost"));
-
-    println!("{EXPLANATION}");
-    HttpServer::new(|| App::new().service(delay))
-        .bind((url, 8080))?
-        .run()
-        .await
+async fn main() -> io::Result<ExitCode> {
+    let options = match Options::parse(env::args().skip(1), |name| env::var(name).ok()) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            return Ok(ExitCode::SUCCESS);
+        }
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+
+    let (server, addrs) = bind(&options.config)?;
+    // With port 0 this is the only way to know which one we got
+    for addr in addrs {
+        println!("Listening on http://{addr}");
+    }
+    if !options.quiet {
+        println!("{EXPLANATION}");
+    }
+    server.await?;
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,215 @@
+//! What a server has been up to: every request is an `Exchange` from the
+//! moment it comes in until the last byte of its response is sent, or actix
+//! gives up on it. One that hangs stays in flight until the server stops.
+//! The counts and the latencies of all of them are served on `/metrics`, in
+//! the text format Prometheus scrapes:
+//!
+//! - `delayserver_requests_in_flight`: received and not answered yet
+//! - `delayserver_requests_in_flight_max`: the most there were at once, which
+//!   is what a test asserts on to know its requests overlapped
+//! - `delayserver_requests_total`: answered, or given up on
+//! - `delayserver_request_duration_seconds`: a histogram of how long that
+//!   took, the delay included
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    web::Bytes,
+    HttpRequest,
+};
+use std::{
+    fmt,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::log::{Log, Record};
+
+/// The upper bounds of the histogram's buckets in seconds, the last one
+/// `+Inf` is left out
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// Numbers the requests, starting at 1
+    received: AtomicUsize,
+    stats: Mutex<Stats>,
+}
+
+#[derive(Default)]
+struct Stats {
+    in_flight: usize,
+    max_in_flight: usize,
+    served: u64,
+    /// How many took at most as long as each of `BUCKETS`, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    sum: Duration,
+}
+
+impl Metrics {
+    /// A request that just came in, to be logged as `log` says once it's over
+    pub(crate) fn receive(
+        self: &Arc<Self>,
+        req: &HttpRequest,
+        delay_ms: u64,
+        message: &str,
+        log: Log,
+    ) -> Exchange {
+        let id = self.received.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut stats = self.stats.lock().unwrap();
+        stats.in_flight += 1;
+        stats.max_in_flight = stats.max_in_flight.max(stats.in_flight);
+        drop(stats);
+        Exchange {
+            metrics: Arc::clone(self),
+            log,
+            id,
+            client: req.peer_addr(),
+            delay_ms,
+            message: message.to_string(),
+            status: None,
+            bytes: 0,
+            started: Instant::now(),
+            arrived: SystemTime::now(),
+        }
+    }
+
+    /// Requests received and not answered yet
+    pub(crate) fn in_flight(&self) -> usize {
+        self.stats.lock().unwrap().in_flight
+    }
+
+    fn finish(&self, took: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.in_flight -= 1;
+        stats.served += 1;
+        stats.sum += took;
+        let seconds = took.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            stats.buckets[bucket] += 1;
+        }
+    }
+}
+
+/// `/metrics`
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.stats.lock().unwrap();
+        help(
+            f,
+            "requests_in_flight",
+            "gauge",
+            "Requests received and not answered yet",
+        )?;
+        writeln!(f, "delayserver_requests_in_flight {}", stats.in_flight)?;
+        help(
+            f,
+            "requests_in_flight_max",
+            "gauge",
+            "The most requests in flight at once",
+        )?;
+        writeln!(
+            f,
+            "delayserver_requests_in_flight_max {}",
+            stats.max_in_flight
+        )?;
+        help(f, "requests_total", "counter", "Requests answered")?;
+        writeln!(f, "delayserver_requests_total {}", stats.served)?;
+
+        let name = "delayserver_request_duration_seconds";
+        help(
+            f,
+            "request_duration_seconds",
+            "histogram",
+            "From receiving a request to sending the last byte of its response",
+        )?;
+        let mut count = 0;
+        for (bound, n) in BUCKETS.iter().zip(stats.buckets) {
+            count += n;
+            writeln!(f, "{name}_bucket{{le=\"{bound}\"}} {count}")?;
+        }
+        writeln!(f, "{name}_bucket{{le=\"+Inf\"}} {}", stats.served)?;
+        writeln!(f, "{name}_sum {}", stats.sum.as_secs_f64())?;
+        writeln!(f, "{name}_count {}", stats.served)
+    }
+}
+
+/// The `# HELP` and `# TYPE` lines that come before a metric
+fn help(f: &mut fmt::Formatter<'_>, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(f, "# HELP delayserver_{name} {help}")?;
+    writeln!(f, "# TYPE delayserver_{name} {kind}")
+}
+
+/// A request from the moment it comes in. It's over when it's dropped, which
+/// `Logged` does once the response's body is sent.
+pub(crate) struct Exchange {
+    metrics: Arc<Metrics>,
+    log: Log,
+    pub(crate) id: usize,
+    client: Option<SocketAddr>,
+    delay_ms: u64,
+    message: String,
+    /// `None` until there's a response, and for good if there never is one
+    pub(crate) status: Option<u16>,
+    /// Of the body, the headers aren't counted
+    bytes: u64,
+    started: Instant,
+    arrived: SystemTime,
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        self.metrics.finish(self.started.elapsed());
+        if self.log == Log::Json {
+            let record = Record {
+                id: self.id,
+                client: self.client,
+                delay_ms: self.delay_ms,
+                message: &self.message,
+                status: self.status,
+                bytes: self.bytes,
+                arrived: self.arrived,
+                completed: SystemTime::now(),
+            };
+            println!("{record}");
+        }
+    }
+}
+
+/// A response's body that counts what it sends for the `Exchange` it ends
+pub(crate) struct Logged<B> {
+    body: B,
+    exchange: Exchange,
+}
+
+impl<B> Logged<B> {
+    pub(crate) fn new(body: B, exchange: Exchange) -> Self {
+        Logged { body, exchange }
+    }
+}
+
+impl<B: MessageBody + Unpin> MessageBody for Logged<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let next = Pin::new(&mut self.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(bytes))) = &next {
+            self.exchange.bytes += bytes.len() as u64;
+        }
+        next
+    }
+}
@@ -0,0 +1,101 @@
+//! The command line of the `delayserver` binary, in the library so it can be
+//! tested without starting one: `--host`, `--port`, `--workers`,
+//! `--keep-alive`, `--log` and `--quiet`, each of which can be set in a
+//! `DELAYSERVER_*` environment variable too
+
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::Config;
+
+/// What the command line and the environment ask for
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub config: Config,
+    /// Don't print the explanation at startup
+    pub quiet: bool,
+}
+
+impl Options {
+    /// The settings in the `DELAYSERVER_*` environment variables, which `var`
+    /// looks up, then the ones in `args`. `Ok(None)` if we're asked for
+    /// `--help`.
+    pub fn parse(
+        args: impl IntoIterator<Item = String>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Options>, String> {
+        let mut options = Options::default();
+        for option in ["host", "port", "workers", "keep-alive", "log", "quiet"] {
+            let name = format!("DELAYSERVER_{}", option.to_uppercase().replace('-', "_"));
+            let Some(value) = var(&name) else {
+                continue;
+            };
+            match option {
+                "quiet" => options.quiet = parse_flag(&name, &value)?,
+                _ => options.set(option, &name, value)?,
+            }
+        }
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(option) = arg.strip_prefix("--") else {
+                // The host is all there used to be, without an option
+                options.config.host = arg;
+                continue;
+            };
+            let (option, value) = match option.split_once('=') {
+                Some((option, value)) => (option, Some(value.to_string())),
+                None => (option, None),
+            };
+            match option {
+                "help" => return Ok(None),
+                "quiet" => options.quiet = true,
+                "host" | "port" | "workers" | "keep-alive" | "log" => {
+                    let value = value
+                        .or_else(|| args.next())
+                        .ok_or_else(|| format!("`--{option}` needs a value"))?;
+                    options.set(option, &format!("--{option}"), value)?;
+                }
+                _ => return Err(format!("Unknown option `{arg}`")),
+            }
+        }
+        Ok(Some(options))
+    }
+
+    /// Sets `option` to `value`, which comes from `source`
+    fn set(&mut self, option: &str, source: &str, value: String) -> Result<(), String> {
+        let config = &mut self.config;
+        match option {
+            "host" => config.host = value,
+            "port" => config.port = value.parse().map_err(|e| invalid(source, &value, e))?,
+            "workers" => match value.parse() {
+                Ok(0) => return Err(format!("`{source}` has to be at least 1")),
+                Ok(workers) => config.workers = Some(workers),
+                Err(e) => return Err(invalid(source, &value, e)),
+            },
+            "keep-alive" => {
+                config.keep_alive = value
+                    .parse()
+                    .map(Duration::from_secs)
+                    .map_err(|e| invalid(source, &value, e))?
+            }
+            "log" => config.log = value.parse().map_err(|e| invalid(source, &value, e))?,
+            _ => unreachable!("`{option}` isn't a setting"),
+        }
+        Ok(())
+    }
+}
+
+/// `DELAYSERVER_QUIET=1`
+fn parse_flag(name: &str, value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" => Ok(true),
+        "" | "0" | "false" | "no" => Ok(false),
+        _ => Err(invalid(name, value, "expected `1` or `0`")),
+    }
+}
+
+/// Why `value` from `source` isn't a setting we can use
+fn invalid(source: &str, value: &str, e: impl Display) -> String {
+    format!("Invalid `{source}` `{value}`: {e}")
+}
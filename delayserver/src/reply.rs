@@ -0,0 +1,221 @@
+//! How a request asks to be answered, in its query string. Some of it is
+//! there to exercise the parsing in a client:
+//!
+//! - `?chunks=3` sends the message with `Transfer-Encoding: chunked`, in that
+//!   many chunks with `interval` ms between them (100 if there's none)
+//! - `?close` closes the connection after the response even if the client
+//!   wants to keep it alive
+//! - `?status=503` answers with that status, the body is still the message
+//!
+//! And some of it at a client's error handling, the response misbehaves on
+//! purpose:
+//!
+//! - `?fault=truncate` sends half of the message and closes the connection
+//! - `?fault=reset` sends half of the message and resets the connection
+//! - `?fault=drip` sends the message a byte at a time, every `interval` ms.
+//!   In chunks if `?chunks` is there too, a byte per chunk.
+//! - `?fault=hang` never answers at all
+//!
+//! The delay in the path comes first either way.
+
+use actix_web::{
+    body::SizedStream,
+    http::{header::ContentType, StatusCode},
+    rt::{net::TcpStream, task::yield_now, time::sleep},
+    web::Bytes,
+    HttpRequest, HttpResponse,
+};
+use futures_util::stream::{self, StreamExt};
+use socket2::{SockRef, Socket};
+use std::{any::Any, collections::HashMap, fmt, io, time::Duration};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fault {
+    Truncate,
+    Reset,
+    /// One byte at a time
+    Drip,
+    Hang,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Fault::Truncate => "truncate",
+            Fault::Reset => "reset",
+            Fault::Drip => "drip",
+            Fault::Hang => "hang",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Reply {
+    status: StatusCode,
+    fault: Option<Fault>,
+    /// How many chunks to send the message in, `None` to send it with a
+    /// `Content-Length`
+    chunks: Option<usize>,
+    /// Between two chunks, or two bytes when it drips
+    interval: Duration,
+    close: bool,
+}
+
+impl Reply {
+    /// What the query string of a request asks for
+    pub(crate) fn parse(query: &HashMap<String, String>) -> Result<Reply, String> {
+        let status = match query.get("status") {
+            Some(status) => status
+                .parse()
+                .ok()
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .ok_or_else(|| format!("Invalid status `{status}`"))?,
+            None => StatusCode::OK,
+        };
+        let chunks = match query.get("chunks") {
+            Some(chunks) => match chunks.parse() {
+                Ok(0) | Err(_) => return Err(format!("Invalid number of chunks `{chunks}`")),
+                Ok(chunks) => Some(chunks),
+            },
+            None => None,
+        };
+        let interval = match query.get("interval") {
+            Some(ms) => ms
+                .parse()
+                .map(Duration::from_millis)
+                .map_err(|e| format!("Invalid interval `{ms}`: {e}"))?,
+            None => DEFAULT_INTERVAL,
+        };
+        let fault = match query.get("fault").map(String::as_str) {
+            None => None,
+            Some("truncate") => Some(Fault::Truncate),
+            Some("reset") => Some(Fault::Reset),
+            Some("drip") => Some(Fault::Drip),
+            Some("hang") => Some(Fault::Hang),
+            Some(fault) => {
+                return Err(format!(
+                    "Unknown fault `{fault}`, expected `truncate`, `reset`, `drip` or `hang`"
+                ))
+            }
+        };
+        Ok(Reply {
+            status,
+            fault,
+            chunks,
+            interval,
+            close: query.contains_key("close"),
+        })
+    }
+
+    /// `message` answered the way the request asked for
+    pub(crate) async fn respond(self, req: &HttpRequest, message: String) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        response.insert_header(ContentType::plaintext());
+        if self.close {
+            response.force_close();
+        }
+        let len = message.len() as u64;
+        let mut message = Bytes::from(message);
+        match self.fault {
+            None | Some(Fault::Drip) => (),
+            Some(Fault::Truncate) => message.truncate(message.len() / 2),
+            Some(Fault::Reset) => {
+                // A socket that lingers for no time at all resets the
+                // connection when it's closed, instead of finishing it
+                if let Some(Connection(socket)) = req.conn_data::<Connection>() {
+                    let _ = socket.set_linger(Some(Duration::ZERO));
+                }
+                message.truncate(message.len() / 2);
+            }
+            Some(Fault::Hang) => return std::future::pending().await,
+        }
+
+        let pieces = match (self.fault, self.chunks) {
+            (Some(Fault::Drip), _) => {
+                let bytes = message.len();
+                split(message, bytes)
+            }
+            (_, Some(chunks)) => split(message, chunks),
+            // All at once, unless it's cut off
+            (None, None) => return response.body(message),
+            (_, None) => vec![message],
+        };
+        let interval = self.interval;
+        let body = stream::iter(pieces)
+            .enumerate()
+            .then(move |(i, piece)| async move {
+                if i > 0 {
+                    sleep(interval).await;
+                }
+                Ok(piece)
+            });
+        let cut_off = matches!(self.fault, Some(Fault::Truncate | Fault::Reset));
+        let body = body.chain(stream::iter(cut_off.then_some(())).then(|()| cut_off_error()));
+        match self.chunks {
+            Some(_) => response.streaming(body),
+            None => response.body(SizedStream::new(len, body)),
+        }
+    }
+}
+
+/// What the log says about a reply, nothing if it's a plain one
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut notes = vec![];
+        if self.status != StatusCode::OK {
+            notes.push(format!("status {}", self.status.as_u16()));
+        }
+        if let Some(chunks) = self.chunks {
+            notes.push(format!("{chunks} chunks"));
+        }
+        if let Some(fault) = self.fault {
+            notes.push(fault.to_string());
+        }
+        if self.chunks.is_some() || self.fault == Some(Fault::Drip) {
+            notes.push(format!("every {}ms", self.interval.as_millis()));
+        }
+        if self.close {
+            notes.push(String::from("close"));
+        }
+        match notes.is_empty() {
+            true => Ok(()),
+            false => write!(f, " ({})", notes.join(", ")),
+        }
+    }
+}
+
+/// `message` in `n` pieces as even as they get, without empty ones unless
+/// the message is empty
+fn split(mut message: Bytes, n: usize) -> Vec<Bytes> {
+    let n = n.clamp(1, message.len().max(1));
+    let size = message.len() / n;
+    let mut pieces: Vec<Bytes> = (1..n).map(|_| message.split_to(size)).collect();
+    pieces.push(message);
+    pieces
+}
+
+/// Ends a body that's cut off: actix drops the connection when the body
+/// fails
+async fn cut_off_error() -> io::Result<Bytes> {
+    // Actix only writes what it has once the body has nothing more for now,
+    // an error before that would throw away what came before it as well
+    yield_now().await;
+    Err(io::Error::other("the response is cut off on purpose"))
+}
+
+/// The connection a request came in on. We only need it to reset it, which
+/// is up to the socket when it's closed.
+pub(crate) struct Connection(Socket);
+
+/// Keeps a handle to every connection, for `HttpServer::on_connect`
+pub(crate) fn on_connect(conn: &dyn Any, data: &mut actix_web::dev::Extensions) {
+    let Some(stream) = conn.downcast_ref::<TcpStream>() else {
+        return;
+    };
+    if let Ok(socket) = SockRef::from(stream).try_clone() {
+        data.insert(Connection(socket));
+    }
+}
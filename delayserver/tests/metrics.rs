@@ -0,0 +1,53 @@
+//! What `/metrics` says about the requests a server answered, and how many
+//! of them it had in flight at once
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+
+use delayserver::{Config, DelayServer};
+
+/// The body of the response to `GET {path}`
+fn get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.split_once("\r\n\r\n").unwrap().1.to_string()
+}
+
+/// The value of the `name` line in `metrics`
+fn value<'a>(metrics: &'a str, name: &str) -> &'a str {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no `{name}` in\n{metrics}"))
+}
+
+#[test]
+fn overlapping_requests() {
+    let server = DelayServer::start(Config::ephemeral()).unwrap();
+    let addr = server.addr();
+    let requests: Vec<_> = (0..3)
+        .map(|i| thread::spawn(move || get(addr, &format!("/300/{i}"))))
+        .collect();
+    for (i, request) in requests.into_iter().enumerate() {
+        assert_eq!(request.join().unwrap(), i.to_string());
+    }
+    get(addr, "/0/quick");
+
+    let metrics = get(addr, "/metrics");
+    assert_eq!(value(&metrics, "delayserver_requests_in_flight"), "0");
+    assert_eq!(value(&metrics, "delayserver_requests_in_flight_max"), "3");
+    assert_eq!(value(&metrics, "delayserver_requests_total"), "4");
+    let bucket = |le| format!("delayserver_request_duration_seconds_bucket{{le=\"{le}\"}}");
+    assert_eq!(value(&metrics, &bucket("0.1")), "1");
+    assert_eq!(value(&metrics, &bucket("0.25")), "1");
+    assert_eq!(value(&metrics, &bucket("0.5")), "4");
+    assert_eq!(value(&metrics, &bucket("+Inf")), "4");
+    assert_eq!(
+        value(&metrics, "delayserver_request_duration_seconds_count"),
+        "4"
+    );
+}
@@ -0,0 +1,112 @@
+//! The command line and the `DELAYSERVER_*` environment variables, without
+//! the environment of the test process getting in the way
+
+use std::time::Duration;
+
+use delayserver::{Config, Log, Options};
+
+/// `args` parsed with only `vars` in the environment
+fn parse(args: &[&str], vars: &[(&str, &str)]) -> Result<Option<Options>, String> {
+    let args = args.iter().map(|arg| arg.to_string());
+    Options::parse(args, |name| {
+        vars.iter()
+            .find(|(var, _)| *var == name)
+            .map(|(_, value)| value.to_string())
+    })
+}
+
+#[test]
+fn defaults() {
+    assert_eq!(parse(&[], &[]), Ok(Some(Options::default())));
+    assert_eq!(parse(&["--help"], &[]), Ok(None));
+}
+
+#[test]
+fn options_and_variables() {
+    let options = parse(
+        &[
+            "--port",
+            "9000",
+            "--workers=2",
+            "--keep-alive",
+            "0",
+            "--quiet",
+        ],
+        &[("DELAYSERVER_HOST", "0.0.0.0"), ("DELAYSERVER_LOG", "json")],
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        options,
+        Options {
+            config: Config {
+                host: String::from("0.0.0.0"),
+                port: 9000,
+                workers: Some(2),
+                keep_alive: Duration::ZERO,
+                log: Log::Json,
+            },
+            quiet: true,
+        }
+    );
+
+    // The host can come without `--host`
+    let options = parse(&["127.0.0.1"], &[]).unwrap().unwrap();
+    assert_eq!(options.config.host, "127.0.0.1");
+}
+
+#[test]
+fn options_win_over_variables() {
+    let vars = [
+        ("DELAYSERVER_HOST", "0.0.0.0"),
+        ("DELAYSERVER_PORT", "9000"),
+        ("DELAYSERVER_WORKERS", "4"),
+        ("DELAYSERVER_QUIET", "1"),
+    ];
+    let options = parse(&["--port=9001", "--workers", "1", "example.com"], &vars)
+        .unwrap()
+        .unwrap();
+    assert_eq!(options.config.host, "example.com");
+    assert_eq!(options.config.port, 9001);
+    assert_eq!(options.config.workers, Some(1));
+    assert!(options.quiet);
+
+    // A variable that's wrong is an error even if the option overrides it
+    let vars = [("DELAYSERVER_PORT", "http")];
+    assert!(parse(&["--port", "9000"], &vars).is_err());
+}
+
+#[test]
+fn invalid_values() {
+    let error = |args: &[&str], vars: &[(&str, &str)]| parse(args, vars).unwrap_err();
+    assert_eq!(
+        error(&["--port", "eighty"], &[]),
+        "Invalid `--port` `eighty`: invalid digit found in string"
+    );
+    assert_eq!(
+        error(&[], &[("DELAYSERVER_PORT", "70000")]),
+        "Invalid `DELAYSERVER_PORT` `70000`: number too large to fit in target type"
+    );
+    assert_eq!(
+        error(&["--log", "xml"], &[]),
+        "Invalid `--log` `xml`: expected `text` or `json`"
+    );
+    assert_eq!(
+        error(&[], &[("DELAYSERVER_QUIET", "maybe")]),
+        "Invalid `DELAYSERVER_QUIET` `maybe`: expected `1` or `0`"
+    );
+    assert_eq!(error(&["--port"], &[]), "`--port` needs a value");
+    assert_eq!(error(&["--verbose"], &[]), "Unknown option `--verbose`");
+}
+
+#[test]
+fn no_workers() {
+    assert_eq!(
+        parse(&["--workers", "0"], &[]),
+        Err(String::from("`--workers` has to be at least 1"))
+    );
+    assert_eq!(
+        parse(&[], &[("DELAYSERVER_WORKERS", "0")]),
+        Err(String::from("`DELAYSERVER_WORKERS` has to be at least 1"))
+    );
+}
@@ -0,0 +1,140 @@
+//! The responses the query string asks for, in chunks, on a connection that's
+//! kept alive or broken on purpose, as a client reading them sees them
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use delayserver::{Config, DelayServer};
+
+/// Sends `GET {path}` and reads until the server closes the connection. A
+/// server that doesn't answer is given a second.
+fn request(addr: SocketAddr, path: &str) -> (Vec<u8>, io::Result<()>) {
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+    send(addr, &request)
+}
+
+/// Sends `requests` on one connection and reads until the server closes it
+fn send(addr: SocketAddr, requests: &str) -> (Vec<u8>, io::Result<()>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    stream.write_all(requests.as_bytes()).unwrap();
+    let mut response = vec![];
+    let res = stream.read_to_end(&mut response).map(|_| ());
+    (response, res)
+}
+
+fn body(response: &[u8]) -> &str {
+    let response = std::str::from_utf8(response).unwrap();
+    response.split_once("\r\n\r\n").unwrap().1
+}
+
+#[test]
+fn status() {
+    let server = DelayServer::start(Config::ephemeral()).unwrap();
+    let (response, res) = request(server.addr(), "/0/down?status=503");
+    res.unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 503 Service Unavailable"));
+    assert_eq!(body(&response), "down");
+}
+
+#[test]
+fn chunks() {
+    let server = DelayServer::start(Config::ephemeral()).unwrap();
+    let start = Instant::now();
+    let (response, res) = request(server.addr(), "/0/helloworld?chunks=3&interval=50");
+    res.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    let text = String::from_utf8_lossy(&response);
+    assert!(text.contains("transfer-encoding: chunked\r\n"), "{text}");
+    assert_eq!(
+        body(&response),
+        "3\r\nhel\r\n3\r\nlow\r\n4\r\norld\r\n0\r\n\r\n"
+    );
+}
+
+#[test]
+fn keep_alive() {
+    let server = DelayServer::start(Config::ephemeral()).unwrap();
+    let addr = server.addr();
+    let requests = format!(
+        "GET /0/one HTTP/1.1\r\nHost: {addr}\r\n\r\n\
+         GET /0/two?chunks=2 HTTP/1.1\r\nHost: {addr}\r\n\r\n\
+         GET /0/three?close HTTP/1.1\r\nHost: {addr}\r\n\r\n"
+    );
+    // The server closing the connection is what ends the read in time
+    let (response, res) = send(addr, &requests);
+    res.unwrap();
+    let text = String::from_utf8_lossy(&response);
+    let bodies: Vec<&str> = text
+        .split("HTTP/1.1 200 OK")
+        .skip(1)
+        .map(|response| response.split_once("\r\n\r\n").unwrap().1)
+        .collect();
+    assert_eq!(bodies, ["one", "1\r\nt\r\n2\r\nwo\r\n0\r\n\r\n", "three"]);
+
+    // Without keep-alive the server closes a connection the client would
+    // keep open
+    let config = Config {
+        keep_alive: Duration::ZERO,
+        ..Config::ephemeral()
+    };
+    let server = DelayServer::start(config).unwrap();
+    let addr = server.addr();
+    let (response, res) = send(
+        addr,
+        &format!("GET /0/one HTTP/1.1\r\nHost: {addr}\r\n\r\n"),
+    );
+    res.unwrap();
+    assert!(String::from_utf8_lossy(&response).ends_with("\r\n\r\none"));
+}
+
+#[test]
+fn truncate() {
+    let server = DelayServer::start(Config::ephemeral()).unwrap();
+    let (response, res) = request(server.addr(), "/0/helloworld?fault=truncate");
+    res.unwrap();
+    let text = String::from_utf8_lossy(&response);
+    assert!(text.contains("content-length: 10\r\n"), "{text}");
+    assert_eq!(body(&response), "hello");
+}
+
+#[test]
+fn reset() {
+    let server = DelayServer::start(Config::ephemeral()).unwrap();
+    let (_, res) = request(server.addr(), "/0/helloworld?fault=reset");
+    assert_eq!(res.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn drip() {
+    let server = DelayServer::start(Config::ephemeral()).unwrap();
+    let start = Instant::now();
+    let (response, res) = request(server.addr(), "/0/abcd?fault=drip&interval=50");
+    res.unwrap();
+    // Three intervals between four bytes
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert_eq!(body(&response), "abcd");
+}
+
+#[test]
+fn hang() {
+    let server = DelayServer::start(Config::ephemeral()).unwrap();
+    let (response, res) = request(server.addr(), "/0/never?fault=hang");
+    assert!(response.is_empty());
+    let kind = res.unwrap_err().kind();
+    assert!(
+        matches!(kind, io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut),
+        "{kind:?}"
+    );
+}
+
+#[test]
+fn unknown_fault() {
+    let server = DelayServer::start(Config::ephemeral()).unwrap();
+    let (response, _) = request(server.addr(), "/0/x?fault=explode");
+    assert!(response.starts_with(b"HTTP/1.1 400 Bad Request"));
+    assert!(body(&response).starts_with("Unknown fault `explode`"));
+}
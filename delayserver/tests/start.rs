@@ -0,0 +1,59 @@
+//! Starts servers in the test process, the way the tests of the examples
+//! can, and talks to them over plain TCP like the examples' `Http::get`.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use delayserver::{Config, DelayServer};
+
+/// The response to `GET /{delay}/{message}`, headers and all
+fn get(addr: SocketAddr, delay: u64, message: &str) -> String {
+    get_path(addr, &format!("/{delay}/{message}"))
+}
+
+/// The response to `GET {path}`
+fn get_path(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn servers_side_by_side() {
+    let first = DelayServer::start(Config::ephemeral()).unwrap();
+    let second = DelayServer::start(Config::ephemeral()).unwrap();
+    assert_ne!(first.addr(), second.addr());
+    assert_eq!(first.url(), format!("http://{}", first.addr()));
+
+    let start = Instant::now();
+    let response = get(first.addr(), 100, "hello%20there");
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    assert!(response.ends_with("\r\n\r\nhello there"), "{response}");
+    assert!(get(second.addr(), 0, "second").ends_with("second"));
+}
+
+#[test]
+fn dropping_the_handle_stops_the_server() {
+    let server = DelayServer::start(Config::ephemeral()).unwrap();
+    let addr = server.addr();
+    assert!(get(addr, 0, "up").ends_with("up"));
+    drop(server);
+    assert!(TcpStream::connect(addr).is_err());
+
+    // `stop` answers the requests that are still waiting first
+    let server = DelayServer::start(Config::ephemeral()).unwrap();
+    let addr = server.addr();
+    let waiting = std::thread::spawn(move || get(addr, 300, "late"));
+    // Until the server has read the request, the connection is an idle one
+    // that stopping closes
+    while !get_path(addr, "/metrics").contains("delayserver_requests_in_flight 1\n") {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    server.stop().unwrap();
+    assert!(waiting.join().unwrap().ends_with("late"));
+}
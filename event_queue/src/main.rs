@@ -55,7 +55,7 @@ fn handle_events(
 
         loop {
             match streams[index].read(&mut data) {
-                Ok(n) if n == 0 => {
+                Ok(0) => {
                     // FIX #4
                     // `insert` returns false if the value already existed in the set.
                     if !handled.insert(index) {
@@ -1,7 +1,4 @@
-use std::{
-    thread,
-    time::{Duration, Instant},
-};
+use std::{thread, time::Duration};
 mod future;
 mod http;
 use crate::http::Http;
@@ -40,27 +37,27 @@ impl Coroutine {
 impl Future for Coroutine {
     type Output = ();
     /// 1. The first thing we do is set the Output type to (). Since we won’t be returning anything, it
-    ///     just makes our example simpler.
+    ///    just makes our example simpler.
     /// 2. Next up is the implementation of the poll method. The first thing you notice is that we
-    ///     write a loop instance that matches self.state. We do this so we can drive the state
-    ///     machine forward until we reach a point where we can’t progress any further without getting
-    ///     `PollState::NotReady` from one of our child futures.
+    ///    write a loop instance that matches self.state. We do this so we can drive the state
+    ///    machine forward until we reach a point where we can’t progress any further without getting
+    ///    `PollState::NotReady` from one of our child futures.
     /// 3. If the state is State::Start, we know that this is the first time it was polled, so we run
-    ///     whatever instructions we need until we reach the point where we get a new future that we
-    ///     need to resolve.
+    ///    whatever instructions we need until we reach the point where we get a new future that we
+    ///    need to resolve.
     /// 4. When we call Http::get, we receive a future in return that we need to poll to completion
-    ///     before we progress any further.
-    ///  5. At this point, we change the state to State::Wait1 and we store the future we want to
-    ///  resolve so we can access it in the next state.
+    ///    before we progress any further.
+    /// 5. At this point, we change the state to State::Wait1 and we store the future we want to
+    ///    resolve so we can access it in the next state.
     /// 6. Our state machine has now changed its state from `Start` to `Wait1`. Since we’re looping on
-    ///     the match statement, we immediately progress to the next state and will reach the match arm
-    ///     in `State::Wait1` on the next iteration.
+    ///    the match statement, we immediately progress to the next state and will reach the match arm
+    ///    in `State::Wait1` on the next iteration.
     /// 7. The first thing we do in `Wait1` to call `poll` on the `Future` instance we’re waiting on.
     /// 8. If the future returns `PollState::NotReady`, we simply bubble that up to the caller by
-    ///     breaking out of the loop and returning `NotReady`.
+    ///    breaking out of the loop and returning `NotReady`.
     /// 9. If the future returns `PollState::Ready` together with our data, we know that we can
-    ///     execute the instructions that rely on the data from the first future and advance to the next state.
-    ///     In our case, we only print out the returned data, so that’s only one line of code.
+    ///    execute the instructions that rely on the data from the first future and advance to the next state.
+    ///    In our case, we only print out the returned data, so that’s only one line of code.
     /// 10. Next, we get to the point where we get a new future by calling `Http::get`. We set the state
     ///     to `Wait2`, just like we did when going from `State::Start` to `State::Wait1`.
     /// 11. Like we did the first time we got a future that we needed to resolve before we continue, we save
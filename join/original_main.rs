@@ -28,12 +28,7 @@ fn main() {
     let start = Instant::now();
     let mut future = async_main();
 
-    loop {
-        match future.poll() {
-            PollState::NotReady => (),
-            PollState::Ready(_) => break,
-        }
-    }
+    while let PollState::NotReady = future.poll() {}
 
     println!("\nELAPSED TIME: {}", start.elapsed().as_secs_f32());
 }
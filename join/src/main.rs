@@ -10,12 +10,7 @@ fn main() {
     let start = Instant::now();
     let mut future = async_main();
 
-    loop {
-        match future.poll() {
-            PollState::NotReady => (),
-            PollState::Ready(_) => break,
-        }
-    }
+    while let PollState::NotReady = future.poll() {}
 
     println!("\nELAPSED TIME: {}", start.elapsed().as_secs_f32());
 }
@@ -28,7 +23,6 @@ fn main() {
 //     let path = format!("/{}/HelloWorld{i}", i * 1000);
 //     let txt = Http::get(&path).wait;
 //     println!("{txt}");
-
 // }
 
 // =================================
@@ -66,9 +60,8 @@ impl Future for Coroutine0 {
                 State0::Start(i) => {
                     // ---- Code you actually wrote ----
                     let path = format!("/{}/HelloWorld{i}", i * 1000);
-
                     // ---------------------------------
-                    let fut1 = Box::new(Http::get(path.clone().as_str()));
+                    let fut1 = Box::new(Http::get(&path));
                     self.state = State0::Wait1(fut1);
                 }
 
@@ -77,7 +70,6 @@ impl Future for Coroutine0 {
                         PollState::Ready(txt) => {
                             // ---- Code you actually wrote ----
                             println!("{txt}");
-
                             // ---------------------------------
                             self.state = State0::Resolved;
                             break PollState::Ready(String::new());
@@ -105,7 +97,6 @@ impl Future for Coroutine0 {
 //     }
 //
 //     future::join_all(futures).wait;
-
 // }
 
 // =================================
@@ -148,7 +139,6 @@ impl Future for Coroutine1 {
                     for i in 0..5 {
                         futures.push(request(i));
                     }
-
                     // ---------------------------------
                     let fut1 = Box::new(future::join_all(futures));
                     self.state = State1::Wait1(fut1);
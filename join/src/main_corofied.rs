@@ -15,25 +15,21 @@ fn main() {
     let start = Instant::now();
     let mut future = async_main();
 
-    loop {
-        match future.poll() {
-            PollState::NotReady => (),
-            PollState::Ready(_) => break,
-        }
-    }
+    while let PollState::NotReady = future.poll() {}
 
     println!("\nELAPSED TIME: {}", start.elapsed().as_secs_f32());
 }
 
+
+
 // =================================
 // We rewrite this:
 // =================================
-    
+
 // coroutine fn request(i: usize) {
 //     let path = format!("/{}/HelloWorld{i}", i * 1000);
 //     let txt = Http::get(&path).wait;
 //     println!("{txt}");
-
 // }
 
 // =================================
@@ -66,13 +62,12 @@ impl Future for Coroutine0 {
 
     fn poll(&mut self) -> PollState<Self::Output> {
         loop {
-        match self.state {
+            match self.state {
                 State0::Start(i) => {
                     // ---- Code you actually wrote ----
                     let path = format!("/{}/HelloWorld{i}", i * 1000);
-
                     // ---------------------------------
-                    let fut1 = Box::new( Http::get(&path));
+                    let fut1 = Box::new(Http::get(&path));
                     self.state = State0::Wait1(fut1);
                 }
 
@@ -81,7 +76,6 @@ impl Future for Coroutine0 {
                         PollState::Ready(txt) => {
                             // ---- Code you actually wrote ----
                             println!("{txt}");
-
                             // ---------------------------------
                             self.state = State0::Resolved;
                             break PollState::Ready(String::new());
@@ -100,7 +94,7 @@ impl Future for Coroutine0 {
 // =================================
 // We rewrite this:
 // =================================
-    
+
 // coroutine fn async_main() {
 //     println!("Program starting");
 //     let mut futures = vec![];
@@ -110,7 +104,6 @@ impl Future for Coroutine0 {
 //     }
 // 
 //     future::join_all(futures).wait;
-
 // }
 
 // =================================
@@ -143,17 +136,15 @@ impl Future for Coroutine1 {
 
     fn poll(&mut self) -> PollState<Self::Output> {
         loop {
-        match self.state {
+            match self.state {
                 State1::Start => {
                     // ---- Code you actually wrote ----
                     println!("Program starting");
-    let mut futures = vec![];
-
-    for i in 0..5 {
-        futures.push(request(i));
-    }
-
+                    let mut futures = vec![];
 
+                    for i in 0..5 {
+                        futures.push(request(i));
+                    }
                     // ---------------------------------
                     let fut1 = Box::new(future::join_all(futures));
                     self.state = State1::Wait1(fut1);
@@ -163,7 +154,7 @@ impl Future for Coroutine1 {
                     match f1.poll() {
                         PollState::Ready(_) => {
                             // ---- Code you actually wrote ----
-                        
+
                             // ---------------------------------
                             self.state = State1::Resolved;
                             break PollState::Ready(String::new());
@@ -1,4 +1,5 @@
 use crate::http::Http;
+use pinning::{future, http, runtime};
 
 use crate::future::{Future, PollState};
 use crate::runtime::Waker;
@@ -0,0 +1,5 @@
+pub mod future;
+pub mod http;
+pub mod runtime;
+
+use future::Future;
@@ -1,8 +1,6 @@
-pub mod future;
-pub mod http;
-pub mod runtime;
 use crate::http::Http;
 use future::{Future, PollState};
+use pinning::{future, http, runtime};
 use runtime::Waker;
 use std::fmt::Write;
 fn main() {
@@ -100,7 +98,7 @@ impl Future for Coroutine0 {
                     match f2.poll(waker) {
                         PollState::Ready(txt) => {
                             // Restore stack
-                            let buffer = self.stack.buffer.as_ref().take().unwrap();
+                            let buffer = self.stack.buffer.as_ref().unwrap();
                             let writer = unsafe { &mut *self.stack.writer.take().unwrap() };
                             // ---- Code you actually wrote ----
                             writeln!(writer, "{txt}").unwrap();
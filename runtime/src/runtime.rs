@@ -25,15 +25,10 @@ impl Runtime {
         F: Future<Output = String>,
     {
         let mut future = future;
-        loop {
-            match future.poll() {
-                PollState::NotReady => {
-                    println!("Schedule other tasks\n");
-                    let mut events = Events::with_capacity(100);
-                    self.poll.poll(&mut events, None).unwrap();
-                }
-                PollState::Ready(_) => break,
-            }
+        while let PollState::NotReady = future.poll() {
+            println!("Schedule other tasks\n");
+            let mut events = Events::with_capacity(100);
+            self.poll.poll(&mut events, None).unwrap();
         }
     }
 }
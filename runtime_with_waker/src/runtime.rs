@@ -1,3 +1,5 @@
+// `spawn` is there for a future that spawns another one, which none here does
+#[allow(unused_imports)]
 pub use executor::{Executor, Waker, spawn};
 pub use reactor::reactor;
 
@@ -5,14 +5,6 @@ use std::{
     sync::{Arc, Mutex},
     thread::{self, Thread},
 };
-/// > Executor's features:
-/// - Holds many top-level futures and switches between them
-/// - Enables to spawn new top-level futures from anywhere
-/// - Hand out `Waker` types so that can sleep when there is nothing to do and wake up when one of the top-level futures can progress
-/// - Enables to run several executors by having each run on its dedicated OS thread
-///
-///
-
 type Task = Box<dyn Future<Output = String>>;
 
 // Allows defining a static variable that's unique to the thread it's first called from
@@ -46,6 +38,11 @@ where
     });
 }
 
+/// > Executor's features:
+/// - Holds many top-level futures and switches between them
+/// - Enables to spawn new top-level futures from anywhere
+/// - Hand out `Waker` types so that can sleep when there is nothing to do and wake up when one of the top-level futures can progress
+/// - Enables to run several executors by having each run on its dedicated OS thread
 pub struct Executor;
 
 impl Executor {